@@ -1,11 +1,216 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::env;
-use std::fs;
+use std::fs::{self, OpenOptions};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use config::{Config, ConfigError, Environment, File};
 
+/// Worker threads allowed per CPU before `thread_count` is clamped
+const MAX_THREADS_PER_CPU: usize = 4;
+/// Worker threads always allowed, however few CPUs there are
+const MIN_THREAD_COUNT_CAP: usize = 512;
+/// A `thread_count` above this can only be a typo and fails the load
+const MAX_THREAD_COUNT: usize = 100_000;
+
+#[derive(Deserialize)]
+pub struct VirtualHost {
+    /// Host name matched against the request `Host` header, without port
+    pub host: String,
+
+    /// Document root for requests to this host
+    pub doc_root: String,
+}
+
+#[derive(Deserialize)]
+pub struct RedirectRule {
+    /// Request path to match
+    pub from: String,
+
+    /// Redirect target, a path or an absolute URL
+    pub to: String,
+
+    /// One of 301, 302, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+
+    /// Match `from` as a path prefix and append the remainder of the path to `to`
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CacheRule {
+    /// Glob (`*` and `?`) matched against the file name, or against the path
+    /// from the document root when it contains a `/`
+    pub pattern: String,
+
+    /// Seconds clients may reuse the file without asking again
+    pub max_age: Option<u64>,
+
+    /// The file never changes under this name, e.g. fingerprinted assets
+    #[serde(default)]
+    pub immutable: bool,
+
+    /// Clients must revalidate before every reuse
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+/// Stylesheets and scripts are cached for an hour unless configured otherwise
+fn default_cache_rules() -> Vec<CacheRule> {
+    ["*.css", "*.js"]
+        .into_iter()
+        .map(|pattern| CacheRule {
+            pattern: String::from(pattern),
+            max_age: Some(3600),
+            immutable: false,
+            no_cache: false,
+        })
+        .collect()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ProxyRule {
+    /// Path prefix forwarded to the upstream, the path is sent unchanged
+    pub prefix: String,
+
+    /// Upstream `host:port`, spoken to in plain HTTP
+    pub upstream: String,
+
+    /// Seconds to wait for the upstream to accept and answer, defaults to 30
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AuthUser {
+    pub username: String,
+
+    /// Plain text password or a bcrypt hash (`$2b$...`)
+    pub password: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AuthRule {
+    /// Path prefix protected by this rule
+    pub prefix: String,
+
+    /// Realm sent in the `WWW-Authenticate` challenge
+    pub realm: String,
+
+    /// Accounts allowed to access the prefix
+    pub users: Vec<AuthUser>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, `*` allows any
+    pub allowed_origins: Vec<String>,
+
+    /// Methods advertised in preflight responses
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers advertised in preflight responses
+    pub allowed_headers: Vec<String>,
+
+    /// Seconds a preflight response may be cached
+    pub max_age: Option<u64>,
+
+    /// Allow cookies and credentials on cross-origin requests
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![
+                String::from("GET"),
+                String::from("HEAD"),
+                String::from("OPTIONS"),
+            ],
+            allowed_headers: Vec::new(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Headers added to every response. Each can be overridden with another value,
+/// or disabled by setting it to `null` or an empty string
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    /// `Strict-Transport-Security`, only sent over TLS
+    pub strict_transport_security: Option<String>,
+
+    /// `X-Content-Type-Options`
+    pub content_type_options: Option<String>,
+
+    /// `X-Frame-Options`
+    pub frame_options: Option<String>,
+
+    /// `Referrer-Policy`
+    pub referrer_policy: Option<String>,
+
+    /// `Content-Security-Policy`, not sent unless configured
+    pub content_security_policy: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            strict_transport_security: Some(String::from("max-age=31536000; includeSubDomains")),
+            content_type_options: Some(String::from("nosniff")),
+            frame_options: Some(String::from("DENY")),
+            referrer_policy: Some(String::from("strict-origin-when-cross-origin")),
+            content_security_policy: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first
+    pub cert_path: String,
+
+    /// PEM file with the private key for the leaf certificate
+    pub key_path: String,
+
+    /// Oldest protocol version accepted, `1.2` (default) or `1.3`
+    pub min_version: Option<String>,
+
+    /// Certificates for other host names, picked by the name the client sends
+    /// with SNI. Clients asking for any other name get the default certificate
+    #[serde(default)]
+    pub certificates: Vec<TlsCertificate>,
+
+    /// Seconds between checks for changed certificate or key files. Changed
+    /// files are loaded for new connections without a restart. Disabled if unset
+    pub reload_interval: Option<u64>,
+
+    /// PEM bundle of CAs client certificates are checked against. When set,
+    /// clients must present a certificate signed by one of them to connect
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TlsCertificate {
+    /// Host name the certificate is served for
+    pub host: String,
+
+    /// PEM file with the certificate chain, leaf first
+    pub cert_path: String,
+
+    /// PEM file with the private key for the leaf certificate
+    pub key_path: String,
+}
+
 #[derive(Deserialize)]
 pub struct ServerConfig {
     /// Ip address to bind to
@@ -20,11 +225,167 @@ pub struct ServerConfig {
     /// Number of worker threads in the threads pool
     pub thread_count: Option<usize>,
 
+    /// Max number of accepted connections waiting for a free worker thread,
+    /// defaults to `max_connections`
+    pub job_queue_size: Option<usize>,
+
+    /// Connection buffers allocated at startup, so the first connections don't
+    /// pay for the allocation. At most `max_connections`, none by default
+    pub prewarm_buffers: Option<usize>,
+
+    /// Seconds to wait for in-flight connections to finish on shutdown before
+    /// their worker threads are abandoned, defaults to 30
+    pub shutdown_timeout: Option<u64>,
+
+    /// Max number of connections the kernel queues before they are accepted,
+    /// defaults to 1024
+    pub listen_backlog: Option<i32>,
+
+    /// Set `SO_REUSEADDR` on the listening socket so a restarted server can bind
+    /// while old connections linger in `TIME_WAIT`, defaults to true
+    pub reuse_address: Option<bool>,
+
+    /// Set `SO_REUSEPORT` on the listening socket so several processes can share
+    /// the port. Ignored on platforms without it
+    #[serde(default)]
+    pub reuse_port: bool,
+
     /// Document root for static files
     pub doc_root: String,
 
-    /// Default file to serve for directory requests
-    pub default_index: String,
+    /// Files tried in order for directory requests, the first one that exists is
+    /// served. Accepts a single file name or a list
+    #[serde(deserialize_with = "string_or_list")]
+    pub default_index: Vec<String>,
+
+    /// Extensions tried in order for extensionless paths that don't match a file,
+    /// e.g. `["html"]` serves `/about` from `about.html`
+    #[serde(default)]
+    pub try_extensions: Vec<String>,
+
+    /// Serve `default_index` for extensionless paths that don't match a file,
+    /// so client side routes of single page apps resolve
+    #[serde(default)]
+    pub spa_fallback: bool,
+
+    /// Glob patterns (`*` and `?`) for paths that are never served, e.g. `*.bak`.
+    /// Patterns without a `/` match any single path component. Dotfiles are
+    /// always denied
+    #[serde(default)]
+    pub deny_patterns: Vec<String>,
+
+    /// `Cache-Control` for static files, the first rule matching the file applies
+    #[serde(default = "default_cache_rules")]
+    pub cache_rules: Vec<CacheRule>,
+
+    /// Regex matched against file names that carry a content hash, e.g.
+    /// `\.[0-9a-f]{6,}\.` for `app.3f9a2b.js`. Matching files are cached for a
+    /// year as immutable, ahead of `cache_rules`
+    pub fingerprint_pattern: Option<String>,
+
+    /// Page under the document root served as the body of `404` responses,
+    /// e.g. `404.html`
+    pub not_found_page: Option<String>,
+
+    /// Recognizes the content type of files with an unknown extension from their
+    /// first bytes. Ignored while `X-Content-Type-Options: nosniff` is sent, which
+    /// promises the type comes from the extension alone
+    #[serde(default)]
+    pub mime_sniffing: bool,
+
+    /// Gzip text files for clients that send `Accept-Encoding: gzip`
+    #[serde(default)]
+    pub gzip: bool,
+
+    /// Bodies larger than this many bytes are sent uncompressed even when gzip is
+    /// accepted, defaults to 1 MiB
+    pub max_compress_size: Option<u64>,
+
+    /// Total bytes of static file contents kept in memory, the cache is disabled
+    /// when unset
+    pub file_cache_size: Option<usize>,
+
+    /// Files larger than this many bytes are never cached, defaults to 1 MiB
+    pub file_cache_max_file_size: Option<usize>,
+
+    /// Seconds a path that 404'd is remembered as missing, so repeated requests for
+    /// it skip the filesystem. Disabled when unset
+    pub negative_cache_ttl: Option<u64>,
+
+    /// Milliseconds file metadata, including a path not existing, is reused
+    /// before stat'ing the file again. Changes to files show up once it passes.
+    /// Disabled when unset
+    pub stat_cache_ttl_ms: Option<u64>,
+
+    /// Charset appended to text content types (HTML, CSS, JavaScript, JSON,
+    /// plain text, SVG) that don't name one, defaults to `utf-8`. An empty value
+    /// sends content types unchanged
+    pub default_charset: Option<String>,
+
+    /// Value of the `Server` response header, defaults to `Xener/0.0.1`. An
+    /// empty value leaves the header out
+    pub server_header: Option<String>,
+
+    /// File the server writes its PID to once started, removed on a clean
+    /// shutdown. With `user` set, that user must be able to write to the
+    /// file's directory
+    pub pid_file: Option<String>,
+
+    /// Forks into the background once the listener is bound (Unix only).
+    /// Logging to stderr goes nowhere once detached, so set `error_log_path`
+    #[serde(default)]
+    pub daemonize: bool,
+
+    /// Unprivileged user to switch to once the listeners are bound, so the
+    /// server can start as root for ports below 1024 (Unix only)
+    pub user: Option<String>,
+
+    /// Group to switch to along with `user`, defaults to the user's primary group
+    pub group: Option<String>,
+
+    /// Confines the process to `doc_root` once listeners and logs are open, before
+    /// switching to `user` (Unix only, needs root). Virtual host roots must lie
+    /// inside `doc_root`. Paths outside it can't be reopened once confined, so it
+    /// can't be combined with `max_log_size` or `tls.reload_interval`
+    #[serde(default)]
+    pub chroot: bool,
+
+    /// HTTP Basic authentication for path prefixes, first matching rule applies
+    #[serde(default)]
+    pub auth: Vec<AuthRule>,
+
+    /// Redirect rules checked in order before serving files
+    #[serde(default)]
+    pub redirects: Vec<RedirectRule>,
+
+    /// Path prefixes forwarded to upstream servers, first matching rule applies
+    #[serde(default)]
+    pub proxies: Vec<ProxyRule>,
+
+    /// Name based virtual hosts, requests for other hosts are served from `doc_root`
+    #[serde(default)]
+    pub virtual_hosts: Vec<VirtualHost>,
+
+    /// Respond 421 Misdirected Request for hosts not listed in `virtual_hosts`
+    /// instead of falling back to `doc_root`
+    #[serde(default)]
+    pub strict_vhost: bool,
+
+    /// Host names (without port) requests may be addressed to, anything else gets
+    /// 400 Bad Request. Every host is accepted when empty
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+
+    /// Networks (CIDR, or single addresses) of proxies whose `X-Forwarded-For`
+    /// is believed. The header is ignored when sent by any other peer
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Path answered with `200 ok` for liveness probes, defaults to `/healthz`
+    pub health_check_path: Option<String>,
+
+    /// Path exposing Prometheus metrics, disabled when unset
+    pub metrics_path: Option<String>,
 
     /// Enable error log
     pub error_log: bool,
@@ -40,10 +401,87 @@ pub struct ServerConfig {
     /// if empty, log of stdout
     pub access_log_path: String,
 
+    /// Strip query strings from paths written to the access log
+    #[serde(default)]
+    pub access_log_strip_query: bool,
+
+    /// Access log line format: `common` (default), `combined`, `json` for one
+    /// object per line, or an Apache style format string such as
+    /// `%h %t "%r" %>s %b "%{User-Agent}i" %L`, where `%L` is the request ID
+    pub log_format: Option<String>,
+
+    /// Rotate the access log once it reaches this many bytes, never rotated when unset
+    pub max_log_size: Option<u64>,
+
+    /// Number of rotated access logs kept, defaults to 5
+    pub max_log_files: Option<usize>,
+
+    /// Flush buffered access log lines after this many, defaults to 32
+    pub access_log_flush_lines: Option<usize>,
+
+    /// Flush buffered access log lines at least this often, defaults to 1000
+    pub access_log_flush_interval_ms: Option<u64>,
+
+    /// Seconds an idle kept-alive connection stays open, defaults to 30
     pub keep_alive_timeout: Option<u64>,
+
+    /// Seconds a read from a client may block, defaults to 30
     pub read_timeout: Option<u64>,
+
+    /// Seconds a write to a client may block, defaults to 30
     pub write_timeout: Option<u64>,
+
     pub max_requests_per_connection: Option<usize>,
+
+    /// Seconds allowed to receive the request line and headers in total, slower
+    /// clients get 408 Request Timeout. Defaults to 5
+    pub header_read_timeout: Option<u64>,
+
+    /// Seconds allowed to receive a whole request, body included, however
+    /// steadily it arrives. Slower clients get 408 Request Timeout, unlimited
+    /// when unset
+    pub max_request_duration: Option<u64>,
+
+    /// Max request body size in bytes, larger requests get 413 Content Too Large
+    pub max_body_size: Option<usize>,
+
+    /// Max request line length in bytes, longer lines get 414 URI Too Long
+    pub max_request_line_length: Option<usize>,
+
+    /// Max length of a single header line in bytes, longer lines get
+    /// 431 Request Header Fields Too Large
+    pub max_header_line_length: Option<usize>,
+
+    /// Max number of request headers, more get 431 Request Header Fields Too Large
+    pub max_header_count: Option<usize>,
+
+    /// Cross-origin resource sharing, disabled when unset
+    pub cors: Option<CorsConfig>,
+
+    /// Security headers added to every response, disabled when unset
+    pub security_headers: Option<SecurityHeadersConfig>,
+
+    /// Serve HTTPS instead of plain HTTP when set
+    pub tls: Option<TlsConfig>,
+
+    /// With `tls` set, also listen for plain HTTP on `http_port` and redirect
+    /// every request there to HTTPS
+    #[serde(default)]
+    pub redirect_http_to_https: bool,
+
+    /// Port of the plain HTTP redirect listener, defaults to 80
+    pub http_port: Option<u16>,
+
+    /// Max seconds a single response body may take to write before the
+    /// connection is dropped, unlimited when unset
+    pub max_stream_duration_secs: Option<u64>,
+
+    /// Max bytes per second written to a single connection, unlimited when unset
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// Settings adjusted while loading, logged once logging is set up
+    #[serde(skip)]
+    pub warnings: Vec<String>,
 }
 
 impl Default for ServerConfig {
@@ -53,16 +491,71 @@ impl Default for ServerConfig {
             port: 8080,
             max_connections: Some(100),
             thread_count: None,
+            job_queue_size: None,
+            prewarm_buffers: None,
+            shutdown_timeout: None,
+            listen_backlog: None,
+            reuse_address: None,
+            reuse_port: false,
             doc_root: String::from("./static"),
-            default_index: String::from("index.html"),
+            default_index: vec![String::from("index.html")],
+            try_extensions: Vec::new(),
+            spa_fallback: false,
+            deny_patterns: Vec::new(),
+            cache_rules: default_cache_rules(),
+            fingerprint_pattern: None,
+            not_found_page: None,
+            mime_sniffing: false,
+            gzip: false,
+            max_compress_size: None,
+            file_cache_size: None,
+            file_cache_max_file_size: None,
+            negative_cache_ttl: None,
+            stat_cache_ttl_ms: None,
+            default_charset: None,
+            server_header: None,
+            pid_file: None,
+            daemonize: false,
+            user: None,
+            group: None,
+            chroot: false,
+            auth: Vec::new(),
+            redirects: Vec::new(),
+            proxies: Vec::new(),
+            virtual_hosts: Vec::new(),
+            strict_vhost: false,
+            allowed_hosts: Vec::new(),
+            trusted_proxies: Vec::new(),
+            health_check_path: None,
+            metrics_path: None,
             error_log: true,
             error_log_path: String::new(),
             access_log: true,
             access_log_path: String::new(),
+            access_log_strip_query: false,
+            log_format: None,
+            max_log_size: None,
+            max_log_files: None,
+            access_log_flush_lines: None,
+            access_log_flush_interval_ms: None,
             keep_alive_timeout: None,
             read_timeout: None,
+            header_read_timeout: None,
+            max_request_duration: None,
             write_timeout: None,
             max_requests_per_connection: None,
+            max_body_size: None,
+            max_request_line_length: None,
+            max_header_line_length: None,
+            max_header_count: None,
+            cors: None,
+            security_headers: None,
+            tls: None,
+            redirect_http_to_https: false,
+            http_port: None,
+            max_stream_duration_secs: None,
+            max_bytes_per_sec: None,
+            warnings: Vec::new(),
         }
     }
 }
@@ -101,7 +594,7 @@ impl ServerConfig {
 
         let config = settings.build()?;
         let mut server_config: ServerConfig = config.try_deserialize()?;
-        server_config.normalize_paths().unwrap();
+        server_config.validate()?;
 
         Ok(server_config)
     }
@@ -115,7 +608,120 @@ impl ServerConfig {
         config
     }
 
-    fn normalize_paths(&mut self) -> io::Result<()> {
+    /// Every worker thread reserves a stack, so a `thread_count` far beyond what the
+    /// CPUs can use is clamped to 4 per CPU (at least 512), and one no machine
+    /// could run is rejected
+    fn validate_thread_count(&mut self) -> Result<(), ConfigError> {
+        let Some(thread_count) = self.thread_count else {
+            return Ok(());
+        };
+        if thread_count > MAX_THREAD_COUNT {
+            return Err(ConfigError::Message(format!(
+                "thread_count {} exceeds the limit of {}",
+                thread_count, MAX_THREAD_COUNT
+            )));
+        }
+
+        let cap = (num_cpus::get() * MAX_THREADS_PER_CPU).max(MIN_THREAD_COUNT_CAP);
+        if thread_count > cap {
+            self.warnings.push(format!(
+                "thread_count {} is more than this machine can use, limiting it to {}",
+                thread_count, cap
+            ));
+            self.thread_count = Some(cap);
+        }
+        Ok(())
+    }
+
+    /// Checks the settings the server can't start without and prepares the
+    /// directories they point to. Every problem found is reported in one error
+    pub fn validate(&mut self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = self.validate_thread_count() {
+            problems.push(e.to_string());
+        }
+        if self.thread_count == Some(0) {
+            problems.push(String::from("thread_count must be at least 1"));
+        }
+        if self.max_connections == Some(0) {
+            problems.push(String::from("max_connections must be at least 1"));
+        }
+        if self.port == 0 {
+            problems.push(String::from("port must be between 1 and 65535"));
+        }
+        if self.http_port == Some(0) {
+            problems.push(String::from("http_port must be between 1 and 65535"));
+        }
+
+        if self.mime_sniffing && self.sends_nosniff() {
+            self.warnings.push(String::from(
+                "mime_sniffing is ignored while X-Content-Type-Options: nosniff is sent",
+            ));
+        }
+
+        if let Err(e) = self.normalize_doc_root() {
+            problems.push(format!("doc_root {}: {}", self.doc_root, e));
+        } else if self.chroot && Path::new(&self.doc_root).is_file() {
+            problems.push(format!(
+                "doc_root {} is a file, chroot needs a directory",
+                self.doc_root
+            ));
+        }
+
+        if self.chroot {
+            if self.access_log && !self.access_log_path.is_empty() && self.max_log_size.is_some() {
+                problems.push(String::from(
+                    "max_log_size can't be used with chroot, the rotated log couldn't be reopened",
+                ));
+            }
+            if self
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.reload_interval.is_some())
+            {
+                problems.push(String::from(
+                    "tls.reload_interval can't be used with chroot, the certificates couldn't be reread",
+                ));
+            }
+        }
+
+        let logs = [
+            ("access_log_path", self.access_log, &self.access_log_path),
+            ("error_log_path", self.error_log, &self.error_log_path),
+        ];
+        for (name, enabled, path) in logs {
+            if !enabled || path.is_empty() {
+                continue;
+            }
+            if let Err(e) = check_writable(path) {
+                problems.push(format!("{} {} is not writable: {}", name, path, e));
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Message(format!(
+            "invalid configuration:\n  - {}",
+            problems.join("\n  - ")
+        )))
+    }
+
+    /// Whether static file content types may be sniffed, see `mime_sniffing`
+    pub fn sniffs_content(&self) -> bool {
+        self.mime_sniffing && !self.sends_nosniff()
+    }
+
+    fn sends_nosniff(&self) -> bool {
+        self.security_headers
+            .as_ref()
+            .and_then(|headers| headers.content_type_options.as_deref())
+            .is_some_and(|value| value.eq_ignore_ascii_case("nosniff"))
+    }
+
+    /// Makes `doc_root` absolute, creating the directory if it doesn't exist
+    fn normalize_doc_root(&mut self) -> io::Result<()> {
         let mut doc_root = PathBuf::from(&self.doc_root);
         if !doc_root.is_absolute() {
             if let Ok(current_dir) = env::current_dir() {
@@ -123,26 +729,14 @@ impl ServerConfig {
             }
         }
 
+        // A file is served as is for every path
         if !doc_root.exists() {
             fs::create_dir_all(&doc_root)?;
-        }
-
-        if !self.access_log_path.is_empty() {
-            let path = PathBuf::from(&self.access_log_path);
-            if let Some(parent) = path.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)?;
-                }
-            }
-        }
-
-        if !self.error_log_path.is_empty() {
-            let path = PathBuf::from(&self.error_log_path);
-            if let Some(parent) = path.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)?;
-                }
-            }
+        } else if !doc_root.is_dir() && !doc_root.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "not a directory or file",
+            ));
         }
 
         self.doc_root = doc_root.to_string_lossy().to_string();
@@ -150,11 +744,40 @@ impl ServerConfig {
     }
 }
 
+/// Opens the log file at `path` for appending, creating it and its directory
+/// if needed, the same way the logger will
+fn check_writable(path: &str) -> io::Result<()> {
+    let path = PathBuf::from(path);
+    if let Some(parent) = path.parent().filter(|parent| !parent.exists()) {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(&path)?;
+    Ok(())
+}
+
+/// Accepts either `key: value` or `key: [value, ...]`
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::One(value) => vec![value],
+        StringOrList::Many(values) => values,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use tempfile::TempDir;
 
-    use super::ServerConfig;
+    use super::{ServerConfig, TlsConfig};
     use std::{env, fs};
 
     #[test]
@@ -163,7 +786,7 @@ mod tests {
         assert_eq!(config.ip, "127.0.0.1");
         assert_eq!(config.port, 8080);
         assert_eq!(config.doc_root, "./static");
-        assert_eq!(config.default_index, "index.html");
+        assert_eq!(config.default_index, vec!["index.html"]);
     }
 
     #[test]
@@ -193,7 +816,7 @@ mod tests {
         assert_eq!(config.port, 9090);
         assert_eq!(config.max_connections, Some(11));
         assert_eq!(config.doc_root, "/var/www/xener");
-        assert_eq!(config.default_index, "index.htm");
+        assert_eq!(config.default_index, vec!["index.htm"]);
         assert_eq!(config.error_log, false);
         assert_eq!(config.error_log_path, "./xener/logs/error.log");
         assert_eq!(config.error_log, false);
@@ -202,9 +825,143 @@ mod tests {
         unsafe {
             env::set_var("XENER__DEFAULT_INDEX", "default.html");
             let config = ServerConfig::load().unwrap();
-            assert_eq!(config.default_index, "default.html");
+            assert_eq!(config.default_index, vec!["default.html"]);
             env::remove_var("XENER__DEFAULT_INDEX");
         }
         env::set_current_dir(original_dir).unwrap();
     }
+
+    #[test]
+    fn test_default_index_accepts_list() {
+        let config: ServerConfig = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                ip: "127.0.0.1"
+                port: 8080
+                doc_root: "./static"
+                default_index: ["index.html", "index.htm"]
+                error_log: false
+                error_log_path: ""
+                access_log: false
+                access_log_path: ""
+                "#,
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(config.default_index, vec!["index.html", "index.htm"]);
+    }
+
+    #[test]
+    fn test_thread_count_clamped() {
+        let mut config = ServerConfig {
+            thread_count: Some(10_000),
+            ..Default::default()
+        };
+        config.validate_thread_count().unwrap();
+
+        let cap = (num_cpus::get() * 4).max(512);
+        assert_eq!(config.thread_count, Some(cap.min(10_000)));
+        assert_eq!(config.warnings.len(), usize::from(cap < 10_000));
+
+        let mut config = ServerConfig {
+            thread_count: Some(8),
+            ..Default::default()
+        };
+        config.validate_thread_count().unwrap();
+        assert_eq!(config.thread_count, Some(8));
+        assert!(config.warnings.is_empty());
+
+        let mut config = ServerConfig {
+            thread_count: Some(1_000_000),
+            ..Default::default()
+        };
+        assert!(config.validate_thread_count().is_err());
+    }
+
+    #[test]
+    fn test_unwritable_log_path_reported() {
+        let temp_dir = TempDir::new().unwrap();
+        let blocker = temp_dir.path().join("logs");
+        fs::write(&blocker, "not a directory").unwrap();
+        let access_log_path = blocker.join("access.log").to_string_lossy().to_string();
+
+        let mut config = ServerConfig {
+            doc_root: temp_dir.path().to_string_lossy().to_string(),
+            access_log_path: access_log_path.clone(),
+            port: 0,
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+
+        assert!(
+            err.contains(&format!(
+                "access_log_path {} is not writable",
+                access_log_path
+            )),
+            "{}",
+            err
+        );
+        assert!(err.contains("port must be between 1 and 65535"), "{}", err);
+    }
+
+    #[test]
+    fn test_file_doc_root_accepted() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("report.pdf");
+        fs::write(&file, "%PDF-1.4").unwrap();
+
+        let mut config = ServerConfig {
+            doc_root: file.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        config.chroot = true;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("chroot needs a directory"), "{}", err);
+    }
+
+    #[test]
+    fn test_chroot_rejects_reopening_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = ServerConfig {
+            doc_root: temp_dir.path().to_string_lossy().to_string(),
+            chroot: true,
+            access_log: true,
+            access_log_path: temp_dir
+                .path()
+                .join("access.log")
+                .to_string_lossy()
+                .to_string(),
+            max_log_size: Some(1024),
+            tls: Some(TlsConfig {
+                cert_path: String::from("/etc/xener/server.crt"),
+                key_path: String::from("/etc/xener/server.key"),
+                min_version: None,
+                certificates: Vec::new(),
+                reload_interval: Some(60),
+                client_ca_path: None,
+            }),
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("max_log_size can't be used with chroot"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("tls.reload_interval can't be used with chroot"),
+            "{}",
+            err
+        );
+
+        config.max_log_size = None;
+        config.tls = None;
+        config.validate().unwrap();
+    }
 }
@@ -21,15 +21,33 @@ pub enum ServerError {
     /// Error parsing Http request (malformed headers, invalid method)
     HttpParse(String),
 
+    /// Request uses an HTTP version the server doesn't speak
+    VersionNotSupported(String),
+
+    /// Request relies on a feature the server doesn't implement (unknown transfer coding)
+    NotImplemented(String),
+
     /// Server is too busy to handle the request (overloaded)
     ServerBusy,
 
+    /// Request body exceeds the configured limit
+    ContentTooLarge(String),
+
+    /// Request line exceeds the configured limit
+    UriTooLong(String),
+
+    /// Header line or header count exceeds the configured limit
+    HeaderFieldsTooLarge(String),
+
     /// Access denied (permission issues, unauthorized)
     Forbidden(String),
 
     /// Request timeout (client too slow, network issues)
     Timeout(String),
 
+    /// Failed to serialize a response body
+    Serialization(String),
+
     /// Generic error with a message
     Other(String),
 }
@@ -42,9 +60,19 @@ impl fmt::Display for ServerError {
             ServerError::Config(msg) => write!(f, "Configuration error: {}", msg),
             ServerError::NotFound(path) => write!(f, "Not found: {}", path),
             ServerError::HttpParse(msg) => write!(f, "Error parsing HTTP request: {}", msg),
+            ServerError::VersionNotSupported(msg) => {
+                write!(f, "HTTP version not supported: {}", msg)
+            }
+            ServerError::NotImplemented(msg) => write!(f, "Not implemented: {}", msg),
             ServerError::ServerBusy => write!(f, "Server is too busy to handle the request"),
+            ServerError::ContentTooLarge(msg) => write!(f, "Content too large: {}", msg),
+            ServerError::UriTooLong(msg) => write!(f, "URI too long: {}", msg),
+            ServerError::HeaderFieldsTooLarge(msg) => {
+                write!(f, "Request header fields too large: {}", msg)
+            }
             ServerError::Forbidden(msg) => write!(f, "Access denied: {}", msg),
             ServerError::Timeout(msg) => write!(f, "Request timeout: {}", msg),
+            ServerError::Serialization(msg) => write!(f, "Serialization error: {}", msg),
             ServerError::Other(msg) => write!(f, "{}", msg),
         }
     }
@@ -64,6 +92,12 @@ impl From<serde_yml::Error> for ServerError {
     }
 }
 
+impl From<serde_json::Error> for ServerError {
+    fn from(err: serde_json::Error) -> Self {
+        ServerError::Serialization(err.to_string())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ServerError>;
 
 pub fn error_to_response(error: &ServerError) -> Response {
@@ -102,6 +136,46 @@ pub fn error_to_response(error: &ServerError) -> Response {
                             </body>\n</html>",
                             msg).as_str()),
 
+        ServerError::VersionNotSupported(msg) => Response::new()
+            .with_status(StatusCode::HttpVersionNotSupported)
+            .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
+            .with_text(format!("<!DOCTYPE html>\n<html>\n<head><title>505 HTTP Version Not Supported</title></head>\n<body>\n\
+                            <h1>505 HTTP Version Not Supported</h1>\n<p>The server only supports HTTP/1.0 and HTTP/1.1: {}</p>\n\
+                            </body>\n</html>",
+                            msg).as_str()),
+
+        ServerError::NotImplemented(msg) => Response::new()
+            .with_status(StatusCode::NotImplemented)
+            .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
+            .with_text(format!("<!DOCTYPE html>\n<html>\n<head><title>501 Not Implemented</title></head>\n<body>\n\
+                            <h1>501 Not Implemented</h1>\n<p>The server does not support the functionality required to fulfill the request: {}</p>\n\
+                            </body>\n</html>",
+                            msg).as_str()),
+
+        ServerError::ContentTooLarge(msg) => Response::new()
+            .with_status(StatusCode::ContentTooLarge)
+            .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
+            .with_text(format!("<!DOCTYPE html>\n<html>\n<head><title>413 Content Too Large</title></head>\n<body>\n\
+                            <h1>413 Content Too Large</h1>\n<p>The request body is larger than the server is willing to process: {}</p>\n\
+                            </body>\n</html>",
+                            msg).as_str()),
+
+        ServerError::UriTooLong(msg) => Response::new()
+            .with_status(StatusCode::UriTooLong)
+            .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
+            .with_text(format!("<!DOCTYPE html>\n<html>\n<head><title>414 URI Too Long</title></head>\n<body>\n\
+                            <h1>414 URI Too Long</h1>\n<p>The request line is longer than the server is willing to interpret: {}</p>\n\
+                            </body>\n</html>",
+                            msg).as_str()),
+
+        ServerError::HeaderFieldsTooLarge(msg) => Response::new()
+            .with_status(StatusCode::RequestHeaderFieldsTooLarge)
+            .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
+            .with_text(format!("<!DOCTYPE html>\n<html>\n<head><title>431 Request Header Fields Too Large</title></head>\n<body>\n\
+                            <h1>431 Request Header Fields Too Large</h1>\n<p>The request headers are larger than the server is willing to process: {}</p>\n\
+                            </body>\n</html>",
+                            msg).as_str()),
+
         ServerError::Timeout(msg) => Response::new()
             .with_status(StatusCode::RequestTimeout)
             .with_content_type(ERROR_RESPONSE_CONTENT_TYPE)
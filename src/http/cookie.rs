@@ -0,0 +1,91 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use super::response::format_http_date;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+/// Optional attributes of a `Set-Cookie` header, only those set are emitted
+#[derive(Debug, Clone, Default)]
+pub struct CookieAttrs {
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub expires: Option<DateTime<Utc>>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl CookieAttrs {
+    /// Formats `name=value` followed by the attributes in canonical order
+    pub fn serialize(&self, name: &str, value: &str) -> String {
+        let mut cookie = format!("{}={}", name, value);
+
+        if let Some(path) = &self.path {
+            cookie.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(expires) = self.expires {
+            cookie.push_str(&format!("; Expires={}", format_http_date(expires)));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.push_str(&format!("; SameSite={}", same_site));
+        }
+
+        cookie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{CookieAttrs, SameSite};
+
+    #[test]
+    fn test_serialize_all_attributes_in_order() {
+        let attrs = CookieAttrs {
+            path: Some(String::from("/")),
+            domain: Some(String::from("example.com")),
+            max_age: Some(3600),
+            expires: Some(Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap()),
+            secure: true,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+        };
+
+        assert_eq!(
+            attrs.serialize("id", "42"),
+            "id=42; Path=/; Domain=example.com; Max-Age=3600; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Lax"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+
+/// Content type for a file based on its extension
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+
+    match extension.to_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "pdf" => "application/pdf",
+        _ => DEFAULT_CONTENT_TYPE,
+    }
+}
+
+/// Content type a file extension doesn't tell
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Bytes `sniff` needs from the start of a file
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Content type recognized from the first bytes of a file, for files whose
+/// extension is unknown. Only covers formats with unambiguous signatures
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: [(&[u8], &str); 6] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1f\x8b\x08", "application/gzip"),
+    ];
+    if let Some(&(_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+    {
+        return Some(content_type);
+    }
+
+    // HTML may start with a byte order mark and whitespace before the markup
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let text = text.trim_ascii_start();
+    ["<!doctype html", "<html", "<head", "<body"]
+        .iter()
+        .any(|tag| {
+            text.get(..tag.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(tag.as_bytes()))
+        })
+        .then_some("text/html")
+}
+
+/// Whether `content_type` is text, which a charset applies to
+pub fn is_text(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || matches!(
+            essence.as_str(),
+            "application/javascript" | "application/json" | "image/svg+xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sniff;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><html>"), Some("text/html"));
+        assert_eq!(sniff(b"\xef\xbb\xbf<HTML>"), Some("text/html"));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(sniff(b"\x1f\x8b\x08\0"), Some("application/gzip"));
+        assert_eq!(sniff(b"<htm"), None);
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(b""), None);
+    }
+}
@@ -1,9 +1,13 @@
 use strum_macros::Display;
 
+use crate::error::ServerError;
+
+pub mod cookie;
+pub mod mime;
 pub mod request;
 pub mod response;
 
-#[derive(Debug, Display, PartialEq)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Method {
     GET,
     POST,
@@ -53,6 +57,17 @@ impl From<&str> for Version {
     }
 }
 
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::HTTP1_0 => "HTTP/1.0",
+            Version::HTTP1_1 => "HTTP/1.1",
+            Version::HTTP2_0 => "HTTP/2.0",
+            Version::UNKNOWN => "-",
+        }
+    }
+}
+
 impl Into<String> for Version {
     fn into(self) -> String {
         match self {
@@ -65,44 +80,87 @@ impl Into<String> for Version {
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum StatusCode {
-    Ok = 200,
-    Created = 201,
-    Accepted = 202,
-    NoContent = 204,
-    MovedPermanently = 301,
-    Found = 302,
-    TemporaryRedirect = 307,
-    PermanentRedirect = 308,
-    BadRequest = 400,
-    Unauthorized = 401,
-    Forbidden = 403,
-    NotFound = 404,
-    MethodNotAllowed = 405,
-    RequestTimeout = 408,
-    ContentTooLarge = 413,
-    UriTooLong = 414,
-    TooManyRequests = 429,
-    RequestHeaderFieldsTooLarge = 431,
-    InternalServerError = 500,
-    NotImplemented = 501,
-    BadGateway = 502,
-    ServiceUnavailable = 503,
-    GatewayTimeout = 504,
+    Continue,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    ContentTooLarge,
+    UriTooLong,
+    RangeNotSatisfiable,
+    MisdirectedRequest,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+    /// Any other code, e.g. one relayed from a proxied upstream. Its reason
+    /// phrase is left empty
+    Other(u16),
 }
 
 impl StatusCode {
     pub fn code(&self) -> u16 {
-        *self as u16
+        match self {
+            Self::Continue => 100,
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::NotModified => 304,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::ContentTooLarge => 413,
+            Self::UriTooLong => 414,
+            Self::RangeNotSatisfiable => 416,
+            Self::MisdirectedRequest => 421,
+            Self::TooManyRequests => 429,
+            Self::RequestHeaderFieldsTooLarge => 431,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::GatewayTimeout => 504,
+            Self::HttpVersionNotSupported => 505,
+            Self::Other(code) => *code,
+        }
     }
 
     pub fn reason_phrase(&self) -> &str {
         match self {
+            Self::Continue => "Continue",
             Self::Ok => "OK",
             Self::Created => "Created",
             Self::Accepted => "Accepted",
             Self::NoContent => "No Content",
+            Self::PartialContent => "Partial Content",
             Self::MovedPermanently => "Moved Permanently",
             Self::Found => "Found",
+            Self::NotModified => "Not Modified",
             Self::TemporaryRedirect => "Temporary Redirect",
             Self::PermanentRedirect => "Permanent Redirect",
             Self::BadRequest => "Bad Request",
@@ -113,6 +171,8 @@ impl StatusCode {
             Self::RequestTimeout => "Request Timeout",
             Self::ContentTooLarge => "Content Too Large",
             Self::UriTooLong => "URI Too Long",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::MisdirectedRequest => "Misdirected Request",
             Self::TooManyRequests => "Too Many Requests",
             Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             Self::InternalServerError => "Internal Server Error",
@@ -120,10 +180,77 @@ impl StatusCode {
             Self::BadGateway => "Bad Gateway",
             Self::ServiceUnavailable => "Service Unavailable",
             Self::GatewayTimeout => "Gateway Timeout",
+            Self::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Self::Other(_) => "",
         }
     }
 
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
     pub fn status_text(&self) -> String {
         format!("{} {}", self.code(), self.reason_phrase())
     }
 }
+
+const KNOWN_STATUS_CODES: [StatusCode; 29] = [
+    StatusCode::Continue,
+    StatusCode::Ok,
+    StatusCode::Created,
+    StatusCode::Accepted,
+    StatusCode::NoContent,
+    StatusCode::PartialContent,
+    StatusCode::MovedPermanently,
+    StatusCode::Found,
+    StatusCode::NotModified,
+    StatusCode::TemporaryRedirect,
+    StatusCode::PermanentRedirect,
+    StatusCode::BadRequest,
+    StatusCode::Unauthorized,
+    StatusCode::Forbidden,
+    StatusCode::NotFound,
+    StatusCode::MethodNotAllowed,
+    StatusCode::RequestTimeout,
+    StatusCode::ContentTooLarge,
+    StatusCode::UriTooLong,
+    StatusCode::RangeNotSatisfiable,
+    StatusCode::MisdirectedRequest,
+    StatusCode::TooManyRequests,
+    StatusCode::RequestHeaderFieldsTooLarge,
+    StatusCode::InternalServerError,
+    StatusCode::NotImplemented,
+    StatusCode::BadGateway,
+    StatusCode::ServiceUnavailable,
+    StatusCode::GatewayTimeout,
+    StatusCode::HttpVersionNotSupported,
+];
+
+impl TryFrom<u16> for StatusCode {
+    type Error = ServerError;
+
+    /// Fails for codes without a named variant
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        KNOWN_STATUS_CODES
+            .into_iter()
+            .find(|status| status.code() == code)
+            .ok_or_else(|| ServerError::Http(format!("unknown status code {}", code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatusCode;
+
+    #[test]
+    fn test_status_code_try_from() {
+        assert_eq!(StatusCode::try_from(404).unwrap(), StatusCode::NotFound);
+        assert_eq!(
+            StatusCode::try_from(StatusCode::RangeNotSatisfiable.code()).unwrap(),
+            StatusCode::RangeNotSatisfiable
+        );
+        assert!(StatusCode::try_from(999).is_err());
+        assert!(StatusCode::try_from(409).is_err());
+        assert_eq!(StatusCode::Other(409).code(), 409);
+    }
+}
@@ -1,96 +1,281 @@
 use super::{Method, Version};
 use crate::error::{Result, ServerError};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::SocketAddr;
 
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_MAX_REQUEST_LINE_LENGTH: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_LINE_LENGTH: usize = 8 * 1024;
+const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+/// Bounds applied while parsing a request so a client can't make the server
+/// allocate arbitrary amounts of memory
+pub struct RequestLimits {
+    pub max_body_size: usize,
+    pub max_request_line_length: usize,
+    pub max_header_line_length: usize,
+    pub max_header_count: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_request_line_length: DEFAULT_MAX_REQUEST_LINE_LENGTH,
+            max_header_line_length: DEFAULT_MAX_HEADER_LINE_LENGTH,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Request {
     pub method: Method,
     pub path: String,
+    /// Authority (`host[:port]`) of an absolute-form target such as
+    /// `GET http://example.com/page HTTP/1.1`, which takes precedence over `Host`
+    pub authority: Option<String>,
     pub version: Version,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    /// Address of the client that sent the request, when read from a connection
+    pub peer_addr: Option<SocketAddr>,
 }
 
 impl Request {
     pub fn from_stream<T: Read>(stream: &mut T) -> Result<Self> {
-        let mut reader = BufReader::new(stream);
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
+        Self::from_stream_with_limits(stream, &RequestLimits::default())
+    }
+
+    pub fn from_stream_with_limits<T: Read>(
+        stream: &mut T,
+        limits: &RequestLimits,
+    ) -> Result<Self> {
+        Self::from_reader(&mut BufReader::new(stream), limits, &mut |_| Ok(()))
+    }
+
+    /// Parses one request from `reader`, leaving anything after it buffered for
+    /// the next request on the connection. `send_continue` is called with the
+    /// reader before reading the body of an `Expect: 100-continue` request that
+    /// is within limits, so the client knows to start uploading
+    pub fn from_reader<R: BufRead>(
+        reader: &mut R,
+        limits: &RequestLimits,
+        send_continue: &mut dyn FnMut(&mut R) -> io::Result<()>,
+    ) -> Result<Self> {
+        let mut request = Self::read_head(reader, limits)?;
+        request.read_body(reader, limits, send_continue)?;
+        Ok(request)
+    }
+
+    /// Parses the request line and headers from `reader`, leaving the body unread
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Self> {
+        Self::read_head_with_buffer(reader, limits, &mut String::new())
+    }
+
+    /// Same as `read_head`, reading each line into `line` instead of allocating
+    /// one per line. A connection passes the same buffer for every request
+    pub fn read_head_with_buffer<R: BufRead>(
+        reader: &mut R,
+        limits: &RequestLimits,
+        line: &mut String,
+    ) -> Result<Self> {
+        let request_line = &mut *line;
+        if !read_line_bounded(reader, request_line, limits.max_request_line_length)? {
+            return Err(ServerError::UriTooLong(format!(
+                "request line exceeds {} bytes",
+                limits.max_request_line_length
+            )));
+        }
 
         let parts: Vec<&str> = request_line.trim().split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(ServerError::HttpParse(
-                "Invalid Http request line".to_string(),
-            ));
+        match parts.len() {
+            3 => {}
+            // `GET /` without a version is an HTTP/0.9 simple request
+            2 => {
+                return Err(ServerError::VersionNotSupported(String::from(
+                    "HTTP/0.9 requests without a version are not supported",
+                )));
+            }
+            _ => {
+                return Err(ServerError::HttpParse(format!(
+                    "malformed request line {:?}, expected METHOD TARGET VERSION",
+                    request_line.trim()
+                )));
+            }
         }
 
         let method = Method::from(parts[0]);
-        let path = String::from(parts[1]);
-        let version = Version::from(parts[2]);
+        let (authority, path) = split_request_target(parts[1]);
+        let version = parse_version(parts[2])?;
+        validate_path(method, &path)?;
 
         let mut headers = HashMap::new();
         loop {
-            let mut header_line = String::new();
-            reader.read_line(&mut header_line)?;
-            let header_line = header_line.trim();
+            if !read_line_bounded(reader, line, limits.max_header_line_length)? {
+                return Err(ServerError::HeaderFieldsTooLarge(format!(
+                    "header line exceeds {} bytes",
+                    limits.max_header_line_length
+                )));
+            }
+            // Obsolete line folding continues the previous header on a line
+            // starting with whitespace, which other parsers may read differently
+            if line.starts_with([' ', '\t']) && !line.trim().is_empty() {
+                return Err(ServerError::HttpParse(String::from(
+                    "obsolete line folding in headers",
+                )));
+            }
+            let header_line = line.trim();
 
             if header_line.is_empty() {
                 break;
             }
 
-            if let Some(pos) = header_line.find(':') {
-                let (name, value) = header_line.split_at(pos);
-                let value = value[1..].trim();
-                headers.insert(name.to_string(), value.to_string());
+            if headers.len() >= limits.max_header_count {
+                return Err(ServerError::HeaderFieldsTooLarge(format!(
+                    "more than {} headers",
+                    limits.max_header_count
+                )));
             }
-        }
 
-        let mut body = Vec::new();
-        if let Some(content_length) = headers.get("Content-Length") {
-            if let Ok(length) = content_length.parse::<usize>() {
-                let mut buffer = vec![0; length];
-                reader.read_exact(&mut buffer)?;
-                body = buffer;
+            let Some((name, value)) = header_line.split_once(':') else {
+                return Err(ServerError::HttpParse(format!(
+                    "header line {:?} without a colon",
+                    header_line
+                )));
+            };
+            if !is_token(name) {
+                return Err(ServerError::HttpParse(format!(
+                    "invalid header name {:?}",
+                    name
+                )));
             }
+            let value = value.trim();
+            // Differing lengths leave it ambiguous where the body ends
+            if name.eq_ignore_ascii_case("content-length")
+                && find_header(&headers, name).is_some_and(|previous| previous != value)
+            {
+                return Err(ServerError::HttpParse(String::from(
+                    "conflicting Content-Length headers",
+                )));
+            }
+            // Names keep their case in the map, so which of several would be read
+            // back is arbitrary
+            if name.eq_ignore_ascii_case("transfer-encoding")
+                && find_header(&headers, name).is_some()
+            {
+                return Err(ServerError::HttpParse(String::from(
+                    "repeated Transfer-Encoding headers",
+                )));
+            }
+            headers.insert(name.to_string(), value.to_string());
+        }
+
+        // A front end framing the body by one header and us by the other is how
+        // requests get smuggled, so neither is trusted
+        if find_header(&headers, "transfer-encoding").is_some()
+            && find_header(&headers, "content-length").is_some()
+        {
+            return Err(ServerError::HttpParse(String::from(
+                "both Transfer-Encoding and Content-Length present",
+            )));
+        }
+        if let Some(transfer_encoding) = find_header(&headers, "transfer-encoding") {
+            validate_transfer_encoding(transfer_encoding)?;
         }
 
         Ok(Request {
             method,
             path,
+            authority,
             version,
             headers,
-            body,
+            body: Vec::new(),
+            peer_addr: None,
         })
     }
 
-    // Support for case insensitive header lookup
-    pub fn get_header(&self, name: &str) -> Option<&String> {
-        for (key, value) in &self.headers {
-            if key.to_lowercase() == name.to_lowercase() {
-                return Some(value);
+    /// Reads the body announced by the headers parsed with `read_head`, see
+    /// `from_reader` for when `send_continue` is called
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &RequestLimits,
+        send_continue: &mut dyn FnMut(&mut R) -> io::Result<()>,
+    ) -> Result<()> {
+        // `read_head` only lets through a Transfer-Encoding that is exactly chunked
+        let chunked = find_header(&self.headers, "transfer-encoding").is_some();
+
+        let expects_continue = self.version == Version::HTTP1_1
+            && find_header(&self.headers, "expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+
+        if chunked {
+            if expects_continue {
+                send_continue(reader)?;
+            }
+            self.body = read_chunked_body(reader, limits)?;
+        } else if let Some(content_length) = find_header(&self.headers, "content-length") {
+            let content_length = content_length.trim();
+            if content_length.is_empty() || !content_length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ServerError::HttpParse(format!(
+                    "invalid Content-Length {:?}",
+                    content_length
+                )));
+            }
+            // All digits but unparsable means it overflowed, which is too large either way
+            let length = match content_length.parse::<usize>() {
+                Ok(length) if length <= limits.max_body_size => length,
+                _ => {
+                    return Err(ServerError::ContentTooLarge(format!(
+                        "Content-Length {} exceeds the limit of {} bytes",
+                        content_length, limits.max_body_size
+                    )));
+                }
+            };
+
+            if expects_continue && length > 0 {
+                send_continue(reader)?;
             }
+            self.body = read_exact_body(reader, length)?;
         }
-        None
+
+        Ok(())
+    }
+
+    // Support for case insensitive header lookup
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        find_header(&self.headers, name)
+    }
+
+    /// `Content-Length` as a number, `None` when absent or not a valid length
+    pub fn content_length(&self) -> Option<usize> {
+        self.get_header("content-length")?.trim().parse().ok()
+    }
+
+    /// Media type from `Content-Type` without any parameters, e.g. `text/html`
+    /// for `text/html; charset=utf-8`
+    pub fn content_type(&self) -> Option<&str> {
+        let value = self.get_header("content-type")?;
+        Some(value.split(';').next().unwrap_or(value).trim())
+    }
+
+    /// Whether `option` is one of the comma separated `Connection` header options
+    fn has_connection_option(&self, option: &str) -> bool {
+        self.get_header("connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        })
     }
 
     pub fn wants_keep_alive(&self) -> bool {
         match self.version {
-            Version::HTTP1_1 => {
-                if let Some(connection) = self.get_header("connection") {
-                    !connection.to_lowercase().contains("close")
-                } else {
-                    // Default for HTTP/1.1 is Keep-Alive
-                    true
-                }
-            }
-            Version::HTTP1_0 => {
-                if let Some(connection) = self.get_header("connection") {
-                    connection.to_lowercase().contains("keep-alive")
-                } else {
-                    // Default for HTTP/1.0 is to close
-                    false
-                }
-            }
+            // Default for HTTP/1.1 is Keep-Alive
+            Version::HTTP1_1 => !self.has_connection_option("close"),
+            // Default for HTTP/1.0 is to close
+            Version::HTTP1_0 => self.has_connection_option("keep-alive"),
             _ => false,
         }
     }
@@ -126,13 +311,296 @@ impl Request {
         }
         None
     }
+
+    /// Cookies from the `Cookie` header. Values are returned as sent, minus any
+    /// surrounding quotes; malformed pairs are skipped
+    pub fn cookies(&self) -> HashMap<String, String> {
+        let Some(header) = self.get_header("cookie") else {
+            return HashMap::new();
+        };
+
+        header
+            .split(';')
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// HTTP/1.0 or HTTP/1.1. Any other well-formed `HTTP/x.y` is a version we
+/// don't speak, anything else isn't a version at all
+fn parse_version(version: &str) -> Result<Version> {
+    match Version::from(version) {
+        version @ (Version::HTTP1_0 | Version::HTTP1_1) => Ok(version),
+        _ => {
+            let well_formed = version.strip_prefix("HTTP/").is_some_and(|number| {
+                let number = number.as_bytes();
+                number.len() == 3
+                    && number[0].is_ascii_digit()
+                    && number[1] == b'.'
+                    && number[2].is_ascii_digit()
+            });
+            if well_formed {
+                Err(ServerError::VersionNotSupported(version.to_string()))
+            } else {
+                Err(ServerError::HttpParse(format!(
+                    "invalid HTTP version {:?}",
+                    version
+                )))
+            }
+        }
+    }
+}
+
+/// Accepts `Transfer-Encoding: chunked` and nothing else. Without chunked as
+/// the final coding the body has no delimiter, and any other coding is one we
+/// can't decode, so reading on would desync from the client
+fn validate_transfer_encoding(value: &str) -> Result<()> {
+    let codings: Vec<&str> = value.split(',').map(str::trim).collect();
+    if !codings
+        .last()
+        .is_some_and(|last| last.eq_ignore_ascii_case("chunked"))
+    {
+        return Err(ServerError::HttpParse(format!(
+            "Transfer-Encoding {:?} does not end with chunked",
+            value
+        )));
+    }
+    match codings.iter().find(|c| !c.eq_ignore_ascii_case("chunked")) {
+        Some(coding) => Err(ServerError::NotImplemented(format!(
+            "transfer coding {:?}",
+            coding
+        ))),
+        None if codings.len() > 1 => Err(ServerError::HttpParse(String::from(
+            "chunked applied more than once",
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Rejects targets that aren't origin-form paths, except `*` for OPTIONS, and
+/// any with control characters, which could end up in a header or a file name
+fn validate_path(method: Method, path: &str) -> Result<()> {
+    if path.chars().any(char::is_control) {
+        return Err(ServerError::HttpParse(format!(
+            "control character in request target {:?}",
+            path
+        )));
+    }
+    let asterisk_form = method == Method::OPTIONS && path == "*";
+    if !path.starts_with('/') && !asterisk_form {
+        return Err(ServerError::HttpParse(format!(
+            "request target {:?} doesn't start with /",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// Splits an absolute-form target into its authority and path plus query,
+/// other targets are returned as the path unchanged
+fn split_request_target(target: &str) -> (Option<String>, String) {
+    let absolute = target.split_once("://").filter(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    let Some((_, rest)) = absolute else {
+        return (None, target.to_string());
+    };
+
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+
+    (Some(authority.to_string()), path)
+}
+
+/// Whether `name` is a non-empty RFC 7230 token, which header names must be.
+/// Rules out spaces before the colon and names with separators
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a String> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// Reads exactly `length` body bytes. Anything the client sends beyond them is
+/// left in `reader` as the start of the next request
+fn read_exact_body<R: BufRead>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    let mut body = vec![0; length];
+    fill_body(reader, &mut body)?;
+    Ok(body)
+}
+
+/// Fills `buf` from `reader`, reporting a slow client as a timeout and one that
+/// stops early as a truncated body
+fn fill_body<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    let length = buf.len();
+    let mut received = 0;
+    while received < length {
+        match reader.read(&mut buf[received..]) {
+            Ok(0) => {
+                return Err(ServerError::HttpParse(format!(
+                    "request body truncated, received {} of {} bytes",
+                    received, length
+                )));
+            }
+            Ok(n) => received += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(ServerError::Timeout(format!(
+                    "request body incomplete, received {} of {} bytes",
+                    received, length
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Size from a chunk size line: hex digits, optionally followed by `;` and
+/// extensions, which are ignored. No sign or whitespace, so every parser in
+/// front of us reads the same size
+fn parse_chunk_size(line: &str) -> Result<usize> {
+    let digits = line.split(';').next().unwrap_or_default();
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ServerError::HttpParse(format!(
+            "Invalid chunk size line {:?}",
+            line
+        )));
+    }
+    usize::from_str_radix(digits, 16)
+        .map_err(|_| ServerError::HttpParse(format!("Invalid chunk size '{}'", digits)))
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, consuming any trailers so the
+/// reader is left at the start of the next request
+fn read_chunked_body<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        let mut size_line = String::new();
+        if !read_line_bounded(reader, &mut size_line, limits.max_header_line_length)? {
+            return Err(ServerError::HttpParse(
+                "Chunk size line too long".to_string(),
+            ));
+        }
+        if size_line.is_empty() {
+            return Err(ServerError::HttpParse(
+                "Unexpected end of chunked body".to_string(),
+            ));
+        }
+
+        let size = parse_chunk_size(size_line.trim_end_matches(['\r', '\n']))?;
+
+        if size == 0 {
+            break;
+        }
+
+        // A huge size from the client mustn't overflow the sum
+        if size > limits.max_body_size.saturating_sub(body.len()) {
+            return Err(ServerError::ContentTooLarge(format!(
+                "chunked body exceeds the limit of {} bytes",
+                limits.max_body_size
+            )));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        fill_body(reader, &mut body[start..])?;
+
+        let mut crlf = [0; 2];
+        fill_body(reader, &mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(ServerError::HttpParse(
+                "Chunk data not followed by CRLF".to_string(),
+            ));
+        }
+    }
+
+    // Trailers, terminated by an empty line. They're discarded, so together they
+    // get no more room than a single header line
+    let mut trailer_count = 0;
+    let mut trailer_size = 0;
+    loop {
+        let mut trailer = String::new();
+        if !read_line_bounded(reader, &mut trailer, limits.max_header_line_length)? {
+            return Err(ServerError::HeaderFieldsTooLarge(format!(
+                "trailer line exceeds {} bytes",
+                limits.max_header_line_length
+            )));
+        }
+        if trailer.trim().is_empty() {
+            break;
+        }
+
+        trailer_count += 1;
+        trailer_size += trailer.len();
+        if trailer_count > limits.max_header_count {
+            return Err(ServerError::HeaderFieldsTooLarge(format!(
+                "more than {} trailers",
+                limits.max_header_count
+            )));
+        }
+        if trailer_size > limits.max_header_line_length {
+            return Err(ServerError::HeaderFieldsTooLarge(format!(
+                "trailers exceed {} bytes",
+                limits.max_header_line_length
+            )));
+        }
+    }
+
+    Ok(body)
+}
+
+/// Reads a single line into `line`, replacing what it held, without buffering
+/// more than `limit` bytes of it. Returns false when the line (excluding the
+/// line ending) is longer than that
+fn read_line_bounded<R: BufRead>(reader: &mut R, line: &mut String, limit: usize) -> Result<bool> {
+    line.clear();
+    reader.take(limit as u64 + 2).read_line(line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).len() <= limit)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::io::{self, Cursor, Read};
 
-    use crate::http::{Method, Version, request::Request};
+    use crate::error::{ServerError, error_to_response};
+    use crate::http::request::{Request, RequestLimits};
+    use crate::http::{Method, StatusCode, Version};
 
     #[test]
     fn test_request_from_stream_valid() {
@@ -147,4 +615,588 @@ mod tests {
         assert_eq!(request.body, b"Hello");
         assert_eq!(request.get_header("Content-Length"), Some(&"5".to_string()))
     }
+
+    #[test]
+    fn test_read_head_reuses_line_buffer() {
+        let raw = b"GET /first HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n\
+                    GET /second HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test\r\n\r\n";
+        let mut reader = Cursor::new(&raw[..]);
+        let limits = RequestLimits::default();
+        let mut line = String::with_capacity(128);
+        let buffer = line.as_ptr();
+
+        let first = Request::read_head_with_buffer(&mut reader, &limits, &mut line).unwrap();
+        let second = Request::read_head_with_buffer(&mut reader, &limits, &mut line).unwrap();
+
+        assert_eq!(first.path, "/first");
+        assert_eq!(first.get_header("Accept"), Some(&"*/*".to_string()));
+        assert_eq!(second.path, "/second");
+        assert_eq!(second.get_header("User-Agent"), Some(&"test".to_string()));
+        // Every line of both requests went through the same allocation
+        assert_eq!(line.as_ptr(), buffer);
+        assert_eq!(line.capacity(), 128);
+    }
+
+    #[test]
+    fn test_request_body_over_limit_rejected() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 11\r\n\r\nHello World";
+        let limits = RequestLimits {
+            max_body_size: 10,
+            ..RequestLimits::default()
+        };
+
+        let result = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits);
+        assert!(matches!(result, Err(ServerError::ContentTooLarge(_))));
+    }
+
+    #[test]
+    fn test_request_body_at_limit_accepted() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\nHelloWorld";
+        let limits = RequestLimits {
+            max_body_size: 10,
+            ..RequestLimits::default()
+        };
+
+        let request = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits).unwrap();
+        assert_eq!(request.body, b"HelloWorld");
+    }
+
+    #[test]
+    fn test_request_content_length_overflow_rejected() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 99999999999999999999999999\r\n\r\n";
+
+        let result = Request::from_stream(&mut Cursor::new(raw));
+        assert!(matches!(result, Err(ServerError::ContentTooLarge(_))));
+    }
+
+    #[test]
+    fn test_request_line_too_long_rejected() {
+        let raw = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100));
+        let limits = RequestLimits {
+            max_request_line_length: 64,
+            ..RequestLimits::default()
+        };
+
+        let err = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits)
+            .err()
+            .unwrap();
+        assert_eq!(error_to_response(&err).status, StatusCode::UriTooLong);
+    }
+
+    #[test]
+    fn test_too_many_headers_rejected() {
+        let headers: String = (0..10)
+            .map(|i| format!("X-Header-{}: {}\r\n", i, i))
+            .collect();
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        let limits = RequestLimits {
+            max_header_count: 5,
+            ..RequestLimits::default()
+        };
+
+        let err = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error_to_response(&err).status,
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+    }
+
+    #[test]
+    fn test_header_line_too_long_rejected() {
+        let raw = format!("GET / HTTP/1.1\r\nX-Large: {}\r\n\r\n", "a".repeat(100));
+        let limits = RequestLimits {
+            max_header_line_length: 64,
+            ..RequestLimits::default()
+        };
+
+        let result = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits);
+        assert!(matches!(result, Err(ServerError::HeaderFieldsTooLarge(_))));
+    }
+
+    #[test]
+    fn test_request_chunked_body() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nHello\r\n7;ext=1\r\n, World\r\n0\r\nX-Trailer: done\r\n\r\n";
+
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.body, b"Hello, World");
+    }
+
+    #[test]
+    fn test_request_malformed_chunk_size_rejected() {
+        let raw =
+            b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nHello\r\n0\r\n\r\n";
+
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_oversized_chunk_size_rejected() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nHello\r\nffffffffffffffff\r\nHello\r\n0\r\n\r\n";
+
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert_eq!(error_to_response(&err).status, StatusCode::ContentTooLarge);
+    }
+
+    #[test]
+    fn test_too_many_trailers_rejected() {
+        let trailers: String = (0..10)
+            .map(|i| format!("X-Trailer-{}: {}\r\n", i, i))
+            .collect();
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{}\r\n",
+            trailers
+        );
+        let limits = RequestLimits {
+            max_header_count: 5,
+            ..RequestLimits::default()
+        };
+
+        let result = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits);
+        assert!(matches!(result, Err(ServerError::HeaderFieldsTooLarge(_))));
+    }
+
+    #[test]
+    fn test_oversized_trailers_rejected() {
+        let trailers: String = (0..4)
+            .map(|i| format!("X-Trailer-{}: {}\r\n", i, "a".repeat(40)))
+            .collect();
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{}\r\n",
+            trailers
+        );
+        let limits = RequestLimits {
+            max_header_line_length: 64,
+            ..RequestLimits::default()
+        };
+
+        let result = Request::from_stream_with_limits(&mut Cursor::new(raw), &limits);
+        assert!(matches!(result, Err(ServerError::HeaderFieldsTooLarge(_))));
+    }
+
+    #[test]
+    fn test_request_cookies() {
+        let raw =
+            b"GET / HTTP/1.1\r\nCookie: a=1; b=hello%20world;  c=\"quoted\"; broken; =x\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+
+        let cookies = request.cookies();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies.get("a"), Some(&"1".to_string()));
+        assert_eq!(cookies.get("b"), Some(&"hello%20world".to_string()));
+        assert_eq!(cookies.get("c"), Some(&"quoted".to_string()));
+    }
+
+    #[test]
+    fn test_request_without_cookies() {
+        let raw = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+
+        assert!(request.cookies().is_empty());
+    }
+
+    #[test]
+    fn test_request_absolute_form_target() {
+        let raw = b"GET http://example.com:8080/page?x=1 HTTP/1.1\r\nHost: other.test\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.path, "/page?x=1");
+        assert_eq!(request.authority.as_deref(), Some("example.com:8080"));
+
+        let raw = b"GET http://example.com HTTP/1.1\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.path, "/");
+
+        let raw = b"GET /page HTTP/1.1\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.path, "/page");
+        assert_eq!(request.authority, None);
+    }
+
+    #[test]
+    fn test_request_expect_continue() {
+        let raw = b"PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello";
+        let mut continued = false;
+        let request = Request::from_reader(
+            &mut Cursor::new(raw),
+            &RequestLimits::default(),
+            &mut |_| {
+                continued = true;
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert!(continued, "100 Continue was not sent");
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_request_expect_continue_over_limit() {
+        let raw = b"PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 100\r\n\r\n";
+        let limits = RequestLimits {
+            max_body_size: 10,
+            ..RequestLimits::default()
+        };
+        let mut continued = false;
+        let result = Request::from_reader(&mut Cursor::new(raw), &limits, &mut |_| {
+            continued = true;
+            Ok(())
+        });
+
+        assert!(matches!(result, Err(ServerError::ContentTooLarge(_))));
+        assert!(!continued, "100 Continue sent for a rejected body");
+    }
+
+    #[test]
+    fn test_request_line_without_version() {
+        let err = Request::from_stream(&mut Cursor::new(b"GET /\r\n\r\n"))
+            .err()
+            .unwrap();
+
+        assert!(matches!(err, ServerError::VersionNotSupported(_)));
+        assert!(
+            err.to_string().contains("HTTP/0.9"),
+            "unexpected error: {}",
+            err
+        );
+        assert_eq!(
+            error_to_response(&err).status,
+            StatusCode::HttpVersionNotSupported
+        );
+    }
+
+    #[test]
+    fn test_request_line_single_token() {
+        let err = Request::from_stream(&mut Cursor::new(b"GARBAGE\r\n\r\n"))
+            .err()
+            .unwrap();
+
+        assert!(matches!(err, ServerError::HttpParse(_)));
+        assert!(
+            err.to_string()
+                .contains("malformed request line \"GARBAGE\""),
+            "unexpected error: {}",
+            err
+        );
+        assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_request_from_reader_leaves_next_request_buffered() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                    POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+                    GET /c HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(raw);
+        let limits = RequestLimits::default();
+
+        let first = Request::from_reader(&mut reader, &limits, &mut |_| Ok(())).unwrap();
+        let second = Request::from_reader(&mut reader, &limits, &mut |_| Ok(())).unwrap();
+        let third = Request::from_reader(&mut reader, &limits, &mut |_| Ok(())).unwrap();
+
+        assert_eq!(
+            (first.path.as_str(), first.body.as_slice()),
+            ("/a", &b"hello"[..])
+        );
+        assert_eq!(
+            (second.path.as_str(), second.body.as_slice()),
+            ("/b", &b"abc"[..])
+        );
+        assert_eq!(third.path, "/c");
+        assert_eq!(reader.position() as usize, raw.len());
+    }
+
+    #[test]
+    fn test_content_length() {
+        let mut request =
+            Request::from_stream(&mut Cursor::new(b"GET / HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!(request.content_length(), None);
+
+        request
+            .headers
+            .insert(String::from("Content-Length"), String::from(" 42 "));
+        assert_eq!(request.content_length(), Some(42));
+
+        for malformed in ["", "-1", "4 2", "abc", "99999999999999999999999999"] {
+            request
+                .headers
+                .insert(String::from("Content-Length"), String::from(malformed));
+            assert_eq!(request.content_length(), None, "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        let raw = b"POST /form HTTP/1.1\r\nContent-Type: text/html; charset=utf-8\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.content_type(), Some("text/html"));
+
+        let raw = b"POST /form HTTP/1.1\r\ncontent-type: application/json\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.content_type(), Some("application/json"));
+
+        let request = Request::from_stream(&mut Cursor::new(b"GET / HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!(request.content_type(), None);
+    }
+
+    #[test]
+    fn test_truncated_body_rejected() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\nHello";
+
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+        assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_invalid_content_length_rejected() {
+        for length in ["abc", "-5", "5, 5", ""] {
+            let raw = format!(
+                "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\nHello",
+                length
+            );
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_bytes_past_content_length_start_next_request() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloEXTRA\r\n";
+        let mut reader = Cursor::new(raw);
+        let limits = RequestLimits::default();
+
+        let first = Request::from_reader(&mut reader, &limits, &mut |_| Ok(())).unwrap();
+        assert_eq!(first.body, b"hello");
+
+        let next = Request::from_reader(&mut reader, &limits, &mut |_| Ok(()));
+        assert!(matches!(next, Err(ServerError::HttpParse(_))));
+    }
+
+    #[test]
+    fn test_content_length_with_transfer_encoding_rejected() {
+        let raw =
+            b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n0\r\n\r\n";
+
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+        assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_duplicate_content_length() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 6\r\n\r\nhello!";
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+        assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_malformed_header_lines_rejected() {
+        for header in [
+            "X-Long: first\r\n  second",
+            "X-Long: first\r\n\tsecond",
+            ": no name",
+            "Bad Name: value",
+            "Host : localhost",
+            "no colon",
+        ] {
+            let raw = format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header);
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_invalid_request_target_rejected() {
+        for target in ["/index\0.html", "/a\x1bb", "index.html", "*"] {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(matches!(err, ServerError::HttpParse(_)), "{:?}", err);
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+
+        let raw = b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            Request::from_stream(&mut Cursor::new(raw)).unwrap().path,
+            "*"
+        );
+    }
+
+    #[test]
+    fn test_transfer_encoding_must_end_with_chunked() {
+        for coding in [
+            "gzip",
+            "chunked, gzip",
+            "chunkedx",
+            "x-chunked",
+            "",
+            "chunked, chunked",
+        ] {
+            let raw = format!(
+                "POST /upload HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n\
+                 GET /big.bin HTTP/1.1\r\n\r\n",
+                coding
+            );
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::HttpParse(_)),
+                "{}: {:?}",
+                coding,
+                err
+            );
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_unknown_transfer_coding_not_implemented() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                    5\r\nhello\r\n0\r\n\r\n";
+
+        let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+        assert!(matches!(err, ServerError::NotImplemented(_)), "{:?}", err);
+        assert_eq!(error_to_response(&err).status, StatusCode::NotImplemented);
+
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding:  Chunked \r\n\r\n\
+                    5\r\nhello\r\n0\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_request_line_extra_tokens_rejected() {
+        for line in [
+            "GET /index.html x HTTP/1.1",
+            "GET /index.html HTTP/1.1 junk",
+            "GET /index.html HTTP/1.1 HTTP/1.1",
+        ] {
+            let raw = format!("{}\r\nHost: localhost\r\n\r\n", line);
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::HttpParse(_)),
+                "{}: {:?}",
+                line,
+                err
+            );
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_request_line_unsupported_version() {
+        for version in ["HTTP/9.9", "HTTP/2.0", "HTTP/0.9", "HTTP/3.0"] {
+            let raw = format!("GET /index.html {}\r\nHost: localhost\r\n\r\n", version);
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::VersionNotSupported(_)),
+                "{}: {:?}",
+                version,
+                err
+            );
+            assert_eq!(
+                error_to_response(&err).status,
+                StatusCode::HttpVersionNotSupported
+            );
+        }
+
+        for version in ["junk", "HTTP/1.1x", "http/1.1", "HTTP/11"] {
+            let raw = format!("GET /index.html {}\r\nHost: localhost\r\n\r\n", version);
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::HttpParse(_)),
+                "{}: {:?}",
+                version,
+                err
+            );
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+
+        let raw = b"GET /index.html HTTP/1.0\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.version, Version::HTTP1_0);
+    }
+
+    #[test]
+    fn test_repeated_transfer_encoding_rejected() {
+        for second in ["transfer-encoding: identity", "Transfer-Encoding: chunked"] {
+            let raw = format!(
+                "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n{}\r\n\r\n\
+                 5\r\nhello\r\n0\r\n\r\n",
+                second
+            );
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::HttpParse(_)),
+                "{}: {:?}",
+                second,
+                err
+            );
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_lenient_chunk_size_rejected() {
+        for size in ["+5", " 5", "5 ", "0x5", "5 ;ext", ""] {
+            let raw = format!(
+                "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 {}\r\nhello\r\n0\r\n\r\n",
+                size
+            );
+
+            let err = Request::from_stream(&mut Cursor::new(raw)).err().unwrap();
+            assert!(
+                matches!(err, ServerError::HttpParse(_)),
+                "{:?}: {:?}",
+                size,
+                err
+            );
+            assert_eq!(error_to_response(&err).status, StatusCode::BadRequest);
+        }
+
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;name=value\r\nhello\r\n0\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_stalled_chunk_data_is_timeout() {
+        struct Stalled<'a>(&'a [u8]);
+
+        impl Read for Stalled<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled"));
+                }
+                let n = self.0.len().min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel";
+        let err = Request::from_stream(&mut Stalled(raw)).err().unwrap();
+        assert!(matches!(err, ServerError::Timeout(_)), "{:?}", err);
+    }
 }
@@ -1,32 +1,137 @@
 use std::collections::HashMap;
-use std::io::Write;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::cookie::CookieAttrs;
+use super::mime::{content_type_for, is_text};
 use super::{StatusCode, Version};
 use crate::error::Result;
 
+/// Files up to this size are read into `body`, larger ones are sent from `body_file`
+const IN_MEMORY_FILE_SIZE: u64 = 64 * 1024;
+
 pub struct Response {
     pub version: Version,
     pub status: StatusCode,
+    /// Sent instead of the status's standard reason phrase, e.g. one relayed
+    /// from a proxied upstream
+    pub reason_phrase: Option<String>,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    /// Body streamed instead of `body`, chunked unless its length is known up front
+    pub body_reader: Option<Box<dyn Read + Send>>,
+    /// File sent as the body, `Content-Length` bytes of it. Kept open rather than
+    /// read so connections can hand it to the kernel to copy
+    pub body_file: Option<File>,
+    /// `Set-Cookie` values, kept apart from `headers` since each cookie needs its own line
+    pub cookies: Vec<String>,
 }
 
+/// `Server` header sent unless configured otherwise
+pub const DEFAULT_SERVER_HEADER: &str = "Xener/0.0.1";
+
 impl Response {
     pub fn new() -> Self {
         let mut headers = HashMap::new();
         headers.insert(String::from("Content-Type"), String::from("text/html"));
-        headers.insert(String::from("Server"), String::from("Xener/0.0.1"));
+        headers.insert(String::from("Server"), String::from(DEFAULT_SERVER_HEADER));
 
         Self {
             version: Version::HTTP1_1,
             status: StatusCode::Ok,
+            reason_phrase: None,
             headers,
             body: Vec::new(),
+            body_reader: None,
+            body_file: None,
+            cookies: Vec::new(),
+        }
+    }
+
+    /// 200 response with `value` serialized as the JSON body
+    pub fn json<T: Serialize>(value: &T) -> Result<Response> {
+        let body = serde_json::to_vec(value)?;
+        Ok(Response::new()
+            .with_content_type("application/json")
+            .with_body(body))
+    }
+
+    /// 200 response with the contents of the file at `path`. A missing file is an
+    /// error of kind `NotFound`
+    pub fn from_file(path: &Path) -> io::Result<Response> {
+        Response::from_open_file(File::open(path)?, path)
+    }
+
+    /// Like `from_file` for `file` already opened from `path`. Size and
+    /// modification time come from the open handle, so they describe the
+    /// contents actually sent even if `path` has since been replaced
+    pub fn from_open_file(mut file: File, path: &Path) -> io::Result<Response> {
+        let metadata = file.metadata()?;
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let response = Response::new().with_content_type(content_type_for(path));
+
+        let (mut response, etag) = if len > IN_MEMORY_FILE_SIZE {
+            (response.with_body_file(file, len), None)
+        } else {
+            let mut body = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut body)?;
+            let etag = content_etag(&body);
+            (response.with_body(body), Some(etag))
+        };
+
+        if let Some(modified) = modified {
+            // Streamed files are never read up front, so they only get the weak
+            // validator from their metadata
+            let etag = etag.unwrap_or_else(|| file_etag(len, modified));
+            response = response
+                .with_header(
+                    "Last-Modified",
+                    &format_http_date(DateTime::<Utc>::from(modified)),
+                )
+                .with_header("ETag", &etag);
+        } else if let Some(etag) = etag {
+            response = response.with_header("ETag", &etag);
         }
+
+        Ok(response)
+    }
+
+    /// Redirects to `location` with a small HTML body linking to it for clients
+    /// that don't follow redirects. Panics if `status` isn't a 3xx code
+    pub fn redirect(location: &str, status: StatusCode) -> Response {
+        assert!(
+            status.is_redirection(),
+            "redirect status must be 3xx, got {}",
+            status.code()
+        );
+
+        let location_html = escape_html(location);
+        Response::new()
+            .with_status(status)
+            .with_header("Location", location)
+            .with_text(&format!(
+                "<html><body><p>{}. Redirecting to <a href=\"{}\">{}</a></p></body></html>",
+                status.status_text(),
+                location_html,
+                location_html
+            ))
     }
 
     pub fn with_status(mut self, status: StatusCode) -> Self {
         self.status = status;
+        self.reason_phrase = None;
+        self
+    }
+
+    /// Sends `reason` in the status line in place of the standard reason phrase
+    pub fn with_reason_phrase(mut self, reason: &str) -> Self {
+        self.reason_phrase = Some(reason.to_string());
         self
     }
 
@@ -35,10 +140,104 @@ impl Response {
         self
     }
 
+    /// Sets every header in `headers`, in order, so a later value for the same
+    /// name replaces an earlier one
+    pub fn with_headers<I: IntoIterator<Item = (String, String)>>(mut self, headers: I) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Lists `field` in `Vary` as a request header the response depends on,
+    /// unless it's already there
+    pub fn add_vary(&mut self, field: &str) {
+        let Some(vary) = self.headers.get_mut("Vary") else {
+            self.headers.insert(String::from("Vary"), field.to_string());
+            return;
+        };
+        // `*` already covers every field
+        if !vary
+            .split(',')
+            .any(|listed| listed.trim() == "*" || listed.trim().eq_ignore_ascii_case(field))
+        {
+            vary.push_str(", ");
+            vary.push_str(field);
+        }
+    }
+
+    /// Adds a `Set-Cookie` header, keeping any cookies set before
+    pub fn with_cookie(mut self, name: &str, value: &str, attrs: CookieAttrs) -> Self {
+        self.cookies.push(attrs.serialize(name, value));
+        self
+    }
+
     pub fn with_body(mut self, body: Vec<u8>) -> Self {
         self.headers
             .insert(String::from("Content-Length"), body.len().to_string());
         self.body = body;
+        self.body_file = None;
+        self
+    }
+
+    /// Streams the body from `reader` using `Transfer-Encoding: chunked`, for content
+    /// whose length isn't known up front
+    pub fn with_body_reader(mut self, reader: Box<dyn Read + Send>) -> Self {
+        self.headers.remove("Content-Length");
+        self.headers
+            .insert(String::from("Transfer-Encoding"), String::from("chunked"));
+        self.body = Vec::new();
+        self.body_file = None;
+        self.body_reader = Some(reader);
+        self
+    }
+
+    /// Streams exactly `len` bytes from `reader` with a `Content-Length`, for
+    /// content whose length is known but that isn't a file
+    pub fn with_sized_body_reader(self, reader: Box<dyn Read + Send>, len: u64) -> Self {
+        self.with_body_reader(reader)
+            .without_chunked_encoding()
+            .with_header("Content-Length", &len.to_string())
+    }
+
+    /// Sends a `body_reader` as is instead of chunked, for HTTP/1.0 clients that
+    /// don't understand the framing. Only closing the connection marks its end
+    pub fn without_chunked_encoding(mut self) -> Self {
+        self.headers.remove("Transfer-Encoding");
+        self
+    }
+
+    /// Whether the body is framed with `Transfer-Encoding: chunked`
+    pub fn is_chunked(&self) -> bool {
+        self.headers.contains_key("Transfer-Encoding")
+    }
+
+    /// Sends `len` bytes of `file`, from its current position, as the body
+    pub fn with_body_file(mut self, file: File, len: u64) -> Self {
+        self.headers
+            .insert(String::from("Content-Length"), len.to_string());
+        self.body = Vec::new();
+        self.body_reader = None;
+        self.body_file = Some(file);
+        self
+    }
+
+    /// Number of body bytes sent, unknown (0) for chunked bodies
+    pub fn body_len(&self) -> usize {
+        match (&self.body_file, &self.body_reader) {
+            (None, None) => self.body.len(),
+            _ => self
+                .headers
+                .get("Content-Length")
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Drops the body for a HEAD request. `Content-Length` or `Transfer-Encoding`
+    /// stay as a GET would send them
+    pub fn without_body(mut self) -> Self {
+        self.body = Vec::new();
+        self.body_reader = None;
+        self.body_file = None;
         self
     }
 
@@ -53,6 +252,42 @@ impl Response {
     }
 
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.write_head_to(writer)?;
+
+        match &self.body_file {
+            Some(file) => {
+                io::copy(&mut file.take(self.body_len() as u64), writer)?;
+            }
+            None => writer.write_all(&self.body)?,
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the response with its body framed as chunks, draining `body_reader`
+    /// when set
+    pub fn write_chunked_to<W: Write>(&mut self, writer: &mut W) -> Result<()> {
+        self.write_head_to(writer)?;
+
+        let mut chunked = ChunkedWriter::new(&mut *writer);
+        match (self.body_reader.as_mut(), &self.body_file) {
+            (Some(reader), _) => {
+                io::copy(reader, &mut chunked)?;
+            }
+            (None, Some(file)) => {
+                io::copy(&mut file.take(self.body_len() as u64), &mut chunked)?;
+            }
+            (None, None) => chunked.write_all(&self.body)?,
+        }
+        chunked.finish()?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the status line and headers, leaving the body to the caller
+    pub fn write_head_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         let version: String = self.version.clone().into();
 
         write!(
@@ -60,17 +295,25 @@ impl Response {
             "{} {} {}\r\n",
             version,
             self.status.code(),
-            self.status.reason_phrase()
+            self.reason_phrase
+                .as_deref()
+                .unwrap_or_else(|| self.status.reason_phrase())
         )?;
 
         for (name, value) in &self.headers {
             write!(writer, "{}: {}\r\n", name, value)?;
         }
 
-        write!(writer, "\r\n")?; // Additional line between headers and body
+        for cookie in &self.cookies {
+            write!(writer, "Set-Cookie: {}\r\n", cookie)?;
+        }
 
-        writer.write_all(&self.body)?;
-        writer.flush()?;
+        // Stamped at write time so reused responses don't carry a stale date
+        if !self.headers.contains_key("Date") {
+            write!(writer, "Date: {}\r\n", http_date())?;
+        }
+
+        write!(writer, "\r\n")?; // Additional line between headers and body
 
         Ok(())
     }
@@ -112,13 +355,192 @@ impl Response {
             .insert(String::from("Cache-Control"), cache_value);
         self
     }
+
+    /// Replaces the `Server` header with `server`, or drops it when `server` is empty
+    pub fn with_server_header(mut self, server: &str) -> Self {
+        if server.is_empty() {
+            self.headers.remove("Server");
+        } else {
+            self.headers
+                .insert(String::from("Server"), server.to_string());
+        }
+        self
+    }
+
+    /// Appends `; charset=<charset>` to a text `Content-Type` that doesn't name a
+    /// charset yet. Binary types are left alone
+    pub fn with_default_charset(mut self, charset: &str) -> Self {
+        if let Some(content_type) = self.headers.get_mut("Content-Type")
+            && !charset.is_empty()
+            && is_text(content_type)
+            && !content_type.to_ascii_lowercase().contains("charset=")
+        {
+            content_type.push_str("; charset=");
+            content_type.push_str(charset);
+        }
+        self
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Current time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date() -> String {
+    format_http_date(Utc::now())
+}
+
+pub fn format_http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Weak validator for a file built from its modification time and size. Two
+/// versions written within the same second with the same size can't be told
+/// apart, so it mustn't be used where byte-for-byte equality matters, such as
+/// serving ranges
+pub fn file_etag(len: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    format!("W/\"{:x}-{:x}\"", modified, len)
+}
+
+/// Strong validator hashing the exact bytes of `body`, for bodies already in
+/// memory. FNV-1a keeps it stable across restarts and builds
+pub fn content_etag(body: &[u8]) -> String {
+    let hash = body.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("\"{:016x}-{:x}\"", hash, body.len())
+}
+
+/// Frames everything written through it as HTTP/1.1 chunks
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    /// Writes the terminating zero-size chunk
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Cursor;
+
+    use serde::{Deserialize, Serialize};
+
     use crate::http::StatusCode;
+    use crate::http::cookie::{CookieAttrs, SameSite};
+    use crate::http::request::Request;
     use crate::http::response::Response;
 
+    #[test]
+    fn test_with_headers_sets_all() {
+        let response = Response::new()
+            .with_header("X-Frame-Options", "DENY")
+            .with_headers([
+                (String::from("X-Frame-Options"), String::from("SAMEORIGIN")),
+                (String::from("X-Custom"), String::from("one")),
+                (String::from("X-Custom"), String::from("two")),
+                (String::from("Referrer-Policy"), String::from("no-referrer")),
+            ]);
+
+        let mut head = Vec::new();
+        response.write_head_to(&mut head).unwrap();
+        let head = String::from_utf8(head).unwrap();
+
+        for line in [
+            "X-Frame-Options: SAMEORIGIN\r\n",
+            "X-Custom: two\r\n",
+            "Referrer-Policy: no-referrer\r\n",
+        ] {
+            assert!(head.contains(line), "{:?} missing from {}", line, head);
+        }
+        assert!(!head.contains("DENY"), "{}", head);
+        assert!(!head.contains("X-Custom: one"), "{}", head);
+    }
+
+    #[test]
+    fn test_add_vary_deduplicates() {
+        let mut response = Response::new();
+        response.add_vary("Accept-Encoding");
+        response.add_vary("Origin");
+        response.add_vary("accept-encoding");
+
+        assert_eq!(
+            response.headers.get("Vary"),
+            Some(&"Accept-Encoding, Origin".to_string())
+        );
+
+        let mut response = Response::new().with_header("Vary", "*");
+        response.add_vary("Origin");
+        assert_eq!(response.headers.get("Vary"), Some(&"*".to_string()));
+    }
+
+    #[test]
+    fn test_default_charset_only_for_text() {
+        let html = Response::new()
+            .with_content_type("text/html")
+            .with_default_charset("utf-8");
+        assert_eq!(
+            html.headers.get("Content-Type"),
+            Some(&"text/html; charset=utf-8".to_string())
+        );
+
+        let image = Response::new()
+            .with_content_type("image/png")
+            .with_default_charset("utf-8");
+        assert_eq!(
+            image.headers.get("Content-Type"),
+            Some(&"image/png".to_string())
+        );
+
+        let explicit = Response::new()
+            .with_content_type("text/plain; charset=iso-8859-1")
+            .with_default_charset("utf-8");
+        assert_eq!(
+            explicit.headers.get("Content-Type"),
+            Some(&"text/plain; charset=iso-8859-1".to_string())
+        );
+    }
+
     #[test]
     fn test_response_write_to() {
         let response = Response::new()
@@ -137,4 +559,202 @@ mod tests {
         assert!(result.contains("X-Test: Xener Server"));
         assert!(result.contains("Hello!"));
     }
+
+    #[test]
+    fn test_response_write_chunked_to() {
+        let source: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let mut response = Response::new()
+            .with_content_type("application/octet-stream")
+            .with_body_reader(Box::new(Cursor::new(source.clone())));
+
+        let mut buf = Vec::new();
+        response.write_chunked_to(&mut buf).unwrap();
+
+        let head_end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&buf[..head_end]);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+
+        // Decode the framed body with the request parser
+        let mut raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend_from_slice(&buf[head_end..]);
+        let decoded = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+
+        assert_eq!(decoded.body, source);
+    }
+
+    #[test]
+    fn test_response_includes_date_header() {
+        let response = Response::new().with_text("hello");
+
+        let mut buf = Vec::new();
+        response.write_to(&mut buf).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+
+        let date = output
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .expect("missing Date header");
+        assert!(date.ends_with(" GMT"), "not a GMT date: {}", date);
+        let parsed = chrono::NaiveDateTime::parse_from_str(date, "%a, %d %b %Y %H:%M:%S GMT");
+        assert!(parsed.is_ok(), "invalid IMF-fixdate: {}", date);
+    }
+
+    #[test]
+    fn test_response_with_cookies() {
+        let response = Response::new()
+            .with_cookie(
+                "session",
+                "abc123",
+                CookieAttrs {
+                    path: Some(String::from("/")),
+                    http_only: true,
+                    same_site: Some(SameSite::Strict),
+                    ..CookieAttrs::default()
+                },
+            )
+            .with_cookie("theme", "dark", CookieAttrs::default());
+
+        let mut buf = Vec::new();
+        response.write_to(&mut buf).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+
+        let cookies: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "session=abc123; Path=/; HttpOnly; SameSite=Strict",
+                "theme=dark"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_response_redirect() {
+        let response = Response::redirect("/new?a=1&b=2", StatusCode::Found);
+
+        assert_eq!(response.status, StatusCode::Found);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&"/new?a=1&b=2".to_string())
+        );
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(
+            body.contains("<a href=\"/new?a=1&amp;b=2\">"),
+            "unexpected body: {}",
+            body
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "redirect status must be 3xx")]
+    fn test_response_redirect_rejects_non_3xx() {
+        let _ = Response::redirect("/new", StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_response_json() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Status {
+            name: String,
+            healthy: bool,
+            workers: u32,
+        }
+
+        let status = Status {
+            name: String::from("xener"),
+            healthy: true,
+            workers: 8,
+        };
+        let response = Response::json(&status)
+            .unwrap()
+            .with_status(StatusCode::Created);
+
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some(&"application/json".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Content-Length"),
+            Some(&response.body.len().to_string())
+        );
+        let decoded: Status = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(decoded, status);
+    }
+
+    #[test]
+    fn test_response_from_large_file_keeps_it_open() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("large.bin");
+        let content = vec![7u8; 100 * 1024];
+        std::fs::write(&path, &content).unwrap();
+
+        let response = Response::from_file(&path).unwrap();
+        assert!(response.body_file.is_some());
+        assert!(response.body.is_empty());
+        assert_eq!(response.body_len(), content.len());
+        assert!(
+            response
+                .headers
+                .get("ETag")
+                .is_some_and(|etag| etag.starts_with("W/\""))
+        );
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        assert!(output.ends_with(&content));
+        assert!(String::from_utf8_lossy(&output).contains("Content-Length: 102400\r\n"));
+    }
+
+    #[test]
+    fn test_response_from_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("style.css");
+        std::fs::write(&path, "body { color: red; }").unwrap();
+
+        let response = Response::from_file(&path).unwrap();
+
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.body, b"body { color: red; }");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some(&"text/css".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Content-Length"),
+            Some(&"20".to_string())
+        );
+        assert!(response.headers.contains_key("Last-Modified"));
+        assert!(
+            response
+                .headers
+                .get("ETag")
+                .is_some_and(|etag| etag.starts_with('"') && etag.ends_with("-14\""))
+        );
+
+        let missing = Response::from_file(&temp_dir.path().join("missing.css"));
+        assert_eq!(
+            missing.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_server_header() {
+        let response = Response::new();
+        assert_eq!(
+            response.headers.get("Server"),
+            Some(&"Xener/0.0.1".to_string())
+        );
+
+        let response = response.with_server_header("edge");
+        assert_eq!(response.headers.get("Server"), Some(&"edge".to_string()));
+
+        let response = response.with_server_header("");
+        assert!(!response.headers.contains_key("Server"));
+    }
 }
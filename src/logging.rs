@@ -1,56 +1,720 @@
+use std::borrow::Cow;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use chrono::Local;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
 use std::io::Write;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_subscriber::EnvFilter;
 
-pub fn init_logger() -> Result<(), Box<dyn Error>> {
+use crate::config::ServerConfig;
+use crate::http::request::Request;
+
+const DEFAULT_MAX_LOG_FILES: usize = 5;
+const DEFAULT_FLUSH_LINES: usize = 32;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Apache Common Log Format followed by the duration in milliseconds
+pub const COMMON_LOG_FORMAT: &str = "%h %l %u %t \"%r\" %>s %b %{ms}T";
+
+/// Common Log Format plus referer and user agent, followed by the duration in
+/// milliseconds
+pub const COMBINED_LOG_FORMAT: &str =
+    "%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\" %{ms}T";
+
+/// Installs the global tracing subscriber, writing JSON lines to `error_log_path`
+/// when the error log is enabled, otherwise to stderr. Writes happen on a
+/// background thread; keep the returned guard alive so they're flushed on exit.
+pub fn init_logger(config: &ServerConfig) -> Result<WorkerGuard, Box<dyn Error>> {
+    let (writer, guard) = error_log_writer(config)?;
+    tracing::subscriber::set_global_default(subscriber(writer))?;
+
+    Ok(guard)
+}
+
+fn error_log_writer(config: &ServerConfig) -> io::Result<(NonBlocking, WorkerGuard)> {
+    if config.error_log && !config.error_log_path.is_empty() {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&config.error_log_path)?;
+        Ok(tracing_appender::non_blocking(file))
+    } else {
+        Ok(tracing_appender::non_blocking(io::stderr()))
+    }
+}
+
+fn subscriber(writer: NonBlocking) -> impl Subscriber + Send + Sync {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .json()
-        .init();
-
-    Ok(())
+        .with_writer(writer)
+        .finish()
 }
 
 pub struct AccessLogger {
-    log_path: Option<std::path::PathBuf>,
+    log_path: Option<PathBuf>,
     access_log: bool,
+    strip_query: bool,
+    format: String,
+    /// One JSON object per line instead of `format`
+    json: bool,
+    rotation: Option<Rotation>,
+    flush_lines: usize,
+    flush_interval: Duration,
+    /// Open log file, the lock keeps concurrent lines and rotations from
+    /// interleaving. Lines go to stdout when there is none
+    file: Option<Mutex<LogFile>>,
+}
+
+struct Rotation {
+    max_size: u64,
+    max_files: usize,
+}
+
+/// The `BufWriter` flushes whatever is left when the logger is dropped
+struct LogFile {
+    writer: BufWriter<File>,
+    /// Bytes in the current file, tracked so rotation doesn't stat every line
+    written: u64,
+    unflushed_lines: usize,
+    last_flush: Instant,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        // An existing file counts towards the first rotation
+        let written = file.metadata()?.len();
+
+        Ok(LogFile {
+            writer: BufWriter::new(file),
+            written,
+            unflushed_lines: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            tracing::error!("Failed to flush access log: {}", e);
+        }
+        self.unflushed_lines = 0;
+        self.last_flush = Instant::now();
+    }
 }
 
 impl AccessLogger {
-    pub fn new(access_log: bool, log_path: Option<std::path::PathBuf>) -> Self {
+    pub fn new(access_log: bool, log_path: Option<PathBuf>) -> Self {
+        let log_path = log_path.filter(|path| !path.as_os_str().is_empty());
+        let file = match &log_path {
+            Some(path) if access_log => match LogFile::open(path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to open access log {}, logging to stdout: {}",
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+
         Self {
             access_log,
             log_path,
+            strip_query: false,
+            format: String::from(COMMON_LOG_FORMAT),
+            json: false,
+            rotation: None,
+            flush_lines: DEFAULT_FLUSH_LINES,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            file,
         }
     }
 
-    pub fn log(&self, client: &str, method: &str, path: &str, status: u16, size: usize) {
+    /// Rotates the log file once it reaches `max_size` bytes, keeping up to
+    /// `max_files` old logs as `access.log.1` (newest) to `access.log.N`
+    pub fn with_rotation(mut self, max_size: Option<u64>, max_files: Option<usize>) -> Self {
+        self.rotation = max_size.map(|max_size| Rotation {
+            max_size,
+            max_files: max_files.unwrap_or(DEFAULT_MAX_LOG_FILES),
+        });
+        self
+    }
+
+    /// Buffered lines are written out once `lines` have accumulated or
+    /// `interval` has passed since the last flush, whichever comes first
+    pub fn with_flush_policy(mut self, lines: Option<usize>, interval: Option<Duration>) -> Self {
+        self.flush_lines = lines.unwrap_or(DEFAULT_FLUSH_LINES).max(1);
+        self.flush_interval = interval.unwrap_or(DEFAULT_FLUSH_INTERVAL);
+        self
+    }
+
+    /// Sets the line format: `common`, `combined`, `json`, or an Apache style format
+    /// string using `%h %l %u %t %r %m %U %q %H %s %>s %b %L %{Header}i %{ms}T %%`,
+    /// where `%L` is the request ID and `%{ms}T` the duration in milliseconds. The
+    /// named formats leave out the request ID so standard parsers still read them
+    pub fn with_format(mut self, format: &str) -> Self {
+        self.json = format == "json";
+        self.format = match format {
+            "common" => String::from(COMMON_LOG_FORMAT),
+            "combined" => String::from(COMBINED_LOG_FORMAT),
+            custom => String::from(custom),
+        };
+        self
+    }
+
+    /// Omit everything after `?` from logged paths, query strings can carry tokens or PII
+    pub fn with_strip_query(mut self, strip_query: bool) -> Self {
+        self.strip_query = strip_query;
+        self
+    }
+
+    /// Records a request from `client` answered with `status` and `size` body
+    /// bytes, taking `duration` if known
+    pub fn log(
+        &self,
+        client: &str,
+        request: &Request,
+        status: u16,
+        size: usize,
+        duration: Option<Duration>,
+    ) {
         if !self.access_log {
             return;
         }
-        let now = Local::now();
-        let message = format!(
-            "{} - - [{}] \"{} {} HTTP/1.1\" {} {}",
+        let message = if self.json {
+            self.json_line(client, request, status, size, duration, Local::now())
+        } else {
+            self.format_line(client, request, status, size, duration, Local::now())
+        };
+
+        match &self.file {
+            Some(file) => self.append(&mut file.lock().unwrap(), &message),
+            None => println!("{}", message),
+        }
+    }
+
+    /// Flushes lines left in the buffer once `flush_interval` has passed since the
+    /// last flush, even when no further requests come in to trigger it. Returns
+    /// once the logger is dropped, or right away when appends already flush
+    pub fn watch(logger: Weak<Self>) {
+        while let Some(current) = logger.upgrade() {
+            let Some(wait) = current.flush_if_due() else {
+                return;
+            };
+            drop(current);
+            thread::sleep(wait);
+        }
+    }
+
+    /// Flushes lines that have waited `flush_interval` and returns how long until
+    /// the next check. `None` when there's no file or every line is flushed
+    /// right away
+    fn flush_if_due(&self) -> Option<Duration> {
+        if self.flush_interval.is_zero() {
+            return None;
+        }
+        let mut file = self.file.as_ref()?.lock().unwrap();
+        if file.unflushed_lines == 0 {
+            return Some(self.flush_interval);
+        }
+        match self.flush_interval.checked_sub(file.last_flush.elapsed()) {
+            Some(wait) if !wait.is_zero() => Some(wait),
+            _ => {
+                file.flush();
+                Some(self.flush_interval)
+            }
+        }
+    }
+
+    fn append(&self, file: &mut LogFile, message: &str) {
+        if writeln!(file.writer, "{}", message).is_ok() {
+            file.written += message.len() as u64 + 1;
+            file.unflushed_lines += 1;
+        }
+
+        if file.unflushed_lines >= self.flush_lines
+            || file.last_flush.elapsed() >= self.flush_interval
+        {
+            file.flush();
+        }
+
+        let (Some(rotation), Some(path)) = (&self.rotation, &self.log_path) else {
+            return;
+        };
+        if file.written >= rotation.max_size {
+            file.flush();
+            let reopened = rotate(path, rotation.max_files).and_then(|_| LogFile::open(path));
+            match reopened {
+                Ok(reopened) => *file = reopened,
+                Err(e) => {
+                    tracing::error!("Failed to rotate access log {}: {}", path.display(), e);
+                    file.written = 0;
+                }
+            }
+        }
+    }
+
+    fn json_line(
+        &self,
+        client: &str,
+        request: &Request,
+        status: u16,
+        size: usize,
+        duration: Option<Duration>,
+        now: DateTime<Local>,
+    ) -> String {
+        let method = request.method.to_string();
+        let line = JsonLine {
+            time: now.to_rfc3339_opts(SecondsFormat::Millis, false),
             client,
-            now.format("%d/%b/%Y:%H:%M:%S %z"),
-            method,
-            path,
+            method: &method,
+            path: self.logged_path(request),
+            protocol: request.version.as_str(),
             status,
-            size
-        );
+            size,
+            duration_ms: duration.map(|duration| duration.as_secs_f64() * 1000.0),
+            referer: request.get_header("referer").map(String::as_str),
+            user_agent: request.get_header("user-agent").map(String::as_str),
+            request_id: request.get_header("x-request-id").map(String::as_str),
+        };
+        // Only strings and numbers, which always serialize
+        serde_json::to_string(&line).unwrap_or_default()
+    }
 
-        if let Some(path) = &self.log_path {
-            if let Ok(mut file) = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(path)
-            {
-                let _ = writeln!(file, "{}", message);
-            }
+    fn logged_path<'a>(&self, request: &'a Request) -> &'a str {
+        if self.strip_query {
+            request.path.split('?').next().unwrap_or(&request.path)
         } else {
-            println!("{}", message);
+            &request.path
+        }
+    }
+
+    fn format_line(
+        &self,
+        client: &str,
+        request: &Request,
+        status: u16,
+        size: usize,
+        duration: Option<Duration>,
+        now: DateTime<Local>,
+    ) -> String {
+        let path = self.logged_path(request);
+
+        let mut line = String::with_capacity(self.format.len() * 2);
+        let mut chars = self.format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                line.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                Some('h') => line.push_str(client),
+                Some('l') | Some('u') => line.push('-'),
+                Some('t') => line.push_str(&format!("[{}]", now.format("%d/%b/%Y:%H:%M:%S %z"))),
+                Some('r') => line.push_str(&escape(&format!(
+                    "{} {} {}",
+                    request.method,
+                    path,
+                    request.version.as_str()
+                ))),
+                Some('m') => line.push_str(&request.method.to_string()),
+                Some('U') => {
+                    line.push_str(&escape(request.path.split('?').next().unwrap_or_default()))
+                }
+                Some('q') => {
+                    if let Some((_, query)) = path.split_once('?') {
+                        line.push('?');
+                        line.push_str(&escape(query));
+                    }
+                }
+                Some('H') => line.push_str(request.version.as_str()),
+                Some('s') => line.push_str(&status.to_string()),
+                Some('>') if chars.next_if_eq(&'s').is_some() => line.push_str(&status.to_string()),
+                Some('b') => line.push_str(&size.to_string()),
+                Some('L') => line.push_str(
+                    &request
+                        .get_header("x-request-id")
+                        .map_or(Cow::Borrowed("-"), |value| escape(value)),
+                ),
+                Some('{') => {
+                    let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    match chars.next() {
+                        Some('i') => line.push_str(
+                            &request
+                                .get_header(&name)
+                                .map_or(Cow::Borrowed("-"), |value| escape(value)),
+                        ),
+                        Some('T') if name == "ms" => match duration {
+                            Some(duration) => line.push_str(&duration.as_millis().to_string()),
+                            None => line.push('-'),
+                        },
+                        // Unsupported directive, kept as written
+                        other => {
+                            line.push_str(&format!("%{{{}}}", name));
+                            line.extend(other);
+                        }
+                    }
+                }
+                Some('%') => line.push('%'),
+                Some(other) => {
+                    line.push('%');
+                    line.push(other);
+                }
+                None => line.push('%'),
+            }
+        }
+
+        line
+    }
+}
+
+/// Escapes `"`, `\` and bytes that aren't printable ASCII as Apache does, so
+/// client supplied text can't end a quoted field or start a fake log line
+fn escape(value: &str) -> Cow<'_, str> {
+    let needs_escape = |b: u8| b == b'"' || b == b'\\' || !(b' '..=b'~').contains(&b);
+    if !value.bytes().any(needs_escape) {
+        return Cow::Borrowed(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len() + 8);
+    for b in value.bytes() {
+        match b {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            _ if needs_escape(b) => escaped.push_str(&format!("\\x{:02x}", b)),
+            _ => escaped.push(b as char),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    client: &'a str,
+    method: &'a str,
+    path: &'a str,
+    protocol: &'a str,
+    status: u16,
+    size: usize,
+    duration_ms: Option<f64>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    request_id: Option<&'a str>,
+}
+
+/// Shifts `path.1`..`path.N-1` up by one, dropping the oldest, and moves the
+/// current log to `path.1`
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+
+    for index in (1..max_files).rev() {
+        let from = numbered(path, index);
+        if from.exists() {
+            fs::rename(from, numbered(path, index + 1))?;
+        }
+    }
+    fs::rename(path, numbered(path, 1))
+}
+
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{AccessLogger, LogFile, error_log_writer, subscriber};
+    use crate::config::ServerConfig;
+    use crate::http::request::Request;
+
+    fn request(raw: &str) -> Request {
+        Request::from_stream(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap()
+    }
+
+    fn log_line(logger: AccessLogger, raw: &str) -> String {
+        log_request(logger, &request(raw))
+    }
+
+    fn log_request(logger: AccessLogger, request: &Request) -> String {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let logger = AccessLogger {
+            file: Some(Mutex::new(LogFile::open(&log_path).unwrap())),
+            log_path: Some(log_path.clone()),
+            ..logger
+        };
+        logger.log(
+            "127.0.0.1",
+            request,
+            200,
+            42,
+            Some(Duration::from_millis(5)),
+        );
+        drop(logger);
+
+        fs::read_to_string(log_path).unwrap()
+    }
+
+    #[test]
+    fn test_access_log_strip_query() {
+        let logger = AccessLogger::new(true, None).with_strip_query(true);
+        let line = log_line(logger, "GET /search?token=secret HTTP/1.1\r\n\r\n");
+        assert!(
+            line.contains("\"GET /search HTTP/1.1\""),
+            "unexpected line: {}",
+            line
+        );
+        assert!(!line.contains("token=secret"), "query string was logged");
+    }
+
+    #[test]
+    fn test_access_log_preserve_query() {
+        let logger = AccessLogger::new(true, None);
+        let line = log_line(logger, "GET /search?token=secret HTTP/1.1\r\n\r\n");
+        assert!(
+            line.contains("\"GET /search?token=secret HTTP/1.1\""),
+            "unexpected line: {}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_access_log_uses_request_version() {
+        let logger = AccessLogger::new(true, None);
+        let line = log_line(logger, "GET / HTTP/1.0\r\n\r\n");
+        assert!(
+            line.ends_with("\"GET / HTTP/1.0\" 200 42 5\n"),
+            "unexpected line: {}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_access_log_combined_format() {
+        let raw = "GET / HTTP/1.1\r\nReferer: http://example.com/\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let line = log_line(AccessLogger::new(true, None).with_format("combined"), raw);
+        assert!(
+            line.ends_with("200 42 \"http://example.com/\" \"curl/8.0\" 5\n"),
+            "unexpected line: {}",
+            line
+        );
+
+        let line = log_line(
+            AccessLogger::new(true, None).with_format("combined"),
+            "GET / HTTP/1.1\r\n\r\n",
+        );
+        assert!(
+            line.ends_with("200 42 \"-\" \"-\" 5\n"),
+            "unexpected line: {}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_access_log_escapes_client_text() {
+        let mut request = request("GET /a\"b HTTP/1.1\r\n\r\n");
+        request.headers.insert(
+            String::from("User-Agent"),
+            String::from("evil\" 200 1\n127.0.0.1 - - \\ \u{e9}"),
+        );
+        let line = log_request(
+            AccessLogger::new(true, None).with_format("combined"),
+            &request,
+        );
+
+        assert_eq!(line.lines().count(), 1, "{}", line);
+        assert!(line.contains("\"GET /a\\\"b HTTP/1.1\""), "{}", line);
+        assert!(
+            line.contains("\"evil\\\" 200 1\\x0a127.0.0.1 - - \\\\ \\xc3\\xa9\""),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_access_log_includes_request_id() {
+        let raw = "GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n";
+        let line = log_line(AccessLogger::new(true, None).with_format("%>s %L"), raw);
+        assert_eq!(line, "200 abc-123\n");
+
+        // Only when asked for, the named formats stay parseable as standard CLF
+        let line = log_line(AccessLogger::new(true, None), raw);
+        assert!(!line.contains("abc-123"), "unexpected line: {}", line);
+    }
+
+    #[test]
+    fn test_access_log_json_format() {
+        let raw = "GET /a?b=1 HTTP/1.1\r\nUser-Agent: curl/8.0\r\nX-Request-Id: abc-123\r\n\r\n";
+        let line = log_line(AccessLogger::new(true, None).with_format("json"), raw);
+        assert_eq!(line.lines().count(), 1);
+
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["client"], "127.0.0.1");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/a?b=1");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["size"], 42);
+        assert_eq!(entry["duration_ms"], 5.0);
+        assert_eq!(entry["user_agent"], "curl/8.0");
+        assert_eq!(entry["request_id"], "abc-123");
+        assert!(entry["referer"].is_null());
+        assert!(entry["time"].is_string());
+    }
+
+    #[test]
+    fn test_access_log_custom_format() {
+        let logger =
+            AccessLogger::new(true, None).with_format("%m %U%q %>s %{user-agent}i %{ms}T 100%%");
+        let line = log_line(
+            logger,
+            "GET /a?b=1 HTTP/1.1\r\nUser-Agent: xener-test\r\n\r\n",
+        );
+        assert_eq!(line, "GET /a?b=1 200 xener-test 5 100%\n");
+    }
+
+    #[test]
+    fn test_error_log_written_to_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("error.log");
+
+        let config = ServerConfig {
+            error_log: true,
+            error_log_path: log_path.to_string_lossy().into_owned(),
+            ..ServerConfig::default()
+        };
+
+        let (writer, guard) = error_log_writer(&config).unwrap();
+        tracing::subscriber::with_default(subscriber(writer), || {
+            tracing::error!("disk on fire");
+        });
+        // Flushes the background writer
+        drop(guard);
+
+        let contents = fs::read_to_string(log_path).unwrap();
+        assert!(
+            contents.contains("disk on fire"),
+            "unexpected log: {}",
+            contents
+        );
+        assert!(
+            contents.contains("\"level\":\"ERROR\""),
+            "unexpected log: {}",
+            contents
+        );
+    }
+
+    #[test]
+    fn test_access_log_rotation() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let logger = AccessLogger::new(true, Some(log_path.clone()))
+            .with_format("%m %U")
+            .with_rotation(Some(64), Some(2));
+        let request = request("GET /rotate HTTP/1.1\r\n\r\n");
+
+        // "GET /rotate\n" is 12 bytes, so the 6th line crosses 64 bytes
+        for _ in 0..7 {
+            logger.log("127.0.0.1", &request, 200, 0, None);
+        }
+        drop(logger);
+
+        let rotated = temp_dir.path().join("access.log.1");
+        assert!(rotated.exists(), "log was not rotated");
+        assert_eq!(fs::read_to_string(rotated).unwrap().lines().count(), 6);
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "GET /rotate\n");
+        assert!(!temp_dir.path().join("access.log.2").exists());
+    }
+
+    #[test]
+    fn test_access_log_concurrent_lines_are_complete() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let logger = Arc::new(
+            AccessLogger::new(true, Some(log_path.clone()))
+                .with_format("%m %U %>s")
+                .with_flush_policy(Some(16), None),
+        );
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let logger = Arc::clone(&logger);
+                thread::spawn(move || {
+                    let request = request(&format!("GET /thread-{} HTTP/1.1\r\n\r\n", thread_id));
+                    for _ in 0..250 {
+                        logger.log("127.0.0.1", &request, 200, 0, None);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
         }
+        drop(logger);
+
+        let contents = fs::read_to_string(log_path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 8 * 250);
+        for line in lines {
+            let thread_id = line
+                .strip_prefix("GET /thread-")
+                .and_then(|rest| rest.strip_suffix(" 200"))
+                .unwrap_or_else(|| panic!("malformed line: {:?}", line));
+            assert!(thread_id.parse::<usize>().is_ok_and(|id| id < 8));
+        }
+    }
+
+    #[test]
+    fn test_idle_access_log_flushed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let logger = Arc::new(
+            AccessLogger::new(true, Some(log_path.clone()))
+                .with_format("%m %U")
+                .with_flush_policy(Some(100), Some(Duration::from_millis(50))),
+        );
+        let watched = Arc::downgrade(&logger);
+        let watcher = thread::spawn(move || AccessLogger::watch(watched));
+
+        logger.log(
+            "127.0.0.1",
+            &request("GET /idle HTTP/1.1\r\n\r\n"),
+            200,
+            0,
+            None,
+        );
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "GET /idle\n");
+
+        drop(logger);
+        watcher.join().unwrap();
     }
 }
@@ -3,7 +3,8 @@
 use std::{process, sync::Arc};
 
 use config::ServerConfig;
-use tracing::{error, info};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tracing::{error, info, warn};
 
 mod config;
 mod error;
@@ -12,28 +13,58 @@ mod logging;
 mod server;
 
 fn main() {
-    if let Err(e) = logging::init_logger() {
-        eprintln!("Failed to initialize logger: {}", e);
-        process::exit(1)
-    }
-
-    info!("Starting Xener Server...");
-
     let config = match ServerConfig::load() {
         Ok(config) => config,
         Err(e) => {
-            error!("Failed to load configuration: {}", e);
-            error!("Using default configuration");
-            ServerConfig::default()
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1)
         }
     };
 
+    let listener = match server::bind(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", config.address(), e);
+            process::exit(1)
+        }
+    };
+
+    // Before the logger starts its writer thread, which wouldn't survive the fork
+    if config.daemonize {
+        #[cfg(unix)]
+        if let Err(e) = server::daemonize() {
+            eprintln!("Failed to daemonize: {}", e);
+            process::exit(1)
+        }
+        #[cfg(not(unix))]
+        eprintln!("daemonize is only supported on Unix, staying in the foreground");
+    }
+
+    let _log_guard = match logging::init_logger(&config) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Failed to initialize logger: {}", e);
+            process::exit(1)
+        }
+    };
+
+    info!("Starting Xener Server...");
+
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+
     info!("Server configured to listen on {}", config.address());
     info!("Serving files from {}", config.doc_root);
 
     let server = server::Server::new(Arc::new(config));
+    for signal in [SIGINT, SIGTERM] {
+        if let Err(e) = signal_hook::flag::register(signal, server.shutdown_flag()) {
+            error!("Failed to install handler for signal {}: {}", signal, e);
+        }
+    }
 
-    match server.run() {
+    match server.serve(listener) {
         Ok(_) => info!("Server shutdown successfully"),
         Err(e) => {
             error!("Server error: {}", e);
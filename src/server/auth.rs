@@ -0,0 +1,169 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use tracing::{debug, warn};
+
+use crate::config::AuthRule;
+use crate::http::StatusCode;
+use crate::http::request::Request;
+use crate::http::response::Response;
+
+/// HTTP Basic authentication for configured path prefixes
+pub struct BasicAuth {
+    rules: Vec<AuthRule>,
+}
+
+impl BasicAuth {
+    pub fn new(rules: Vec<AuthRule>) -> Self {
+        BasicAuth { rules }
+    }
+
+    /// Returns a 401 challenge when the request targets a protected path
+    /// without valid credentials, `None` when it may proceed
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        let path = request.path.split('?').next().unwrap_or(&request.path);
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| matches_prefix(path, &rule.prefix))?;
+
+        let authorized = request
+            .get_header("authorization")
+            .and_then(|value| decode_credentials(value))
+            .is_some_and(|(username, password)| {
+                rule.users.iter().any(|user| {
+                    constant_time_eq(user.username.as_bytes(), username.as_bytes())
+                        & verify_password(&user.password, &password)
+                })
+            });
+
+        if authorized {
+            return None;
+        }
+
+        debug!("Unauthorized request for {}", request.path);
+        Some(
+            Response::new()
+                .with_status(StatusCode::Unauthorized)
+                .with_header(
+                    "WWW-Authenticate",
+                    &format!("Basic realm=\"{}\"", rule.realm.replace('"', "")),
+                )
+                .with_text(&StatusCode::Unauthorized.status_text()),
+        )
+    }
+}
+
+/// Whether `path` is `prefix` or lies below it, `/docs` doesn't match `/docs-old`
+pub fn matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Decodes an `Authorization: Basic <base64(user:password)>` header value
+fn decode_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Passwords starting with `$2` are treated as bcrypt hashes, anything else as plain text
+fn verify_password(expected: &str, given: &str) -> bool {
+    if expected.starts_with("$2") {
+        return bcrypt::verify(given, expected).unwrap_or_else(|e| {
+            warn!("Invalid bcrypt hash in auth config: {}", e);
+            false
+        });
+    }
+    constant_time_eq(expected.as_bytes(), given.as_bytes())
+}
+
+/// Compares without short-circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let mut diff = a.len() ^ b.len();
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    use super::BasicAuth;
+    use crate::config::{AuthRule, AuthUser};
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+
+    fn auth() -> BasicAuth {
+        BasicAuth::new(vec![AuthRule {
+            prefix: String::from("/admin"),
+            realm: String::from("Admin Area"),
+            users: vec![
+                AuthUser {
+                    username: String::from("alice"),
+                    password: String::from("wonderland"),
+                },
+                AuthUser {
+                    username: String::from("bob"),
+                    password: bcrypt::hash("builder", 4).unwrap(),
+                },
+            ],
+        }])
+    }
+
+    fn request(credentials: Option<&str>) -> Request {
+        let header = credentials
+            .map(|c| format!("Authorization: Basic {}\r\n", STANDARD.encode(c)))
+            .unwrap_or_default();
+        let raw = format!("GET /admin/index.html HTTP/1.1\r\n{}\r\n", header);
+        Request::from_stream(&mut Cursor::new(raw)).unwrap()
+    }
+
+    #[test]
+    fn test_missing_credentials_challenged() {
+        let response = auth().check(&request(None)).unwrap();
+
+        assert_eq!(response.status, StatusCode::Unauthorized);
+        assert_eq!(
+            response.headers.get("WWW-Authenticate"),
+            Some(&"Basic realm=\"Admin Area\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_valid_credentials_allowed() {
+        assert!(auth().check(&request(Some("alice:wonderland"))).is_none());
+        assert!(auth().check(&request(Some("bob:builder"))).is_none());
+    }
+
+    #[test]
+    fn test_wrong_credentials_rejected() {
+        let response = auth().check(&request(Some("alice:guess"))).unwrap();
+        assert_eq!(response.status, StatusCode::Unauthorized);
+
+        let response = auth().check(&request(Some("bob:wonderland"))).unwrap();
+        assert_eq!(response.status, StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn test_unprotected_path_allowed() {
+        let raw = "GET /public/index.html HTTP/1.1\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw)).unwrap();
+        assert!(auth().check(&request).is_none());
+    }
+}
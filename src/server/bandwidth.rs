@@ -0,0 +1,70 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket used to pace writes on a single connection.
+///
+/// Tokens are bytes, refilled continuously at `rate` per second up to `capacity`.
+/// Spending more than is available sleeps until the deficit has been refilled.
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        let rate = rate.max(1);
+        let capacity = capacity.max(1);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Largest write that can go out without exceeding the burst size
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Blocks until `bytes` may be sent
+    pub fn consume(&mut self, bytes: usize) {
+        self.refill();
+
+        let bytes = bytes as f64;
+        if self.tokens >= bytes {
+            self.tokens -= bytes;
+            return;
+        }
+
+        let deficit = bytes - self.tokens;
+        thread::sleep(Duration::from_secs_f64(deficit / self.rate as f64));
+        self.tokens = 0.0;
+        self.last_refill = Instant::now();
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity as f64);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn test_burst_is_not_delayed() {
+        let mut bucket = TokenBucket::new(1024, 1024);
+
+        let start = Instant::now();
+        bucket.consume(1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Read, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tracing::error;
+
+use crate::config::ServerConfig;
+use crate::http::StatusCode;
+use crate::http::mime::is_text;
+use crate::http::request::Request;
+use crate::http::response::Response;
+
+/// Bodies smaller than this gain too little to be worth compressing
+const MIN_COMPRESS_SIZE: u64 = 256;
+const DEFAULT_MAX_COMPRESS_SIZE: u64 = 1024 * 1024;
+
+/// Gzips text responses for clients that accept it. Bodies over `max_size` are
+/// sent as they are, so no single request ties a worker up compressing
+pub struct Gzip {
+    enabled: bool,
+    max_size: u64,
+}
+
+impl Gzip {
+    pub fn new(config: &ServerConfig) -> Self {
+        Gzip {
+            enabled: config.gzip,
+            max_size: config
+                .max_compress_size
+                .unwrap_or(DEFAULT_MAX_COMPRESS_SIZE),
+        }
+    }
+
+    /// Compresses a `200` text response if the client accepts gzip. The strong
+    /// ETag of the uncompressed body becomes weak, the bytes no longer match it
+    pub fn apply(&self, request: &Request, mut response: Response) -> Response {
+        if !self.enabled
+            || response.status != StatusCode::Ok
+            || response.body_reader.is_some()
+            || response.headers.contains_key("Content-Encoding")
+            || !response
+                .headers
+                .get("Content-Type")
+                .is_some_and(|content_type| is_text(content_type))
+        {
+            return response;
+        }
+        // Whether or not this one is compressed, the response depends on the header
+        response.add_vary("Accept-Encoding");
+
+        let len = response.body_len() as u64;
+        if !(MIN_COMPRESS_SIZE..=self.max_size).contains(&len) || !accepts_gzip(request) {
+            return response;
+        }
+
+        let compressed = match response.body_file.take() {
+            Some(file) => {
+                let mut body = Vec::with_capacity(len as usize);
+                file.take(len)
+                    .read_to_end(&mut body)
+                    .and_then(|_| compress(&body))
+            }
+            None => compress(&response.body),
+        };
+        let compressed = match compressed {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                error!("Failed to compress response: {}", e);
+                return Response::new()
+                    .with_status(StatusCode::InternalServerError)
+                    .with_text(&StatusCode::InternalServerError.status_text());
+            }
+        };
+
+        if let Some(etag) = response.headers.get_mut("ETag")
+            && !etag.starts_with("W/")
+        {
+            etag.insert_str(0, "W/");
+        }
+        response
+            .with_body(compressed)
+            .with_header("Content-Encoding", "gzip")
+    }
+}
+
+/// The encoder's window and state have a fixed size whatever the level, so
+/// memory only grows with the output, which `max_size` bounds
+fn compress(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Whether `Accept-Encoding` allows gzip, named or through `*`, with a nonzero
+/// quality. A listing of gzip itself takes precedence over `*`
+fn accepts_gzip(request: &Request) -> bool {
+    let Some(accept) = request.get_header("accept-encoding") else {
+        return false;
+    };
+
+    let mut wildcard = None;
+    for coding in accept.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(quality > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use flate2::read::GzDecoder;
+
+    use super::Gzip;
+    use crate::config::ServerConfig;
+    use crate::http::request::Request;
+    use crate::http::response::Response;
+
+    fn gzip(max_compress_size: Option<u64>) -> Gzip {
+        Gzip::new(&ServerConfig {
+            gzip: true,
+            max_compress_size,
+            ..ServerConfig::default()
+        })
+    }
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!(
+            "GET /app.js HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        );
+        Request::from_stream(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    fn text_response(len: usize) -> Response {
+        Response::new()
+            .with_content_type("application/javascript")
+            .with_body(b"console.log(1);\n".repeat(len / 16))
+            .with_header("ETag", "\"abc\"")
+    }
+
+    #[test]
+    fn test_accepted_text_is_compressed() {
+        let response = gzip(None).apply(&request("br, gzip"), text_response(4096));
+
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(response.headers.get("Vary").unwrap(), "Accept-Encoding");
+        assert_eq!(response.headers.get("ETag").unwrap(), "W/\"abc\"");
+        assert_eq!(
+            response.headers.get("Content-Length").unwrap(),
+            &response.body.len().to_string()
+        );
+        let mut body = Vec::new();
+        GzDecoder::new(&response.body[..])
+            .read_to_end(&mut body)
+            .unwrap();
+        assert_eq!(body, text_response(4096).body);
+    }
+
+    #[test]
+    fn test_body_above_cap_sent_uncompressed() {
+        let response = gzip(Some(1024)).apply(&request("gzip"), text_response(4096));
+
+        assert!(!response.headers.contains_key("Content-Encoding"));
+        assert_eq!(response.headers.get("ETag").unwrap(), "\"abc\"");
+        assert_eq!(response.body, text_response(4096).body);
+    }
+
+    #[test]
+    fn test_gzip_not_accepted() {
+        for accept_encoding in ["identity", "gzip;q=0", "gzip;q=0, *", "br"] {
+            let response = gzip(None).apply(&request(accept_encoding), text_response(4096));
+            assert!(
+                !response.headers.contains_key("Content-Encoding"),
+                "{}",
+                accept_encoding
+            );
+        }
+
+        let response = gzip(None).apply(&request("*;q=0.5"), text_response(4096));
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let gzip = Gzip::new(&ServerConfig::default());
+        let response = gzip.apply(&request("gzip"), text_response(4096));
+        assert!(!response.headers.contains_key("Content-Encoding"));
+        assert!(!response.headers.contains_key("Vary"));
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::DateTime;
+
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode};
+
+/// Headers a `304` repeats from the response it replaces, so caches can update
+/// their stored copy
+const KEPT_HEADERS: [&str; 6] = [
+    "ETag",
+    "Last-Modified",
+    "Cache-Control",
+    "Expires",
+    "Vary",
+    "Content-Location",
+];
+
+/// Answers a GET or HEAD with `304 Not Modified` in place of a full `200` when
+/// the client's copy is still current. `If-None-Match` takes precedence, as RFC
+/// 7232 requires: `If-Modified-Since` only counts when there's no ETag validator
+pub fn apply(request: &Request, response: Response) -> Response {
+    if !matches!(request.method, Method::GET | Method::HEAD) || response.status != StatusCode::Ok {
+        return response;
+    }
+
+    let unchanged = match request.get_header("If-None-Match") {
+        Some(etags) => etag_matches(etags, &response),
+        None => request
+            .get_header("If-Modified-Since")
+            .is_some_and(|since| not_modified_since(since, &response)),
+    };
+    if unchanged {
+        not_modified(&response)
+    } else {
+        response
+    }
+}
+
+/// Whether any of the listed ETags matches the response's. The comparison is
+/// weak, an equivalent body is enough to reuse a cached copy
+fn etag_matches(etags: &str, response: &Response) -> bool {
+    let Some(etag) = response.headers.get("ETag") else {
+        return false;
+    };
+    let etags = etags.trim();
+    etags == "*"
+        || etags
+            .split(',')
+            .any(|candidate| opaque_tag(candidate.trim()) == opaque_tag(etag))
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+/// Whether the response was last modified no later than `since`. Unparseable
+/// dates on either side never match
+fn not_modified_since(since: &str, response: &Response) -> bool {
+    let Some(last_modified) = response.headers.get("Last-Modified") else {
+        return false;
+    };
+    match (
+        DateTime::parse_from_rfc2822(since.trim()),
+        DateTime::parse_from_rfc2822(last_modified),
+    ) {
+        (Ok(since), Ok(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn not_modified(response: &Response) -> Response {
+    let mut not_modified = Response::new().with_status(StatusCode::NotModified);
+    not_modified.headers.remove("Content-Type");
+    for name in KEPT_HEADERS {
+        if let Some(value) = response.headers.get(name) {
+            not_modified = not_modified.with_header(name, value);
+        }
+    }
+    not_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::apply;
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+    use crate::http::response::Response;
+
+    const ETAG: &str = "\"abc-a\"";
+    const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /file HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+        Request::from_stream(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    fn file_response() -> Response {
+        Response::new()
+            .with_text("0123456789")
+            .with_header("ETag", ETAG)
+            .with_header("Last-Modified", LAST_MODIFIED)
+            .with_header("Cache-Control", "public, max-age=60")
+    }
+
+    fn status_for(headers: &str) -> StatusCode {
+        apply(&request(headers), file_response()).status
+    }
+
+    #[test]
+    fn test_matching_etag_wins_over_stale_date() {
+        let headers = format!(
+            "If-None-Match: {}\r\nIf-Modified-Since: Sat, 05 Nov 1994 08:49:37 GMT\r\n",
+            ETAG
+        );
+        let response = apply(&request(&headers), file_response());
+
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag"), Some(&ETAG.to_string()));
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=60".to_string())
+        );
+        assert!(!response.headers.contains_key("Content-Length"));
+        assert!(!response.headers.contains_key("Content-Type"));
+    }
+
+    #[test]
+    fn test_mismatched_etag_ignores_date() {
+        let headers = format!(
+            "If-None-Match: \"other\"\r\nIf-Modified-Since: {}\r\n",
+            LAST_MODIFIED
+        );
+        assert_eq!(status_for(&headers), StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_date_only() {
+        let matching = format!("If-Modified-Since: {}\r\n", LAST_MODIFIED);
+        assert_eq!(status_for(&matching), StatusCode::NotModified);
+        assert_eq!(
+            status_for("If-Modified-Since: Mon, 07 Nov 1994 08:49:37 GMT\r\n"),
+            StatusCode::NotModified
+        );
+        assert_eq!(
+            status_for("If-Modified-Since: Sat, 05 Nov 1994 08:49:37 GMT\r\n"),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status_for("If-Modified-Since: yesterday\r\n"),
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn test_etag_lists_and_weak_tags() {
+        assert_eq!(
+            status_for("If-None-Match: \"other\", W/\"abc-a\"\r\n"),
+            StatusCode::NotModified
+        );
+        assert_eq!(status_for("If-None-Match: *\r\n"), StatusCode::NotModified);
+        assert_eq!(status_for(""), StatusCode::Ok);
+    }
+}
@@ -1,22 +1,39 @@
-use std::io;
-use std::net::{SocketAddr, TcpStream};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, info_span, trace, warn};
 
 use crate::config::ServerConfig;
-use crate::error::{Result, ServerError};
-use crate::http::request::Request;
-use crate::http::response::Response;
-use crate::http::{Method, StatusCode};
+use crate::error::{Result, ServerError, error_to_response};
+use crate::http::request::{Request, RequestLimits};
+use crate::http::response::{ChunkedWriter, DEFAULT_SERVER_HEADER, Response};
+use crate::http::{Method, StatusCode, Version};
+use crate::server::bandwidth::TokenBucket;
+use crate::server::metrics::Metrics;
+use crate::server::security_headers::SecurityHeaders;
+#[cfg(target_os = "linux")]
+use crate::server::sendfile;
+use crate::server::stream::Stream;
 
 const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 1000;
 const DEFAULT_CONNECTION_TIMEOUT: u64 = 30;
 const DEFAULT_READ_TIMEOUT: u64 = 30;
+const DEFAULT_HEADER_READ_TIMEOUT: u64 = 5;
 const DEFAULT_WRITE_TIMEOUT: u64 = 30;
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const DEFAULT_CHARSET: &str = "utf-8";
+/// How often an idle keep-alive connection checks whether the server is stopping
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-#[derive(Default)]
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default, Clone, Debug)]
 pub struct ConnectionStats {
     pub requests_handled: usize,
     pub bytes_received: usize,
@@ -27,55 +44,138 @@ pub struct ConnectionStats {
 }
 
 pub struct HttpConnection {
-    stream: TcpStream,
+    /// The socket, buffered for reads so bytes past the end of one request carry
+    /// over to the next. Writes go through `get_mut().stream`
+    reader: BufReader<DeadlineStream>,
     peer_addr: SocketAddr,
     request_count: usize,
     created_at: Instant,
     last_active: Instant,
     max_requests: usize,
     idle_timeout: u64,
+    header_read_timeout: Duration,
+    max_request_duration: Option<Duration>,
     stats: ConnectionStats,
     is_secure: bool,
+    /// Common name of the verified client certificate, set after the handshake
+    client_identity: Option<String>,
+    write_buf: Vec<u8>,
+    /// Holds each request and header line while it's parsed, reused across
+    /// requests so reading headers doesn't allocate per line
+    line_buf: String,
+    max_stream_duration: Option<Duration>,
+    throttle: Option<TokenBucket>,
+    limits: RequestLimits,
+    default_charset: String,
+    server_header: String,
+    security_headers: SecurityHeaders,
+    metrics: Option<Arc<Metrics>>,
+    stopping: Option<Arc<AtomicBool>>,
 }
 
 impl HttpConnection {
-    pub fn new(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Self> {
-        let peer_addr = stream.peer_addr().map_err(|e| ServerError::Io(e))?;
+    /// Wraps an accepted stream, `write_buf` is a (possibly recycled) buffer used to
+    /// serialize responses before they are written to the socket
+    pub fn new(stream: Stream, config: Arc<ServerConfig>, write_buf: Vec<u8>) -> Result<Self> {
+        let socket = stream.tcp();
+        let peer_addr = socket.peer_addr().map_err(|e| ServerError::Io(e))?;
 
-        stream.set_nodelay(true).map_err(|e| ServerError::Io(e))?;
+        socket.set_nodelay(true).map_err(|e| ServerError::Io(e))?;
 
         let idle_timeout = config
             .keep_alive_timeout
             .unwrap_or(DEFAULT_CONNECTION_TIMEOUT);
         let read_timeout = config.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT);
+        let header_read_timeout = config
+            .header_read_timeout
+            .unwrap_or(DEFAULT_HEADER_READ_TIMEOUT);
         let write_timeout = config.write_timeout.unwrap_or(DEFAULT_WRITE_TIMEOUT);
 
-        stream
+        socket
             .set_read_timeout(Some(Duration::from_secs(read_timeout)))
             .map_err(|e| ServerError::Io(e))?;
-        stream
+        socket
             .set_write_timeout(Some(Duration::from_secs(write_timeout)))
             .map_err(|e| ServerError::Io(e))?;
 
         let max_requests = config
             .max_requests_per_connection
             .unwrap_or(DEFAULT_MAX_REQUESTS_PER_CONNECTION);
+        let max_stream_duration = config.max_stream_duration_secs.map(Duration::from_secs);
+        let throttle = config
+            .max_bytes_per_sec
+            .map(|rate| TokenBucket::new(rate, rate.min(STREAM_CHUNK_SIZE as u64)));
+
+        let defaults = RequestLimits::default();
+        let limits = RequestLimits {
+            max_body_size: config.max_body_size.unwrap_or(defaults.max_body_size),
+            max_request_line_length: config
+                .max_request_line_length
+                .unwrap_or(defaults.max_request_line_length),
+            max_header_line_length: config
+                .max_header_line_length
+                .unwrap_or(defaults.max_header_line_length),
+            max_header_count: config.max_header_count.unwrap_or(defaults.max_header_count),
+        };
 
         let now = Instant::now();
+        let is_secure = stream.is_secure();
 
         Ok(HttpConnection {
-            stream,
+            reader: BufReader::new(DeadlineStream {
+                stream: CountingStream::new(stream),
+                read_timeout: Duration::from_secs(read_timeout),
+                deadline: None,
+            }),
             peer_addr,
             request_count: 0,
             created_at: now,
             last_active: now,
             max_requests,
             idle_timeout,
+            header_read_timeout: Duration::from_secs(header_read_timeout),
+            max_request_duration: config.max_request_duration.map(Duration::from_secs),
             stats: ConnectionStats::default(),
-            is_secure: false,
+            is_secure,
+            client_identity: None,
+            write_buf,
+            line_buf: String::new(),
+            max_stream_duration,
+            throttle,
+            limits,
+            default_charset: config
+                .default_charset
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_CHARSET)),
+            server_header: config
+                .server_header
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_SERVER_HEADER)),
+            security_headers: SecurityHeaders::new(config.security_headers.clone()),
+            metrics: None,
+            stopping: None,
         })
     }
 
+    /// Records every request served on this connection into `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Once `stopping` is set the current request is answered with
+    /// `Connection: close` and an idle connection stops waiting for another one
+    pub fn with_stop_flag(mut self, stopping: Arc<AtomicBool>) -> Self {
+        self.stopping = Some(stopping);
+        self
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping
+            .as_ref()
+            .is_some_and(|stopping| stopping.load(Ordering::Relaxed))
+    }
+
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
@@ -84,14 +184,36 @@ impl HttpConnection {
         self.is_secure
     }
 
+    /// Who the client authenticated as with a client certificate, `None` without
+    /// mutual TLS or before the first request
+    pub fn client_identity(&self) -> Option<&str> {
+        self.client_identity.as_deref()
+    }
+
     pub fn stats(&self) -> &ConnectionStats {
         &self.stats
     }
 
-    pub fn handle_request<F>(&mut self, request_handler: F) -> Result<bool>
+    /// Stats for a connection that is being closed, with `duration` covering its
+    /// whole lifetime
+    pub fn final_stats(&mut self) -> &ConnectionStats {
+        self.stats.duration = self.lifetime();
+        &self.stats
+    }
+
+    /// Reads one request, answers it with `request_handler` and sends the response.
+    /// `on_complete` is called once the response is out, with the time taken from
+    /// reading the request to finishing the response. Returns whether the
+    /// connection can be kept alive
+    pub fn handle_request<F, C>(&mut self, request_handler: F, on_complete: C) -> Result<bool>
     where
         F: FnOnce(&Request) -> Response,
+        C: FnOnce(&Request, &Response, Duration),
     {
+        if self.request_count > 0 && !self.wait_for_next_request()? {
+            return Ok(false);
+        }
+
         self.last_active = Instant::now();
         let request_start = Instant::now();
 
@@ -105,17 +227,75 @@ impl HttpConnection {
             return Ok(false);
         }
 
-        let request = match Request::from_stream(&mut self.stream) {
-            Ok(req) => {
-                // TODO: Move from Rough estimate to actual bytes more accuracy
-                self.stats.bytes_received += req
-                    .headers
-                    .iter()
-                    .map(|(k, v)| k.len() + v.len() + 2) //+2 -> ": "
-                    .sum::<usize>()
-                    + req.path.len()
-                    + 20; // rough estimate for request line
+        let mut send_continue = |reader: &mut BufReader<DeadlineStream>| {
+            let stream = &mut reader.get_mut().stream;
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\n\r\n",
+                StatusCode::Continue.status_text()
+            )?;
+            stream.flush()
+        };
+
+        // The request line and headers must arrive within the header timeout in
+        // total, so a client dribbling bytes can't hold the worker indefinitely.
+        // The body only has what's left of the limit on the whole request
+        let request_deadline = self
+            .max_request_duration
+            .map(|duration| request_start + duration);
+        let header_deadline = Instant::now() + self.header_read_timeout;
+        self.reader.get_mut().set_deadline(Some(
+            request_deadline.map_or(header_deadline, |deadline| deadline.min(header_deadline)),
+        ))?;
+        let head =
+            Request::read_head_with_buffer(&mut self.reader, &self.limits, &mut self.line_buf);
+        self.reader.get_mut().set_deadline(request_deadline)?;
+
+        if self.is_secure && self.client_identity.is_none() {
+            self.client_identity = self.reader.get_ref().stream.inner.client_identity();
+            if let Some(identity) = &self.client_identity {
+                debug!("Client {} authenticated as {}", self.peer_addr, identity);
+            }
+        }
+
+        let parsed = match head {
+            Ok(mut request) => request
+                .read_body(&mut self.reader, &self.limits, &mut send_continue)
+                .map(|_| request)
+                .map_err(|err| match (err, request_deadline) {
+                    // Chunked bodies surface the deadline as a plain read timeout
+                    (ServerError::Io(e), Some(deadline))
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) && Instant::now() >= deadline =>
+                    {
+                        ServerError::Timeout(format!(
+                            "request not received within {:?}",
+                            deadline - request_start
+                        ))
+                    }
+                    (err, _) => err,
+                }),
+            Err(ServerError::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(ServerError::Timeout(format!(
+                    "request headers not received within {:?}",
+                    self.header_read_timeout
+                )))
+            }
+            Err(err) => Err(err),
+        };
+        self.reader.get_mut().set_deadline(None)?;
 
+        let mut request = match parsed {
+            Ok(mut req) => {
+                self.count_bytes();
+                req.peer_addr = Some(self.peer_addr);
                 req
             }
             Err(err) => {
@@ -142,42 +322,43 @@ impl HttpConnection {
                 }
 
                 error!("Error parsing request from {}: {}", self.peer_addr, err);
-                let response = Response::new()
-                    .with_status(StatusCode::BadRequest)
-                    .with_keep_alive(false, None, None)
-                    .with_text(&StatusCode::BadRequest.status_text());
-
-                // response.write_to(&mut self.stream)?;
-                //
-                match response.write_to(&mut self.stream) {
+                let response = match err {
+                    ServerError::Io(_) => Response::new()
+                        .with_status(StatusCode::BadRequest)
+                        .with_text(&StatusCode::BadRequest.status_text()),
+                    _ => error_to_response(&err),
+                }
+                .with_keep_alive(false, None, None);
+                let mut response = self.finalize(response);
+
+                match self.send(&mut response) {
                     Ok(_) => {
-                        self.stats.bytes_sent += 100;
+                        self.count_bytes();
                         self.stats.requests_handled += 1;
+                        self.record(response.status.code(), request_start.elapsed());
                         return Ok(false);
                     }
-                    Err(write_err) => {
-                        if let ServerError::Io(io_err) = &write_err {
-                            match io_err.kind() {
-                                io::ErrorKind::BrokenPipe
-                                | io::ErrorKind::ConnectionReset
-                                | io::ErrorKind::ConnectionAborted => {
-                                    error!(
-                                        "Client {} disconnected during error response write: {}",
-                                        self.peer_addr, io_err
-                                    );
-                                    return Ok(false);
-                                }
-                                _ => {}
-                            }
-                        }
-                        return Err(write_err);
+                    Err(ServerError::Io(io_err)) if is_disconnect(&io_err) => {
+                        debug!(
+                            "Client {} disconnected during error response write: {}",
+                            self.peer_addr, io_err
+                        );
+                        return Ok(false);
                     }
+                    Err(write_err) => return Err(write_err),
                 }
             }
         };
 
-        let keep_alive = request.wants_keep_alive();
-        let timeout = request.keep_alive_timeout().unwrap_or(self.idle_timeout);
+        // The last request allowed on the connection is answered with `Connection:
+        // close`, rather than closing silently when the client sends another
+        let keep_alive = request.wants_keep_alive() && self.request_count < self.max_requests;
+        // Clients may ask for a shorter idle timeout, never a longer one
+        let timeout = request
+            .keep_alive_timeout()
+            .map_or(self.idle_timeout, |client_timeout| {
+                client_timeout.min(self.idle_timeout)
+            });
 
         let max_remaining = self.max_requests - self.request_count;
         let max_requests = request
@@ -192,24 +373,68 @@ impl HttpConnection {
 
         let is_head = matches!(request.method, Method::HEAD);
 
-        let mut response = request_handler(&request);
-        response = response.with_keep_alive(keep_alive, Some(timeout), max_requests);
+        // Handlers and the access log read the ID back from the request headers,
+        // so one the client sent that didn't qualify is replaced there too
+        let request_id = request_id_for(&request);
+        request
+            .headers
+            .retain(|name, _| !name.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+        request
+            .headers
+            .insert(String::from(REQUEST_ID_HEADER), request_id.clone());
+        let span = info_span!("request", id = %request_id);
+        let _span = span.enter();
 
-        if keep_alive && request.path.ends_with(".css") || request.path.ends_with(".js") {
-            response = response.with_cache_control(3600);
+        let mut response = request_handler(&request);
+        // HTTP/1.0 has no chunked framing, a streamed body there ends when the
+        // connection closes
+        let close_delimited = request.version == Version::HTTP1_0 && response.is_chunked();
+        if close_delimited {
+            response = response.without_chunked_encoding();
         }
+        // Shutdown may have started while the handler ran
+        let keep_alive = keep_alive && !close_delimited && !self.is_stopping();
+        response = self.finalize(
+            response
+                .with_keep_alive(keep_alive, Some(timeout), max_requests)
+                .with_header(REQUEST_ID_HEADER, &request_id),
+        );
 
         if is_head {
-            response.body = Vec::new();
+            response = response.without_body();
+        }
+
+        match self.send(&mut response) {
+            Ok(()) => {}
+            Err(ServerError::Timeout(msg)) => {
+                warn!("Aborting response to {}: {}", self.peer_addr, msg);
+                return Ok(false);
+            }
+            // Clients going away mid-response is routine, not a server error
+            Err(ServerError::Io(e)) if is_disconnect(&e) => {
+                debug!(
+                    "Client {} disconnected during response write: {}",
+                    self.peer_addr, e
+                );
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
         }
 
-        response.write_to(&mut self.stream)?;
+        self.count_bytes();
+        self.stats.requests_handled += 1;
+
+        // The idle timeout advertised in `Keep-Alive` applies until the next request
+        self.idle_timeout = timeout;
+        self.last_active = Instant::now();
 
         let request_duration = request_start.elapsed();
+        self.record(response.status.code(), request_duration);
         self.stats.active_time += request_duration;
         if request_duration > self.stats.max_request_time {
             self.stats.max_request_time = request_duration;
         }
+        on_complete(&request, &response, request_duration);
 
         trace!(
             "Response sent to {} (keep-aliveL {}, elapsed: {:?})",
@@ -219,6 +444,141 @@ impl HttpConnection {
         Ok(keep_alive)
     }
 
+    /// Bytes already read ahead into the buffer belong to the next request and
+    /// aren't counted yet
+    fn count_bytes(&mut self) {
+        let stream = &self.reader.get_ref().stream;
+        self.stats.bytes_received = stream.read - self.reader.buffer().len();
+        self.stats.bytes_sent = stream.written;
+    }
+
+    fn record(&self, status: u16, duration: Duration) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_request(status, duration);
+        }
+    }
+
+    /// Waits up to the idle timeout for the client to start another request on a
+    /// kept-alive connection. Returns false if it went away or stayed idle.
+    fn wait_for_next_request(&mut self) -> Result<bool> {
+        // A zero duration isn't a valid socket timeout
+        let idle_timeout = Duration::from_secs(self.idle_timeout.max(1));
+        // Pipelined requests are already buffered, there's nothing to wait for
+        if !self.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+
+        // Waits in short slices when the server may stop, so shutdown isn't held
+        // up until the idle timeout
+        let deadline = Instant::now() + idle_timeout;
+        let slice = match self.stopping {
+            Some(_) => idle_timeout.min(STOP_POLL_INTERVAL),
+            None => idle_timeout,
+        };
+        let socket = self.reader.get_ref().stream.inner.tcp();
+        socket.set_read_timeout(Some(slice))?;
+
+        // Filling the buffer rather than peeking the socket also works for TLS,
+        // where bytes on the wire aren't necessarily a request yet
+        let arrived = loop {
+            match self.reader.fill_buf().map(|buf| !buf.is_empty()) {
+                Ok(false) => {
+                    debug!("Connection from {} closed by client", self.peer_addr);
+                    break false;
+                }
+                Ok(true) => break true,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.is_stopping() {
+                        debug!(
+                            "Closing idle keep-alive connection from {} for shutdown",
+                            self.peer_addr
+                        );
+                        break false;
+                    }
+                    if Instant::now() >= deadline {
+                        debug!(
+                            "Closing idle keep-alive connection from {} after {:?}",
+                            self.peer_addr, idle_timeout
+                        );
+                        break false;
+                    }
+                }
+                Err(e) => return Err(ServerError::Io(e)),
+            }
+        };
+
+        // Once a request has started, reading the rest of it gets the regular timeout
+        let DeadlineStream {
+            stream,
+            read_timeout,
+            ..
+        } = self.reader.get_ref();
+        stream.inner.tcp().set_read_timeout(Some(*read_timeout))?;
+        Ok(arrived)
+    }
+
+    /// Headers every response on this connection carries, whether it came from a
+    /// handler or reports a request that couldn't be read
+    fn finalize(&self, response: Response) -> Response {
+        let response = response
+            .with_default_charset(&self.default_charset)
+            .with_server_header(&self.server_header);
+        self.security_headers.apply(response, self.is_secure)
+    }
+
+    /// Serializes the response head into the connection's buffer and writes it in one go,
+    /// rather than issuing a socket write per header line, then streams the body
+    fn send(&mut self, response: &mut Response) -> Result<()> {
+        let deadline = self
+            .max_stream_duration
+            .map(|duration| Instant::now() + duration);
+
+        self.write_buf.clear();
+        response.write_head_to(&mut self.write_buf)?;
+        let stream = &mut self.reader.get_mut().stream;
+        stream.write_all(&self.write_buf)?;
+
+        let chunked = response.is_chunked();
+        match response.body_reader.as_mut() {
+            Some(reader) if !chunked => stream_body(
+                stream,
+                reader,
+                &mut self.write_buf,
+                deadline,
+                self.throttle.as_mut(),
+            )?,
+            Some(reader) => {
+                let mut chunked = ChunkedWriter::new(&mut *stream);
+                stream_body(
+                    &mut chunked,
+                    reader,
+                    &mut self.write_buf,
+                    deadline,
+                    self.throttle.as_mut(),
+                )?;
+                chunked.finish()?;
+            }
+            None => match &response.body_file {
+                Some(file) => write_file(
+                    stream,
+                    file,
+                    response.body_len() as u64,
+                    &mut self.write_buf,
+                    deadline,
+                    self.throttle.as_mut(),
+                )?,
+                None => write_body(stream, &response.body, deadline, self.throttle.as_mut())?,
+            },
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
     pub fn is_expired(&self) -> bool {
         self.last_active.elapsed() > Duration::from_secs(self.idle_timeout)
     }
@@ -239,18 +599,47 @@ impl HttpConnection {
         self.last_active = Instant::now();
     }
 
+    /// Peeks at the socket without blocking: pending data or nothing to read yet
+    /// means the client is still there, EOF or an error means it went away
     fn is_healthy(&self) -> bool {
-        // TODO: Robust Implementation
-        // - check socket error status
-        // - perform a non-blocking peek operation
-        // - check for pending data or errors
-        self.stream.peer_addr().is_ok()
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+
+        let socket = self.reader.get_ref().stream.inner.tcp();
+        if socket.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0u8; 1];
+        let healthy = match socket.peek(&mut byte) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        };
+        socket.set_nonblocking(false).is_ok() && healthy
     }
 
-    pub fn close(self) -> Result<()> {
-        // TODO: In a real implementation, we might send a proper TCP FIN
-        // and handle TLS closure if needed.
+    pub fn buffer_capacity(&self) -> usize {
+        self.write_buf.capacity()
+    }
+
+    /// Closes the connection and hands back its write buffer for reuse
+    pub fn into_buffer(mut self) -> Vec<u8> {
+        let mut write_buf = std::mem::take(&mut self.write_buf);
+        let _ = self.close();
+        write_buf.clear();
+        write_buf
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        // TODO: In a real implementation, we might send a proper TCP FIN.
         // The connection will be closed when self is dropped.
+        if let Err(e) = self.reader.get_mut().stream.inner.close_notify() {
+            debug!(
+                "Failed to send TLS close_notify to {}: {}",
+                self.peer_addr, e
+            );
+        }
         if self.request_count > 1 || self.lifetime() > Duration::from_secs(10) {
             info!(
                 "Closed connection from {} after {} requests over {:?} (active: {:?}, idle: {:?})",
@@ -264,3 +653,482 @@ impl HttpConnection {
         Ok(())
     }
 }
+
+/// Writes the body in chunks, giving up once `deadline` has passed so a slow
+/// client can't hold a worker thread indefinitely, and pacing chunks through
+/// `throttle` when bandwidth is limited
+fn write_body<W: Write>(
+    writer: &mut W,
+    body: &[u8],
+    deadline: Option<Instant>,
+    mut throttle: Option<&mut TokenBucket>,
+) -> Result<()> {
+    let chunk_size = throttle
+        .as_ref()
+        .map_or(STREAM_CHUNK_SIZE, |bucket| bucket.capacity());
+
+    for chunk in body.chunks(chunk_size) {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Err(ServerError::Timeout(
+                "response body exceeded the maximum stream duration".to_string(),
+            ));
+        }
+        if let Some(bucket) = throttle.as_deref_mut() {
+            bucket.consume(chunk.len());
+        }
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+/// Sends `len` bytes of `file` from its current position, copied by the kernel
+/// with `sendfile` on Linux unless the connection is throttled or encrypted
+fn write_file(
+    stream: &mut CountingStream<Stream>,
+    file: &File,
+    len: u64,
+    buf: &mut Vec<u8>,
+    deadline: Option<Instant>,
+    throttle: Option<&mut TokenBucket>,
+) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if let (Stream::Plain(socket), None) = (&stream.inner, &throttle) {
+        sendfile::send_file(socket, file, len, deadline)?;
+        stream.written += len as usize;
+        return Ok(());
+    }
+
+    stream_body(stream, &mut file.take(len), buf, deadline, throttle)
+}
+
+/// Errors writing to a client that closed or reset the connection
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Echoes a well-formed client supplied `X-Request-Id`, otherwise generates one
+fn request_id_for(request: &Request) -> String {
+    match request.get_header(REQUEST_ID_HEADER) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.clone()
+        }
+        _ => next_request_id(),
+    }
+}
+
+/// Process-unique ID: a per-process prefix taken from the start time, so IDs don't
+/// repeat across restarts, followed by a counter
+fn next_request_id() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u32)
+    });
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:08x}-{:012x}", prefix, count)
+}
+
+/// Copies a body of unknown length from `reader`, using `buf` as scratch space,
+/// with the same deadline and pacing as `write_body`
+fn stream_body<W: Write, R: Read + ?Sized>(
+    writer: &mut W,
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    deadline: Option<Instant>,
+    mut throttle: Option<&mut TokenBucket>,
+) -> Result<()> {
+    buf.clear();
+    buf.resize(STREAM_CHUNK_SIZE, 0);
+
+    loop {
+        let read = match reader.read(buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ServerError::Io(e)),
+        };
+        write_body(writer, &buf[..read], deadline, throttle.as_deref_mut())?;
+    }
+}
+
+/// Socket that fails reads with `TimedOut` once `deadline` has passed, however
+/// steadily bytes keep arriving. Without a deadline reads use `read_timeout`
+struct DeadlineStream {
+    stream: CountingStream<Stream>,
+    read_timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineStream {
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        self.deadline = deadline;
+        if deadline.is_none() {
+            self.stream
+                .inner
+                .tcp()
+                .set_read_timeout(Some(self.read_timeout))?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(deadline) = self.deadline else {
+            return self.stream.read(buf);
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "read deadline elapsed",
+            ));
+        }
+        self.stream
+            .inner
+            .tcp()
+            .set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Counts the bytes passing through `inner` in each direction
+struct CountingStream<S> {
+    inner: S,
+    read: usize,
+    written: usize,
+}
+
+impl<S> CountingStream<S> {
+    fn new(inner: S) -> Self {
+        CountingStream {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<S: Read> Read for CountingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read;
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tempfile::tempdir;
+
+    use super::{HttpConnection, STREAM_CHUNK_SIZE, write_body};
+    use crate::config::ServerConfig;
+    use crate::error::ServerError;
+    use crate::http::response::Response;
+    use crate::server::bandwidth::TokenBucket;
+    use crate::server::stream::Stream;
+
+    struct ThrottledWriter {
+        written: usize,
+        delay: Duration,
+    }
+
+    impl Write for ThrottledWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_body_aborts_past_deadline() {
+        let body = vec![0u8; STREAM_CHUNK_SIZE * 10];
+        let mut writer = ThrottledWriter {
+            written: 0,
+            delay: Duration::from_millis(20),
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let result = write_body(&mut writer, &body, Some(deadline), None);
+
+        assert!(
+            matches!(result, Err(ServerError::Timeout(_))),
+            "stream was not aborted"
+        );
+        assert!(writer.written < body.len(), "whole body was written");
+    }
+
+    #[test]
+    fn test_write_body_without_deadline() {
+        let body = vec![0u8; STREAM_CHUNK_SIZE * 3];
+        let mut writer = ThrottledWriter {
+            written: 0,
+            delay: Duration::from_millis(1),
+        };
+
+        write_body(&mut writer, &body, None, None).unwrap();
+        assert_eq!(writer.written, body.len());
+    }
+
+    #[test]
+    fn test_write_body_respects_bandwidth_limit() {
+        let rate = 64 * 1024;
+        let body = vec![0u8; 64 * 1024];
+        let mut writer = ThrottledWriter {
+            written: 0,
+            delay: Duration::ZERO,
+        };
+        let mut bucket = TokenBucket::new(rate, STREAM_CHUNK_SIZE as u64);
+
+        let start = Instant::now();
+        write_body(&mut writer, &body, None, Some(&mut bucket)).unwrap();
+
+        // The first chunk goes out as a burst, the remaining 48KiB at 64KiB/s
+        assert!(
+            start.elapsed() >= Duration::from_millis(700),
+            "transfer finished too quickly: {:?}",
+            start.elapsed()
+        );
+        assert_eq!(writer.written, body.len());
+    }
+
+    #[test]
+    fn test_closed_client_not_reusable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, "."));
+        let connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            config,
+            Vec::new(),
+        )
+        .unwrap();
+
+        assert!(connection.is_reusable(), "idle connection reported dead");
+
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(
+            !connection.is_reusable(),
+            "closed connection reported reusable"
+        );
+    }
+
+    #[test]
+    fn test_stats_count_exact_bytes() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        fs::write(&path, vec![b'x'; 100 * 1024]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, "."));
+        let mut connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            config,
+            Vec::new(),
+        )
+        .unwrap();
+
+        let request = b"GET /data.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        client.write_all(request).unwrap();
+        let reader = thread::spawn(move || {
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            response
+        });
+
+        connection
+            .handle_request(|_| Response::from_file(&path).unwrap(), |_, _, _| {})
+            .unwrap();
+        let bytes_received = connection.stats().bytes_received;
+        let bytes_sent = connection.stats().bytes_sent;
+        drop(connection);
+
+        assert_eq!(bytes_received, request.len());
+        assert_eq!(bytes_sent, reader.join().unwrap().len());
+    }
+
+    #[test]
+    fn test_streamed_body_not_chunked_for_http_1_0() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, "."));
+        let mut connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            config,
+            Vec::new(),
+        )
+        .unwrap();
+
+        client
+            .write_all(b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let reader = thread::spawn(move || {
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            String::from_utf8(response).unwrap()
+        });
+
+        let keep_alive = connection
+            .handle_request(
+                |_| Response::new().with_body_reader(Box::new(io::Cursor::new(b"hello".to_vec()))),
+                |_, _, _| {},
+            )
+            .unwrap();
+        drop(connection);
+
+        assert!(
+            !keep_alive,
+            "connection kept open after a close-delimited body"
+        );
+        let response = reader.join().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+        assert!(head.contains("Connection: close"), "{}", head);
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn test_client_gone_before_response_closes_quietly() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let config = Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, "."));
+        let mut connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            config,
+            Vec::new(),
+        )
+        .unwrap();
+
+        client
+            .write_all(b"GET /large HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+
+        // Far more than the socket buffers hold, so writing has to fail
+        let keep_alive = connection
+            .handle_request(
+                |_| Response::new().with_body(vec![b'x'; 16 * 1024 * 1024]),
+                |_, _, _| {},
+            )
+            .unwrap();
+        assert!(!keep_alive, "connection kept open after client went away");
+    }
+
+    #[test]
+    fn test_configured_timeouts_applied_to_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut config = ServerConfig::with_params("127.0.0.1", 0, 10, ".");
+        config.read_timeout = Some(7);
+        config.write_timeout = Some(9);
+        let connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            Arc::new(config),
+            Vec::new(),
+        )
+        .unwrap();
+
+        let socket = connection.reader.get_ref().stream.inner.tcp();
+        assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_secs(7)));
+        assert_eq!(
+            socket.write_timeout().unwrap(),
+            Some(Duration::from_secs(9))
+        );
+    }
+
+    #[test]
+    fn test_slow_upload_past_max_request_duration() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut config = ServerConfig::with_params("127.0.0.1", 0, 10, ".");
+        config.max_request_duration = Some(1);
+        let mut connection = HttpConnection::new(
+            Stream::Plain(listener.accept().unwrap().0),
+            Arc::new(config),
+            Vec::new(),
+        )
+        .unwrap();
+
+        let mut reader = client.try_clone().unwrap();
+        let response = thread::spawn(move || {
+            let mut response = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = reader.read(&mut buf) {
+                response.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8_lossy(&response).to_string()
+        });
+        // A byte of body every 100ms stays well within the read timeout
+        let uploader = thread::spawn(move || {
+            client
+                .write_all(
+                    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n",
+                )
+                .unwrap();
+            for _ in 0..100 {
+                thread::sleep(Duration::from_millis(100));
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let keep_alive = connection
+            .handle_request(|_| Response::new().with_text("uploaded"), |_, _, _| {})
+            .unwrap();
+        let elapsed = start.elapsed();
+        drop(connection);
+
+        assert!(!keep_alive, "connection kept open after the limit");
+        assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+        let response = response.join().unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 408"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.contains("Connection: close"), "{}", response);
+        uploader.join().unwrap();
+    }
+}
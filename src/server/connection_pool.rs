@@ -1,66 +1,145 @@
 use std::collections::VecDeque;
-use std::net::TcpStream;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
 use tracing::debug;
 
 use crate::config::ServerConfig;
 use crate::error::Result;
-use crate::server::connection::HttpConnection;
+use crate::server::connection::{ConnectionStats, HttpConnection};
+use crate::server::stream::Stream;
 
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+const MAX_POOLED_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Called with the final stats of every connection as it is closed
+pub type CloseHook = Box<dyn Fn(&ConnectionStats, SocketAddr) + Send + Sync>;
+
+/// Recycles per-connection buffers.
+///
+/// Accepted sockets belong to a single client and are closed once that client is
+/// done, so they are never handed out again. What is reused is the heap allocation
+/// behind each connection: when a connection is released its write buffer goes back
+/// to the pool and the next accepted stream is wrapped around it. Buffers that grew
+/// past `MAX_POOLED_BUFFER_CAPACITY` (e.g. after a large response) are dropped so
+/// one big download doesn't pin that memory for the lifetime of the server.
+/// `prewarm_buffers` fills the pool at startup instead of on first release.
 pub struct ConnectionPool {
-    available: Arc<Mutex<VecDeque<HttpConnection>>>,
+    available: Arc<Mutex<VecDeque<Vec<u8>>>>,
     server_config: Arc<ServerConfig>,
+    on_close: Option<CloseHook>,
 }
 
 impl ConnectionPool {
     pub fn new(config: Arc<ServerConfig>) -> Self {
         let max_connections = config.max_connections.unwrap();
-        let pool = ConnectionPool {
-            available: Arc::new(Mutex::new(VecDeque::with_capacity(max_connections))),
+        let mut available = VecDeque::with_capacity(max_connections);
+        let prewarm = config.prewarm_buffers.unwrap_or(0).min(max_connections);
+        available.extend((0..prewarm).map(|_| Vec::with_capacity(DEFAULT_BUFFER_CAPACITY)));
+        if prewarm > 0 {
+            debug!("Pre-allocated {} connection buffers", prewarm);
+        }
+
+        ConnectionPool {
+            available: Arc::new(Mutex::new(available)),
             server_config: config,
-        };
-
-        let available = Arc::clone(&pool.available);
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_secs(60));
-
-                let mut connections = available.lock().unwrap();
-                let count_before = connections.len();
-
-                connections.retain(|conn| !conn.is_expired());
-                let removed = count_before - connections.len();
-                if removed > 0 {
-                    debug!(
-                        "Removed {} expired connections from pool, {} remaining",
-                        removed,
-                        connections.len()
-                    )
-                }
-            }
-        });
-
-        pool
+            on_close: None,
+        }
+    }
+
+    pub fn set_close_hook(&mut self, hook: CloseHook) {
+        self.on_close = Some(hook);
     }
 
-    pub fn get_connection(&self, stream: TcpStream) -> Result<HttpConnection> {
-        // TODO: Reuse connections from same client
-        HttpConnection::new(stream, self.server_config.clone())
+    pub fn get_connection(&self, stream: Stream) -> Result<HttpConnection> {
+        let buffer = self
+            .available
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Vec::with_capacity(DEFAULT_BUFFER_CAPACITY));
+
+        HttpConnection::new(stream, self.server_config.clone(), buffer)
     }
 
-    pub fn release_connection(&self, connection: HttpConnection) {
-        if !connection.is_reusable() {
-            debug!("Connection not reusable, discarding");
+    pub fn release_connection(&self, mut connection: HttpConnection) {
+        if let Some(on_close) = &self.on_close {
+            let peer_addr = connection.peer_addr();
+            on_close(connection.final_stats(), peer_addr);
+        }
+
+        if connection.buffer_capacity() > MAX_POOLED_BUFFER_CAPACITY {
+            debug!("Connection buffer too large to pool, discarding");
+            let _ = connection.close();
             return;
         }
 
-        let mut connections = self.available.lock().unwrap();
+        let buffer = connection.into_buffer();
+        let mut available = self.available.lock().unwrap();
 
-        if connections.len() < self.server_config.max_connections.unwrap() {
-            connections.push_back(connection);
-            return;
+        if available.len() < self.server_config.max_connections.unwrap() {
+            available.push_back(buffer);
         }
     }
+
+    /// Number of buffers ready to be handed to new connections
+    pub fn available(&self) -> usize {
+        self.available.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    use super::{ConnectionPool, MAX_POOLED_BUFFER_CAPACITY};
+    use crate::config::ServerConfig;
+    use crate::server::connection::HttpConnection;
+    use crate::server::stream::Stream;
+
+    fn accept_stream(listener: &TcpListener) -> Stream {
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        Stream::Plain(listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn test_release_connection_retains_reusable_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, "."));
+        let pool = ConnectionPool::new(config.clone());
+
+        let connection = pool.get_connection(accept_stream(&listener)).unwrap();
+        pool.release_connection(connection);
+        assert_eq!(pool.available(), 1, "reusable connection was not retained");
+
+        let connection = pool.get_connection(accept_stream(&listener)).unwrap();
+        assert_eq!(pool.available(), 0, "pooled buffer was not handed out");
+        pool.release_connection(connection);
+
+        let oversized = HttpConnection::new(
+            accept_stream(&listener),
+            config,
+            Vec::with_capacity(MAX_POOLED_BUFFER_CAPACITY * 2),
+        )
+        .unwrap();
+        pool.release_connection(oversized);
+        assert_eq!(pool.available(), 1, "non-reusable connection was retained");
+    }
+
+    #[test]
+    fn test_prewarmed_buffers_available_at_startup() {
+        let mut config = ServerConfig::with_params("127.0.0.1", 0, 10, ".");
+        config.prewarm_buffers = Some(4);
+        let pool = ConnectionPool::new(Arc::new(config));
+        assert_eq!(pool.available(), 4);
+
+        let mut config = ServerConfig::with_params("127.0.0.1", 0, 10, ".");
+        config.prewarm_buffers = Some(50);
+        let pool = ConnectionPool::new(Arc::new(config));
+        assert_eq!(pool.available(), 10, "more buffers than connections");
+
+        let pool =
+            ConnectionPool::new(Arc::new(ServerConfig::with_params("127.0.0.1", 0, 10, ".")));
+        assert_eq!(pool.available(), 0);
+    }
 }
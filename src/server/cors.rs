@@ -0,0 +1,157 @@
+use crate::config::CorsConfig;
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode};
+
+/// Adds `Access-Control-*` headers for cross-origin requests and answers
+/// preflight requests. Does nothing when CORS isn't configured.
+pub struct CorsPolicy {
+    config: Option<CorsConfig>,
+}
+
+impl CorsPolicy {
+    pub fn new(config: Option<CorsConfig>) -> Self {
+        CorsPolicy { config }
+    }
+
+    /// Builds the response for a preflight request from an allowed origin
+    pub fn preflight(&self, request: &Request) -> Option<Response> {
+        let config = self.config.as_ref()?;
+        if request.method != Method::OPTIONS
+            || request
+                .get_header("access-control-request-method")
+                .is_none()
+        {
+            return None;
+        }
+        let origin = self.allowed_origin(request)?;
+
+        let mut response = Response::new()
+            .with_status(StatusCode::NoContent)
+            .with_header("Access-Control-Allow-Origin", &origin)
+            .with_header(
+                "Access-Control-Allow-Methods",
+                &config.allowed_methods.join(", "),
+            );
+
+        if !config.allowed_headers.is_empty() {
+            response = response.with_header(
+                "Access-Control-Allow-Headers",
+                &config.allowed_headers.join(", "),
+            );
+        }
+        if let Some(max_age) = config.max_age {
+            response = response.with_header("Access-Control-Max-Age", &max_age.to_string());
+        }
+
+        Some(self.with_common_headers(response, &origin))
+    }
+
+    /// Adds the allow-origin headers to a regular response
+    pub fn apply(&self, request: &Request, response: Response) -> Response {
+        match self.allowed_origin(request) {
+            Some(origin) => self
+                .with_common_headers(response, &origin)
+                .with_header("Access-Control-Allow-Origin", &origin),
+            None => response,
+        }
+    }
+
+    fn with_common_headers(&self, mut response: Response, origin: &str) -> Response {
+        if self.config.as_ref().is_some_and(|c| c.allow_credentials) {
+            response = response.with_header("Access-Control-Allow-Credentials", "true");
+        }
+        if origin != "*" {
+            // The header value depends on the request Origin
+            response.add_vary("Origin");
+        }
+        response
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, `None` when the origin isn't allowed
+    fn allowed_origin(&self, request: &Request) -> Option<String> {
+        let config = self.config.as_ref()?;
+        let origin = request.get_header("origin")?;
+
+        if config.allowed_origins.iter().any(|o| o == origin) {
+            return Some(origin.clone());
+        }
+        if config.allowed_origins.iter().any(|o| o == "*") {
+            // Browsers reject a wildcard on credentialed requests, echo the origin instead
+            return Some(if config.allow_credentials {
+                origin.clone()
+            } else {
+                String::from("*")
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::CorsPolicy;
+    use crate::config::CorsConfig;
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+    use crate::http::response::Response;
+
+    fn policy(origins: &[&str]) -> CorsPolicy {
+        CorsPolicy::new(Some(CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_headers: vec![String::from("Content-Type")],
+            max_age: Some(600),
+            ..CorsConfig::default()
+        }))
+    }
+
+    fn request(raw: &str) -> Request {
+        Request::from_stream(&mut Cursor::new(raw.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_preflight_returns_allow_headers() {
+        let request = request(
+            "OPTIONS /fonts/a.woff2 HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+             Access-Control-Request-Method: GET\r\n\r\n",
+        );
+
+        let response = policy(&["https://app.example.com"])
+            .preflight(&request)
+            .unwrap();
+
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some(&"https://app.example.com".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Methods"),
+            Some(&"GET, HEAD, OPTIONS".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Headers"),
+            Some(&"Content-Type".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Max-Age"),
+            Some(&"600".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_carries_allow_origin() {
+        let request = request("GET / HTTP/1.1\r\nOrigin: https://other.com\r\n\r\n");
+
+        let response = policy(&["*"]).apply(&request, Response::new());
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some(&"*".to_string())
+        );
+
+        let response = policy(&["https://app.example.com"]).apply(&request, Response::new());
+        assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::process;
+
+use nix::unistd::{UnlinkatFlags, unlinkat};
+
+use super::privileges::Account;
+
+/// Detaches from the terminal: the parent exits, the child carries on in a new
+/// session with stdin, stdout and stderr on `/dev/null`. Must be called before
+/// any thread is started, they don't survive the fork
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: no other threads exist yet, so the child gets a consistent copy
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => process::exit(0),
+    }
+
+    // SAFETY: plain syscalls on the process and file descriptors it owns
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let dev_null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..=2 {
+            if libc::dup2(dev_null.as_raw_fd(), fd) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// File holding the PID of the running server, removed again when dropped.
+/// Removal goes through a handle on its directory, which still reaches it after
+/// `chroot`
+pub struct PidFile {
+    dir: File,
+    name: OsString,
+}
+
+impl PidFile {
+    /// Once privileges are dropped `owner` has to remove the file, so it's handed
+    /// the file and needs write access to the directory
+    pub fn create(path: &str, owner: Option<&Account>) -> io::Result<Self> {
+        let context = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to write PID file {}: {}", path, e),
+            )
+        };
+        fs::write(path, format!("{}\n", process::id())).map_err(context)?;
+        let path = Path::new(path);
+        if let Some(owner) = owner {
+            owner.chown(path).map_err(context)?;
+        }
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| context(io::Error::from(io::ErrorKind::InvalidInput)))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        Ok(PidFile {
+            dir: File::open(dir).map_err(context)?,
+            name: name.to_os_string(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = unlinkat(&self.dir, self.name.as_os_str(), UnlinkatFlags::NoRemoveDir);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Values keyed by path that expire `ttl` after they're inserted, holding at
+/// most `max_entries`. Callers bring their own locking
+pub struct ExpiringMap<V> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: HashMap<PathBuf, (Instant, V)>,
+}
+
+impl<V> ExpiringMap<V> {
+    pub fn new(ttl: Duration) -> Self {
+        ExpiringMap {
+            ttl,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// The value for `path` unless it's missing or expired
+    pub fn get(&self, path: &Path) -> Option<&V> {
+        self.entries
+            .get(path)
+            .filter(|(since, _)| since.elapsed() < self.ttl)
+            .map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.entries.remove(path);
+    }
+
+    /// Stores `value` for `path`, dropping expired entries first when full
+    pub fn insert(&mut self, path: &Path, value: V) {
+        if self.entries.len() >= self.max_entries {
+            let ttl = self.ttl;
+            self.entries.retain(|_, (since, _)| since.elapsed() < ttl);
+        }
+        // Still full of live entries, a scan shouldn't grow the map without bound
+        if self.entries.len() >= self.max_entries {
+            self.entries.clear();
+        }
+        self.entries
+            .insert(path.to_path_buf(), (Instant::now(), value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ExpiringMap;
+
+    #[test]
+    fn test_entries_expire() {
+        let mut map = ExpiringMap::new(Duration::from_millis(50));
+        map.insert(Path::new("/a"), 1);
+        assert_eq!(map.get(Path::new("/a")), Some(&1));
+
+        thread::sleep(Duration::from_millis(80));
+        assert_eq!(map.get(Path::new("/a")), None);
+    }
+
+    #[test]
+    fn test_full_map_stays_bounded() {
+        let mut map = ExpiringMap::new(Duration::from_millis(50)).with_max_entries(2);
+        map.insert(Path::new("/a"), 1);
+        thread::sleep(Duration::from_millis(80));
+        map.insert(Path::new("/b"), 2);
+
+        // Only the expired entry makes room
+        map.insert(Path::new("/c"), 3);
+        assert_eq!(map.entries.len(), 2);
+        assert_eq!(map.get(Path::new("/b")), Some(&2));
+
+        // Nothing has expired, so everything goes
+        map.insert(Path::new("/d"), 4);
+        assert_eq!(map.entries.len(), 1);
+        assert_eq!(map.get(Path::new("/d")), Some(&4));
+    }
+}
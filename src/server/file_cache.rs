@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use tracing::debug;
+
+use crate::config::ServerConfig;
+use crate::http::mime::content_type_for;
+use crate::http::response::{Response, content_etag, format_http_date};
+
+const DEFAULT_MAX_FILE_SIZE: usize = 1024 * 1024;
+
+struct CachedFile {
+    body: Vec<u8>,
+    content_type: &'static str,
+    modified: SystemTime,
+    etag: String,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    files: HashMap<PathBuf, CachedFile>,
+    total_bytes: usize,
+    clock: u64,
+}
+
+/// Keeps the contents of small static files in memory, evicting the least
+/// recently used ones once `max_bytes` is exceeded. Entries are checked against
+/// the file's modification time and size on every hit
+pub struct FileCache {
+    max_bytes: usize,
+    max_file_size: usize,
+    state: Mutex<CacheState>,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: usize, max_file_size: usize) -> Self {
+        FileCache {
+            max_bytes,
+            max_file_size: max_file_size.min(max_bytes),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// The cache shared by every static file handler, `None` unless enabled
+    pub fn from_config(config: &ServerConfig) -> Option<Arc<Self>> {
+        config.file_cache_size.map(|max_bytes| {
+            Arc::new(FileCache::new(
+                max_bytes,
+                config
+                    .file_cache_max_file_size
+                    .unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            ))
+        })
+    }
+
+    /// Same as `Response::from_file`, answered from memory when the file is
+    /// cached and unchanged
+    pub fn load(&self, path: &Path) -> io::Result<Response> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let Ok(modified) = metadata.modified() else {
+            return Response::from_open_file(file, path);
+        };
+        let len = metadata.len() as usize;
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let now = state.clock;
+
+        match state.files.get_mut(path) {
+            Some(file) if file.modified == modified && file.body.len() == len => {
+                file.last_used = now;
+                return Ok(file.response());
+            }
+            Some(_) => {
+                debug!("Evicting stale cache entry for {}", path.display());
+                state.remove(path);
+            }
+            None => {}
+        }
+        drop(state);
+
+        if len > self.max_file_size {
+            return Response::from_open_file(file, path);
+        }
+
+        let mut body = Vec::with_capacity(len);
+        file.read_to_end(&mut body)?;
+        let file = CachedFile {
+            content_type: content_type_for(path),
+            etag: content_etag(&body),
+            body,
+            modified,
+            last_used: now,
+        };
+        let response = file.response();
+
+        // The file may have been written to while it was read, the next hit
+        // compares against the size actually read
+        if file.body.len() <= self.max_file_size {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.remove(path);
+            state.total_bytes += file.body.len();
+            state.files.insert(path.to_path_buf(), file);
+            state.evict_to(self.max_bytes);
+        }
+
+        Ok(response)
+    }
+}
+
+impl CachedFile {
+    fn response(&self) -> Response {
+        Response::new()
+            .with_content_type(self.content_type)
+            .with_body(self.body.clone())
+            .with_header(
+                "Last-Modified",
+                &format_http_date(DateTime::<Utc>::from(self.modified)),
+            )
+            .with_header("ETag", &self.etag)
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, path: &Path) {
+        if let Some(file) = self.files.remove(path) {
+            self.total_bytes -= file.body.len();
+        }
+    }
+
+    fn evict_to(&mut self, max_bytes: usize) {
+        while self.total_bytes > max_bytes {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Cursor;
+
+    use super::FileCache;
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+    use crate::http::response::content_etag;
+    use crate::server::range;
+
+    #[test]
+    fn test_cache_hit_serves_cached_bytes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("hello.txt");
+        fs::write(&path, "hello").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        let cache = FileCache::new(1024, 1024);
+        let first = cache.load(&path).unwrap();
+
+        // Same size and modification time, so only a cache hit still serves "hello"
+        fs::write(&path, "HELLO").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let second = cache.load(&path).unwrap();
+        assert_eq!(second.body, b"hello");
+        assert_eq!(second.body, first.body);
+        assert_eq!(second.headers.get("ETag"), first.headers.get("ETag"));
+        assert_eq!(
+            second.headers.get("Content-Type"),
+            Some(&"text/plain".to_string())
+        );
+    }
+
+    #[test]
+    fn test_range_served_from_cached_bytes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("data.txt");
+        fs::write(&path, "0123456789").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        let cache = FileCache::new(1024, 1024);
+        let etag = cache.load(&path).unwrap().headers["ETag"].clone();
+
+        // Unchanged size and modification time, so disk reads would give letters
+        fs::write(&path, "abcdefghij").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let raw = format!(
+            "GET /data.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=2-5\r\nIf-Range: {}\r\n\r\n",
+            etag
+        );
+        let request = Request::from_stream(&mut Cursor::new(raw.into_bytes())).unwrap();
+        let response = range::apply(&request, cache.load(&path).unwrap());
+
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(response.body, b"2345");
+        assert_eq!(
+            response.headers.get("Content-Range"),
+            Some(&"bytes 2-5/10".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Content-Length"),
+            Some(&"4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cached_file_has_strong_etag() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("app.js");
+        fs::write(&path, "console.log(1)").unwrap();
+
+        let cache = FileCache::new(1024, 1024);
+        let etag = cache.load(&path).unwrap().headers["ETag"].clone();
+        assert!(etag.starts_with('"'), "weak ETag {}", etag);
+        assert_eq!(etag, content_etag(b"console.log(1)"));
+        assert_ne!(etag, content_etag(b"console.log(2)"));
+        assert_eq!(cache.load(&path).unwrap().headers["ETag"], etag);
+    }
+
+    #[test]
+    fn test_stale_entry_is_refreshed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("page.html");
+        fs::write(&path, "old").unwrap();
+
+        let cache = FileCache::new(1024, 1024);
+        assert_eq!(cache.load(&path).unwrap().body, b"old");
+
+        fs::write(&path, "updated").unwrap();
+        assert_eq!(cache.load(&path).unwrap().body, b"updated");
+    }
+
+    #[test]
+    fn test_least_recently_used_file_evicted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| temp_dir.path().join(name))
+            .collect();
+        for path in &paths {
+            fs::write(path, "0123456789").unwrap();
+        }
+
+        let cache = FileCache::new(20, 20);
+        cache.load(&paths[0]).unwrap();
+        cache.load(&paths[1]).unwrap();
+        cache.load(&paths[0]).unwrap();
+        cache.load(&paths[2]).unwrap();
+
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.total_bytes, 20);
+        assert!(state.files.contains_key(&paths[0]));
+        assert!(!state.files.contains_key(&paths[1]));
+        assert!(state.files.contains_key(&paths[2]));
+    }
+
+    #[test]
+    fn test_large_files_not_cached() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("big.bin");
+        fs::write(&path, vec![0; 64]).unwrap();
+
+        let cache = FileCache::new(1024, 32);
+        assert_eq!(cache.load(&path).unwrap().body.len(), 64);
+        assert!(cache.state.lock().unwrap().files.is_empty());
+    }
+}
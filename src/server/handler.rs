@@ -0,0 +1,39 @@
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode};
+use crate::server::static_handler::StaticFileHandler;
+use crate::server::{conditional, range};
+
+/// Produces a response for a request. Registered per method on the `Server`;
+/// GET and HEAD fall back to serving static files when nothing is registered.
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> RequestHandler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+impl RequestHandler for StaticFileHandler {
+    fn handle(&self, request: &Request) -> Response {
+        match request.method {
+            Method::GET | Method::HEAD => {
+                // Compressed first, so a `304` carries the ETag and `Vary` of the
+                // representation the client has. Preconditions are evaluated
+                // before a range is applied
+                let response = self.compress(request, self.serve(&request.path));
+                let response = conditional::apply(request, response);
+                range::apply(request, response)
+            }
+            _ => Response::new()
+                .with_status(StatusCode::MethodNotAllowed)
+                .with_header("Allow", "GET, HEAD")
+                .with_text(&StatusCode::MethodNotAllowed.status_text()),
+        }
+    }
+}
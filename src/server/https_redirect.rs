@@ -0,0 +1,133 @@
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error};
+
+use crate::error::{Result, error_to_response};
+use crate::http::StatusCode;
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::server::security_headers::SecurityHeaders;
+use crate::server::virtual_host::host_name;
+
+const DEFAULT_HTTPS_PORT: u16 = 443;
+/// Each redirect gets a thread of its own, so a slow client only keeps that
+/// thread around, and only briefly
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers every request on `listener` with a `301` to the same host and target
+/// on the HTTPS listener at `https_port`. Connections beyond `max_connections`
+/// in flight are closed right away
+pub fn serve(
+    listener: TcpListener,
+    https_port: u16,
+    max_connections: usize,
+    security_headers: SecurityHeaders,
+) {
+    let security_headers = Arc::new(security_headers);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // Only this thread adds to the count, so it can't overshoot
+                if active.load(Ordering::Relaxed) >= max_connections {
+                    debug!("Too many redirects in flight, closing connection");
+                    continue;
+                }
+                let in_flight = InFlight::new(Arc::clone(&active));
+                let security_headers = Arc::clone(&security_headers);
+                let spawned = thread::Builder::new()
+                    .name(String::from("https-redirect-conn"))
+                    .spawn(move || {
+                        let _in_flight = in_flight;
+                        if let Err(e) = redirect(stream, https_port, &security_headers) {
+                            debug!("Failed to redirect connection to HTTPS: {}", e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    error!("Failed to start thread for redirect: {}", e);
+                }
+            }
+            Err(e) => error!("Error accepting plaintext connection: {}", e),
+        }
+    }
+}
+
+/// Counts a redirect as in flight until dropped, which also happens when its
+/// thread fails to start
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn redirect(
+    mut stream: TcpStream,
+    https_port: u16,
+    security_headers: &SecurityHeaders,
+) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+    let response = match Request::from_stream(&mut stream) {
+        Ok(request) => {
+            let host = request
+                .authority
+                .as_ref()
+                .or_else(|| request.get_header("Host"));
+            match host {
+                Some(host) => Response::redirect(
+                    &https_location(host, https_port, &request.path),
+                    StatusCode::MovedPermanently,
+                ),
+                None => Response::new()
+                    .with_status(StatusCode::BadRequest)
+                    .with_text(&StatusCode::BadRequest.status_text()),
+            }
+        }
+        Err(err) => error_to_response(&err),
+    };
+
+    security_headers
+        .apply(response.with_keep_alive(false, None, None), false)
+        .write_to(&mut stream)
+}
+
+/// `https://` URL for `target` on `host`, with the port only when it isn't 443
+fn https_location(host: &str, https_port: u16, target: &str) -> String {
+    let host = host_name(host);
+    if https_port == DEFAULT_HTTPS_PORT {
+        format!("https://{}{}", host, target)
+    } else {
+        format!("https://{}:{}{}", host, https_port, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::https_location;
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("Example.com:80", 443, "/docs?page=2"),
+            "https://example.com/docs?page=2"
+        );
+        assert_eq!(
+            https_location("example.com", 8443, "/"),
+            "https://example.com:8443/"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::ServerConfig;
+
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Socket options applied to the listening socket before it is bound
+#[derive(Clone, Copy, Debug)]
+pub struct ListenerOptions {
+    pub backlog: i32,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+}
+
+impl ListenerOptions {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        ListenerOptions {
+            backlog: config.listen_backlog.unwrap_or(DEFAULT_BACKLOG),
+            reuse_address: config.reuse_address.unwrap_or(true),
+            reuse_port: config.reuse_port,
+        }
+    }
+}
+
+/// Binds a listener on `address` with the socket options in `options`
+pub fn bind(address: &str, options: ListenerOptions) -> io::Result<TcpListener> {
+    let addr = address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("could not resolve address {}", address),
+        )
+    })?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    socket.set_reuse_port(options.reuse_port)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    use super::{ListenerOptions, bind};
+
+    #[test]
+    fn test_rebind_after_close_with_reuse_address() {
+        let options = ListenerOptions {
+            backlog: 16,
+            reuse_address: true,
+            reuse_port: false,
+        };
+
+        let listener = bind("127.0.0.1:0", options).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Closing the accepted side first leaves the port in TIME_WAIT
+        let mut client = TcpStream::connect(&address).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        drop(accepted);
+        let _ = client.read(&mut [0; 1]);
+        drop(listener);
+
+        let listener = bind(&address, options).unwrap();
+        assert_eq!(listener.local_addr().unwrap().to_string(), address);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const MIN_STATUS_CODE: u16 = 100;
+const MAX_STATUS_CODE: u16 = 599;
+
+/// Server wide counters shared by all connections, rendered in the Prometheus
+/// text exposition format
+pub struct Metrics {
+    requests_total: AtomicU64,
+    responses_by_status: Vec<AtomicU64>,
+    active_connections: AtomicUsize,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            requests_total: AtomicU64::new(0),
+            responses_by_status: (MIN_STATUS_CODE..=MAX_STATUS_CODE)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            active_connections: AtomicUsize::new(0),
+            latency_buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records a response sent with `status` after `duration` spent on the request
+    pub fn record_request(&self, status: u16, duration: Duration) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+
+        if (MIN_STATUS_CODE..=MAX_STATUS_CODE).contains(&status) {
+            self.responses_by_status[(status - MIN_STATUS_CODE) as usize]
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let requests_total = self.requests_total.load(Ordering::Relaxed);
+
+        let _ = writeln!(
+            out,
+            "# HELP xener_requests_total Total number of HTTP requests handled."
+        );
+        let _ = writeln!(out, "# TYPE xener_requests_total counter");
+        let _ = writeln!(out, "xener_requests_total {}", requests_total);
+
+        let _ = writeln!(
+            out,
+            "# HELP xener_responses_total Number of HTTP responses by status code."
+        );
+        let _ = writeln!(out, "# TYPE xener_responses_total counter");
+        for (code, count) in (MIN_STATUS_CODE..).zip(&self.responses_by_status) {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(out, "xener_responses_total{{code=\"{}\"}} {}", code, count);
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP xener_active_connections Number of connections currently being served."
+        );
+        let _ = writeln!(out, "# TYPE xener_active_connections gauge");
+        let _ = writeln!(
+            out,
+            "xener_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP xener_request_duration_seconds Time spent handling HTTP requests."
+        );
+        let _ = writeln!(out, "# TYPE xener_request_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "xener_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "xener_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            requests_total
+        );
+        let _ = writeln!(
+            out,
+            "xener_request_duration_seconds_sum {}",
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "xener_request_duration_seconds_count {}",
+            requests_total
+        );
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.record_request(200, Duration::from_millis(3));
+        metrics.record_request(200, Duration::from_millis(30));
+        metrics.record_request(404, Duration::from_secs(20));
+
+        let output = metrics.render();
+
+        assert!(output.contains("# TYPE xener_requests_total counter\n"));
+        assert!(output.contains("xener_requests_total 3\n"));
+        assert!(output.contains("xener_responses_total{code=\"200\"} 2\n"));
+        assert!(output.contains("xener_responses_total{code=\"404\"} 1\n"));
+        assert!(output.contains("xener_active_connections 1\n"));
+        assert!(output.contains("xener_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(output.contains("xener_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(output.contains("xener_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(output.contains("xener_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(output.contains("xener_request_duration_seconds_count 3\n"));
+    }
+}
@@ -1,33 +1,94 @@
 #[cfg(test)]
 mod tests;
 
+mod auth;
+mod bandwidth;
+mod compression;
+mod conditional;
 mod connection;
 mod connection_pool;
+mod cors;
+#[cfg(unix)]
+mod daemon;
+mod expiring_map;
+mod file_cache;
+mod handler;
+mod https_redirect;
+mod listener;
+mod metrics;
+mod negative_cache;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
+mod range;
+mod redirect;
+mod router;
+mod security_headers;
+#[cfg(target_os = "linux")]
+mod sendfile;
+mod stat_cache;
 mod static_handler;
+mod stream;
 mod thread_pool;
+mod tls;
+mod trusted_proxy;
+mod virtual_host;
 
 use std::io;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use thread_pool::ThreadPool;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::config::ServerConfig;
-use crate::error::{Result, ServerError};
-use crate::http::response::Response;
-use crate::http::{self, Method, StatusCode};
+use crate::config::{ServerConfig, TlsConfig};
+use crate::error::{ServerError, error_to_response};
+use crate::http::Method;
+use crate::http::response::DEFAULT_SERVER_HEADER;
 use crate::logging::AccessLogger;
 use crate::server::connection::HttpConnection;
 use crate::server::connection_pool::ConnectionPool;
-use static_handler::StaticFileHandler;
+use crate::server::metrics::Metrics;
+use crate::server::security_headers::SecurityHeaders;
+pub use connection::ConnectionStats;
+#[cfg(unix)]
+pub use daemon::daemonize;
+pub use handler::RequestHandler;
+use listener::ListenerOptions;
+use router::Router;
+use stream::Stream;
+use tls::ReloadingConfig;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the shutdown flag and the number of draining connections are checked
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub struct Server {
     address: String,
-    static_handler: Arc<StaticFileHandler>,
+    listener_options: ListenerOptions,
+    tls: Option<TlsConfig>,
+    /// Address of the plain HTTP listener redirecting to HTTPS, if enabled
+    https_redirect_address: Option<String>,
+    https_port: u16,
+    router: Arc<Router>,
     access_logger: Arc<AccessLogger>,
     max_connections: usize,
     thread_count: usize,
+    job_queue_size: usize,
+    shutdown_timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+    server_header: String,
+    /// For the responses written outside of a connection, the busy `503`s and
+    /// the HTTPS redirects
+    security_headers: SecurityHeaders,
+    pid_file: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    /// Directory to confine the process to, the document root when `chroot` is set
+    chroot: Option<String>,
     connection_pool: Arc<ConnectionPool>,
 }
 
@@ -39,25 +100,177 @@ impl Server {
             let cpu_count = num_cpus::get();
             cpu_count * 2
         });
+        let job_queue_size = config.job_queue_size.unwrap_or(max_connections);
         Server {
             address: config.address(),
-            static_handler: Arc::new(StaticFileHandler::new(config.clone())),
-            access_logger: Arc::new(AccessLogger::new(
-                config.access_log,
-                Some(PathBuf::from(&config.access_log_path)),
-            )),
+            listener_options: ListenerOptions::from_config(&config),
+            tls: config.tls.clone(),
+            https_redirect_address: (config.tls.is_some() && config.redirect_http_to_https)
+                .then(|| format!("{}:{}", config.ip, config.http_port.unwrap_or(80))),
+            https_port: config.port,
+            router: Arc::new(Router::new(config.clone())),
+            access_logger: Arc::new(
+                AccessLogger::new(
+                    config.access_log,
+                    Some(PathBuf::from(&config.access_log_path)),
+                )
+                .with_strip_query(config.access_log_strip_query)
+                .with_format(config.log_format.as_deref().unwrap_or("common"))
+                .with_rotation(config.max_log_size, config.max_log_files)
+                .with_flush_policy(
+                    config.access_log_flush_lines,
+                    config
+                        .access_log_flush_interval_ms
+                        .map(Duration::from_millis),
+                ),
+            ),
             max_connections,
             thread_count,
+            job_queue_size,
+            shutdown_timeout: config
+                .shutdown_timeout
+                .map_or(DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            server_header: config
+                .server_header
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_SERVER_HEADER)),
+            security_headers: SecurityHeaders::new(config.security_headers.clone()),
+            pid_file: config.pid_file.clone(),
+            user: config.user.clone(),
+            group: config.group.clone(),
+            chroot: config.chroot.then(|| config.doc_root.clone()),
             connection_pool,
         }
     }
 
+    /// Dispatches requests with `method` to `handler`. Must be called before `run`.
+    pub fn with_handler(mut self, method: Method, handler: impl RequestHandler + 'static) -> Self {
+        Arc::get_mut(&mut self.router)
+            .expect("handlers must be registered before the server starts")
+            .register(method, Box::new(handler));
+        self
+    }
+
+    /// Calls `hook` with the stats of every connection once it is closed, e.g. to
+    /// aggregate them into custom metrics. Must be called before `run`.
+    pub fn with_connection_close_hook(
+        mut self,
+        hook: impl Fn(&ConnectionStats, SocketAddr) + Send + Sync + 'static,
+    ) -> Self {
+        Arc::get_mut(&mut self.connection_pool)
+            .expect("hooks must be registered before the server starts")
+            .set_close_hook(Box::new(hook));
+        self
+    }
+
+    /// Setting the returned flag, e.g. from a signal handler, stops the server: no
+    /// new connections are accepted, in-flight requests get up to the shutdown
+    /// timeout to finish, and `run` returns
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutdown)
+    }
+
     pub fn run(&self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.address)?;
+        self.serve(listener::bind(&self.address, self.listener_options)?)
+    }
+
+    /// Like `run`, on a listener bound beforehand with `bind`
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        #[cfg(unix)]
+        let account = match &self.user {
+            Some(user) => Some(privileges::Account::lookup(user, self.group.as_deref())?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let _pid_file = match &self.pid_file {
+            Some(path) => Some(daemon::PidFile::create(path, account.as_ref())?),
+            None => None,
+        };
+
+        let tls_config = match &self.tls {
+            Some(tls) => Some(Arc::new(
+                ReloadingConfig::new(tls.clone()).map_err(io::Error::other)?,
+            )),
+            None => None,
+        };
+        let redirect_listener = match &self.https_redirect_address {
+            Some(address) => Some(listener::bind(address, self.listener_options)?),
+            None => None,
+        };
+
+        // Everything needing root is bound or loaded by now, nothing else runs as root
+        #[cfg(unix)]
+        {
+            if let Some(root) = &self.chroot {
+                privileges::chroot(root)?;
+                info!("Confined to {}", root);
+            }
+            if let Some(account) = account {
+                account.switch_to()?;
+                info!("Running as user {}", account.name);
+            }
+        }
+        // Static files are served from `/` when confined, never unconfined
+        #[cfg(not(unix))]
+        if self.chroot.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chroot is only supported on Unix",
+            ));
+        }
+
+        if let (Some(config), Some(interval)) = (
+            &tls_config,
+            self.tls.as_ref().and_then(|tls| tls.reload_interval),
+        ) {
+            let config = Arc::clone(config);
+            thread::Builder::new()
+                .name(String::from("tls-reload"))
+                .spawn(move || config.watch(Duration::from_secs(interval)))?;
+        }
+
+        let access_logger = Arc::downgrade(&self.access_logger);
+        thread::Builder::new()
+            .name(String::from("access-log-flush"))
+            .spawn(move || AccessLogger::watch(access_logger))?;
+
+        let wake_address = wake_address(listener.local_addr()?);
+        let shutdown = Arc::clone(&self.shutdown);
+        thread::Builder::new()
+            .name(String::from("shutdown-watcher"))
+            .spawn(move || {
+                while !shutdown.load(Ordering::Relaxed) {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+                // Blocking accept() can't be interrupted, a connection of our own wakes it
+                let _ = TcpStream::connect(wake_address);
+            })?;
+
+        if let (Some(address), Some(redirect_listener)) =
+            (&self.https_redirect_address, redirect_listener)
+        {
+            let https_port = self.https_port;
+            let max_connections = self.max_connections;
+            let security_headers = self.security_headers.clone();
+            info!("Redirecting plain HTTP on {} to HTTPS", address);
+            thread::Builder::new()
+                .name(String::from("https-redirect"))
+                .spawn(move || {
+                    https_redirect::serve(
+                        redirect_listener,
+                        https_port,
+                        max_connections,
+                        security_headers,
+                    )
+                })?;
+        }
 
         let connections_count = Arc::new(Mutex::new(0));
 
-        let pool = ThreadPool::new(self.thread_count);
+        let mut pool = ThreadPool::new(self.thread_count, self.job_queue_size)
+            .with_shutdown_timeout(self.shutdown_timeout);
+        let stopping = pool.stopping();
 
         info!(
             "Server listening on {} with {} worker threads and max {} concurrent connections, keep-alive enabled",
@@ -65,8 +278,11 @@ impl Server {
         );
 
         for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::Relaxed) {
+                break;
+            }
             match stream {
-                Ok(stream) => {
+                Ok(mut stream) => {
                     let mut count = connections_count.lock().unwrap();
                     if *count >= self.max_connections {
                         // we've reached the maximum number of connections
@@ -75,45 +291,85 @@ impl Server {
                             "Maximum connection limit reached ({}), rejecting connection",
                             self.max_connections
                         );
-
-                        let response = http::response::Response::new()
-                            .with_status(http::StatusCode::ServiceUnavailable)
-                            .with_text("503 Service Unavailable - Server at capacity");
-
-                        let _ = response.write_to(&mut TcpStream::from(stream));
+                        reject_busy(
+                            &mut stream,
+                            tls_config.is_some(),
+                            &self.server_header,
+                            &self.security_headers,
+                        );
                         continue;
                     }
                     *count += 1;
                     debug!("New connection accepted, Active Connection: {}", *count);
 
+                    // Kept so the client can still be told we're busy if the job queue is full
+                    let mut reject_stream = match stream.try_clone() {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("Failed to clone connection stream: {}", e);
+                            *count -= 1;
+                            continue;
+                        }
+                    };
+
+                    let stream = match &tls_config {
+                        Some(config) => match Stream::tls(stream, config.current()) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                error!("Failed to set up TLS for connection: {}", e);
+                                *count -= 1;
+                                continue;
+                            }
+                        },
+                        None => Stream::Plain(stream),
+                    };
+
                     let connection = match self.connection_pool.get_connection(stream) {
-                        Ok(conn) => conn,
+                        Ok(conn) => conn
+                            .with_metrics(self.router.metrics())
+                            .with_stop_flag(Arc::clone(&stopping)),
                         Err(e) => {
                             error!("Failed to create connection: {}", e);
+                            *count -= 1;
                             continue;
                         }
                     };
 
-                    let static_handler = Arc::clone(&self.static_handler);
+                    let router = Arc::clone(&self.router);
                     let access_logger = Arc::clone(&self.access_logger);
                     let connection_count = Arc::clone(&connections_count);
                     let connection_pool = Arc::clone(&self.connection_pool);
+                    let stopping = Arc::clone(&stopping);
 
-                    pool.execute(move || {
+                    let result = pool.execute(move || {
                         debug!("Handling connection in thread pool");
+                        let slot = ConnectionSlot::new(connection_count, router.metrics());
 
-                        Self::handle_keep_alive_connection(
+                        let connection = Self::handle_keep_alive_connection(
                             connection,
-                            &static_handler,
+                            &router,
                             &access_logger,
-                            &connection_pool,
+                            &stopping,
                         );
 
-                        let mut count = connection_count.lock().unwrap();
-                        *count -= 1;
-
-                        debug!("Connection handled, action connections: {}", *count);
+                        // The slot is freed before the socket closes, a client that
+                        // reconnects once it sees the close then finds room
+                        drop(slot);
+                        connection_pool.release_connection(connection);
                     });
+
+                    // The job queue is full, answering right away keeps the accept
+                    // loop from stalling behind the busy workers
+                    if let Err(e) = result {
+                        error!("Rejecting connection: {}", e);
+                        *count -= 1;
+                        reject_busy(
+                            &mut reject_stream,
+                            tls_config.is_some(),
+                            &self.server_header,
+                            &self.security_headers,
+                        );
+                    }
                 }
                 Err(e) => {
                     error!("Connection error: {}", e);
@@ -121,43 +377,76 @@ impl Server {
             }
         }
 
+        // New connections are refused from here on
+        drop(listener);
+        if !self.drain(&connections_count, &stopping) {
+            // Waited long enough already, the pool shouldn't wait all over again
+            pool = pool.with_shutdown_timeout(Duration::ZERO);
+        }
+        drop(pool);
+
         Ok(())
     }
 
+    /// Tells connections to close after their current request and waits up to the
+    /// shutdown timeout for all of them to finish. Returns whether they did
+    fn drain(&self, connections_count: &Mutex<usize>, stopping: &AtomicBool) -> bool {
+        stopping.store(true, Ordering::Relaxed);
+        info!(
+            "Stopped accepting connections, waiting up to {:?} for {} active ones",
+            self.shutdown_timeout,
+            connections_count.lock().unwrap()
+        );
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        loop {
+            let active = *connections_count.lock().unwrap();
+            if active == 0 {
+                info!("All connections finished");
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "{} connections still active after {:?}, closing them",
+                    active, self.shutdown_timeout
+                );
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+    }
+
     fn handle_keep_alive_connection(
         mut connection: HttpConnection,
-        static_handler: &StaticFileHandler,
+        router: &Router,
         access_logger: &AccessLogger,
-        connection_pool: &ConnectionPool,
-    ) {
+        stopping: &AtomicBool,
+    ) -> HttpConnection {
         let peer_addr = connection.peer_addr().to_string();
 
         loop {
-            let result = connection.handle_request(|request| {
-                debug!("Processing {} request for {}", request.method, request.path);
-
-                let response = match request.method {
-                    Method::GET | Method::HEAD => static_handler.serve(&request.path),
-                    _ => Response::new()
-                        .with_status(StatusCode::MethodNotAllowed)
-                        .with_header("Allow", "GET, HEAD")
-                        .with_text(&StatusCode::MethodNotAllowed.status_text()),
-                };
-
-                access_logger.log(
-                    &peer_addr,
-                    &format!("{:?}", request.method),
-                    &request.path,
-                    response.status.code(),
-                    response.body.len(),
-                );
-
-                response
-            });
+            let result = connection.handle_request(
+                |request| {
+                    debug!("Processing {} request for {}", request.method, request.path);
+                    router.route(request)
+                },
+                |request, response, duration| {
+                    let client = router
+                        .forwarded_client(request)
+                        .map_or_else(|| peer_addr.clone(), |ip| ip.to_string());
+                    access_logger.log(
+                        &client,
+                        request,
+                        response.status.code(),
+                        response.body_len(),
+                        Some(duration),
+                    );
+                },
+            );
 
             match result {
                 Ok(keep_alive) => {
-                    if !keep_alive {
+                    if !keep_alive || connection.is_expired() || stopping.load(Ordering::Relaxed) {
                         debug!("Closing connection to {}", peer_addr);
                         break;
                     }
@@ -170,67 +459,69 @@ impl Server {
         }
 
         debug!("Release connection from {}", peer_addr);
-        connection_pool.release_connection(connection);
+        connection
     }
+}
 
-    fn handle_connection(
-        mut stream: TcpStream,
-        static_handler: &StaticFileHandler,
-        access_logger: &AccessLogger,
-    ) -> Result<()> {
-        stream.set_read_timeout(Some(std::time::Duration::from_secs(30)))?;
-        stream.set_write_timeout(Some(std::time::Duration::from_secs(30)))?;
-
-        let peer_addr = stream.peer_addr().map_err(|e| ServerError::Io(e))?;
-
-        debug!("Connection established from: {:?}", peer_addr);
-        let request = match http::request::Request::from_stream(&mut stream) {
-            Ok(req) => req,
-            Err(e) => {
-                error!("Error parsing request: {}", e);
-
-                let response_text = http::StatusCode::BadRequest.status_text();
-                let response = http::response::Response::new()
-                    .with_status(http::StatusCode::BadRequest)
-                    .with_text(&response_text);
-
-                response.write_to(&mut stream)?;
-
-                access_logger.log(
-                    &peer_addr.to_string(),
-                    "INVALID",
-                    "",
-                    response.status.code(),
-                    response_text.len(),
-                );
-                response.write_to(&mut stream)?;
-
-                return Err(ServerError::HttpParse(e.to_string()));
-            }
-        };
-
-        debug!("Received {} request for {}", request.method, request.path);
+/// Binds the listener `config` asks for, to be handed to `Server::serve`.
+/// Binding before daemonizing reports a port in use while still attached to the
+/// terminal
+pub fn bind(config: &ServerConfig) -> io::Result<TcpListener> {
+    listener::bind(&config.address(), ListenerOptions::from_config(config))
+}
 
-        let response = match request.method {
-            http::Method::GET | http::Method::HEAD => static_handler.serve(&request.path),
-            _ => http::response::Response::new()
-                .with_status(http::StatusCode::MethodNotAllowed)
-                .with_header("Allow", "GET, HEAD")
-                .with_text(&http::StatusCode::MethodNotAllowed.status_text()),
+/// Address to connect to for reaching a listener bound to `address`, wildcard
+/// binds are reached over loopback
+fn wake_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        let loopback = match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
         };
+        address.set_ip(loopback);
+    }
+    address
+}
 
-        access_logger.log(
-            &peer_addr.to_string(),
-            &request.method.to_string(),
-            &request.path,
-            response.status.code(),
-            response.body.len(),
-        );
+/// One of the `max_connections` slots, held by the job serving a connection.
+/// Dropping it gives the slot back, also when the job panics
+struct ConnectionSlot {
+    count: Arc<Mutex<usize>>,
+    metrics: Arc<Metrics>,
+}
 
-        response.write_to(&mut stream)?;
+impl ConnectionSlot {
+    /// The slot was already counted when the connection was accepted
+    fn new(count: Arc<Mutex<usize>>, metrics: Arc<Metrics>) -> Self {
+        metrics.connection_opened();
+        ConnectionSlot { count, metrics }
+    }
+}
 
-        debug!("Response sent to {:?}", peer_addr);
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.metrics.connection_closed();
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        debug!("Connection handled, action connections: {}", *count);
+    }
+}
 
-        Ok(())
+/// Answers a connection the server has no capacity for with `503` and a
+/// `Retry-After` hint, then lets it close. A TLS client is expecting a
+/// handshake, not plaintext, so there the connection is just closed
+fn reject_busy(
+    stream: &mut TcpStream,
+    tls: bool,
+    server_header: &str,
+    security_headers: &SecurityHeaders,
+) {
+    if tls {
+        return;
     }
+    let response = error_to_response(&ServerError::ServerBusy)
+        .with_keep_alive(false, None, None)
+        .with_server_header(server_header);
+    let response = security_headers.apply(response, false);
+    let _ = response.write_to(stream);
 }
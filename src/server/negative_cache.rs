@@ -0,0 +1,37 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::server::expiring_map::ExpiringMap;
+
+/// Remembers paths that recently turned out not to exist, so repeated requests
+/// for them are answered without touching the filesystem until `ttl` passes
+pub struct NegativeCache {
+    missing: Mutex<ExpiringMap<()>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration) -> Self {
+        NegativeCache {
+            missing: Mutex::new(ExpiringMap::new(ttl)),
+        }
+    }
+
+    /// Whether `path` was found missing less than `ttl` ago. Expired entries are
+    /// dropped so the next lookup goes back to the filesystem
+    pub fn is_missing(&self, path: &Path) -> bool {
+        let mut missing = self.missing.lock().unwrap_or_else(|e| e.into_inner());
+        if missing.get(path).is_some() {
+            return true;
+        }
+        missing.remove(path);
+        false
+    }
+
+    pub fn insert(&self, path: &Path) {
+        self.missing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(path, ());
+    }
+}
@@ -0,0 +1,231 @@
+use std::env;
+use std::io;
+use std::path::Path;
+
+use nix::unistd::{Gid, Group, Uid, User, chown, setgid, setuid};
+
+/// Unprivileged account to switch to, e.g. once ports below 1024 are bound as
+/// root. Looked up ahead of `chroot`, the account database isn't reachable after
+pub struct Account {
+    pub name: String,
+    uid: Uid,
+    gid: Gid,
+}
+
+impl Account {
+    /// The group defaults to the user's primary group. Either one not existing is
+    /// an error, carrying on as root isn't an option
+    pub fn lookup(user: &str, group: Option<&str>) -> io::Result<Self> {
+        let target = User::from_name(user)
+            .map_err(io::Error::from)?
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("unknown user {:?}", user))
+            })?;
+        let gid = match group {
+            Some(group) => {
+                Group::from_name(group)
+                    .map_err(io::Error::from)?
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("unknown group {:?}", group),
+                        )
+                    })?
+                    .gid
+            }
+            None => target.gid,
+        };
+
+        Ok(Account {
+            name: String::from(user),
+            uid: target.uid,
+            gid,
+        })
+    }
+
+    /// Hands `path` over to the account, for files it has to manage once
+    /// privileges are dropped
+    pub fn chown(&self, path: &Path) -> io::Result<()> {
+        chown(path, Some(self.uid), Some(self.gid)).map_err(io::Error::from)
+    }
+
+    /// The group has to change first, the user no longer may once it has
+    pub fn switch_to(&self) -> io::Result<()> {
+        // Supplementary groups are root's until cleared
+        set_groups(self.gid)
+            .and_then(|_| setgid(self.gid))
+            .and_then(|_| setuid(self.uid))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("failed to switch to user {:?}: {}", self.name, e),
+                )
+            })
+    }
+}
+
+/// Makes `gid` the only supplementary group
+fn set_groups(gid: Gid) -> nix::Result<()> {
+    #[cfg(not(target_vendor = "apple"))]
+    return nix::unistd::setgroups(&[gid]);
+
+    // nix leaves `setgroups` out on Apple platforms, libc still has it
+    #[cfg(target_vendor = "apple")]
+    {
+        let groups = [gid.as_raw()];
+        // SAFETY: the count matches the length of `groups`, which outlives the call
+        nix::errno::Errno::result(unsafe { libc::setgroups(1, groups.as_ptr()) }).map(drop)
+    }
+}
+
+/// Makes `dir` the root of the file system for this process, so paths escaping
+/// it can't reach anything outside. Only root may do this
+pub fn chroot(dir: &str) -> io::Result<()> {
+    nix::unistd::chroot(dir).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("failed to chroot into {}: {}", dir, e),
+        )
+    })?;
+    // The working directory would otherwise still point outside
+    env::set_current_dir("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::process::{self, Command};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::fcntl::AtFlags;
+    use nix::sys::stat::fstatat;
+    use nix::unistd::{Uid, User, chown};
+    use tempfile::tempdir;
+
+    use super::{Account, chroot};
+    use crate::config::ServerConfig;
+    use crate::http::StatusCode;
+    use crate::server::Server;
+    use crate::server::static_handler::StaticFileHandler;
+
+    /// Set in the copy of the test binary `in_child` starts
+    const CHILD_ENV: &str = "XENER_PRIVILEGES_CHILD";
+
+    /// Reruns the test `name` in a fresh copy of the test binary, since switching
+    /// users or the root affects the whole process. There the test gets this far
+    /// again and `check` decides the exit status, which is returned as passed
+    fn in_child(name: &str, check: impl FnOnce() -> bool) -> bool {
+        if env::var_os(CHILD_ENV).is_some() {
+            process::exit(if check() { 0 } else { 1 });
+        }
+
+        let module = module_path!().split_once("::").unwrap().1;
+        let output = Command::new(env::current_exe().unwrap())
+            .arg(format!("{}::{}", module, name))
+            .args(["--exact", "--test-threads=1", "--nocapture"])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        if !output.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        // A name matching no test would otherwise pass without running anything
+        let ran = String::from_utf8_lossy(&output.stdout).contains("running 1 test");
+        output.status.success() && ran
+    }
+
+    #[test]
+    fn test_unknown_user_rejected() {
+        assert!(Account::lookup("no-such-user-xener", None).is_err());
+    }
+
+    #[test]
+    fn test_privileges_dropped_after_bind() {
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let nobody = User::from_name("nobody").unwrap().unwrap();
+
+        assert!(in_child("test_privileges_dropped_after_bind", || {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Account::lookup("nobody", None)
+                .and_then(|account| account.switch_to())
+                .is_ok()
+                && Uid::effective() == nobody.uid
+                && listener.local_addr().is_ok()
+        }));
+    }
+
+    #[test]
+    fn test_chroot_hides_files_outside_doc_root() {
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let temp_dir = tempdir().unwrap();
+        let doc_root = temp_dir.path().join("public");
+        fs::create_dir(&doc_root).unwrap();
+        fs::write(doc_root.join("index.html"), "public").unwrap();
+        let secret = temp_dir.path().join("secret.txt");
+        fs::write(&secret, "secret").unwrap();
+
+        assert!(in_child("test_chroot_hides_files_outside_doc_root", || {
+            let doc_root = doc_root.to_string_lossy().to_string();
+            let handler = StaticFileHandler::new(Arc::new(ServerConfig::with_params(
+                "127.0.0.1",
+                8080,
+                1,
+                &doc_root,
+            )))
+            .with_root_dir("/");
+            chroot(&doc_root).is_ok()
+                && handler.serve("/index.html").status == StatusCode::Ok
+                && handler.serve("/../secret.txt").status == StatusCode::NotFound
+                && fs::metadata(&secret).is_err()
+        }));
+    }
+
+    #[test]
+    fn test_pid_file_removed_after_privileges_dropped() {
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let nobody = User::from_name("nobody").unwrap().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let doc_root = temp_dir.path().join("public");
+        fs::create_dir(&doc_root).unwrap();
+        let run_dir = temp_dir.path().join("run");
+        fs::create_dir(&run_dir).unwrap();
+        chown(&run_dir, Some(nobody.uid), Some(nobody.gid)).unwrap();
+        let pid_file = run_dir.join("xener.pid");
+
+        assert!(in_child(
+            "test_pid_file_removed_after_privileges_dropped",
+            || {
+                let doc_root = doc_root.to_string_lossy().to_string();
+                let mut config = ServerConfig::with_params("127.0.0.1", 0, 1, &doc_root);
+                config.pid_file = Some(pid_file.to_string_lossy().to_string());
+                config.user = Some(String::from("nobody"));
+                config.chroot = true;
+                let server = Server::new(Arc::new(config));
+                let shutdown = server.shutdown_flag();
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                // The PID file lies outside the new root, only reachable from here on
+                let run_dir = fs::File::open(&run_dir).unwrap();
+                let handle = thread::spawn(move || server.serve(listener));
+                thread::sleep(Duration::from_millis(100));
+
+                let written = fstatat(&run_dir, "xener.pid", AtFlags::empty()).is_ok();
+                shutdown.store(true, Ordering::Relaxed);
+                handle.join().unwrap().is_ok()
+                    && written
+                    && Uid::effective() == nobody.uid
+                    && fstatat(&run_dir, "xener.pid", AtFlags::empty()).is_err()
+            }
+        ));
+    }
+}
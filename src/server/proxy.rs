@@ -0,0 +1,519 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::config::ProxyRule;
+use crate::error::{Result, ServerError};
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode};
+use crate::server::auth::matches_prefix;
+
+const DEFAULT_UPSTREAM_TIMEOUT: u64 = 30;
+const MAX_UPSTREAM_LINE_LENGTH: usize = 8 * 1024;
+const MAX_UPSTREAM_HEADER_COUNT: usize = 100;
+const VIA: &str = "xener";
+
+/// Headers that only describe a single hop and are never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// Header names not spelled in plain title case
+const IRREGULAR_HEADER_NAMES: [&str; 2] = ["ETag", "WWW-Authenticate"];
+
+/// Forwards requests for configured path prefixes to upstream HTTP servers and
+/// relays their responses
+pub struct ReverseProxy {
+    rules: Vec<ProxyRule>,
+}
+
+impl ReverseProxy {
+    pub fn new(rules: Vec<ProxyRule>) -> Self {
+        ReverseProxy { rules }
+    }
+
+    /// The upstream's response for a request under a proxied prefix, `None` when
+    /// the request isn't proxied. Unreachable upstreams give `502`, slow ones `504`
+    pub fn forward(&self, request: &Request) -> Option<Response> {
+        if request.method == Method::UNKNOWN {
+            return None;
+        }
+        let path = request.path.split('?').next().unwrap_or(&request.path);
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| matches_prefix(path, &rule.prefix))?;
+
+        let timeout = Duration::from_secs(rule.timeout.unwrap_or(DEFAULT_UPSTREAM_TIMEOUT));
+        Some(match exchange(request, &rule.upstream, timeout) {
+            Ok(response) => response,
+            Err(err) => {
+                warn!(
+                    "Proxying {} to {} failed: {}",
+                    request.path, rule.upstream, err
+                );
+                let status = match err {
+                    ServerError::Io(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        ) =>
+                    {
+                        StatusCode::GatewayTimeout
+                    }
+                    _ => StatusCode::BadGateway,
+                };
+                Response::new()
+                    .with_status(status)
+                    .with_text(&status.status_text())
+            }
+        })
+    }
+}
+
+/// Sends `request` to `upstream` over a new connection and reads the response
+/// head. The body is streamed to the client as it arrives
+fn exchange(request: &Request, upstream: &str, timeout: Duration) -> Result<Response> {
+    let address = upstream
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| ServerError::Config(format!("{} does not resolve", upstream)))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    debug!(
+        "Proxying {} {} to {}",
+        request.method, request.path, upstream
+    );
+    stream.write_all(&upstream_request(request, upstream))?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let status_line = read_line(&mut reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let code = parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..1000).contains(code))
+        .ok_or_else(|| ServerError::HttpParse(format!("bad status line {:?}", status_line)))?;
+    // Codes without a variant are relayed too, with the upstream's reason phrase
+    let status = StatusCode::try_from(code).unwrap_or(StatusCode::Other(code));
+
+    let mut response = Response::new()
+        .with_status(status)
+        .with_reason_phrase(parts.next().unwrap_or_default());
+    response.headers.clear();
+    let mut content_length = None;
+    let mut header_count = 0;
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        // Whatever follows would otherwise be relayed as the body
+        header_count += 1;
+        if header_count > MAX_UPSTREAM_HEADER_COUNT {
+            return Err(ServerError::HttpParse(format!(
+                "upstream sent more than {} headers",
+                MAX_UPSTREAM_HEADER_COUNT
+            )));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (canonical_name(name.trim()), value.trim());
+        match name.as_str() {
+            "Content-Length" => {
+                let length = Some(value)
+                    .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|value| value.parse::<u64>().ok());
+                if length.is_none()
+                    || content_length.is_some_and(|previous| Some(previous) != length)
+                {
+                    return Err(ServerError::HttpParse(format!(
+                        "invalid upstream Content-Length {:?}",
+                        value
+                    )));
+                }
+                content_length = length;
+            }
+            // The request went out as HTTP/1.0, so a coded body can't be framed
+            // safely; relaying it raw would let the upstream's framing reach the client
+            "Transfer-Encoding" => {
+                return Err(ServerError::HttpParse(format!(
+                    "upstream sent Transfer-Encoding {:?} to an HTTP/1.0 request",
+                    value
+                )));
+            }
+            // Each cookie is a header of its own, they can't share a map entry
+            "Set-Cookie" => response.cookies.push(value.to_string()),
+            _ if HOP_BY_HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str()) => {}
+            _ => response = response.with_header(&name, value),
+        }
+    }
+
+    let via = format!("{} {}", version.trim_start_matches("HTTP/"), VIA);
+    let via = match response.headers.get("Via") {
+        Some(existing) => format!("{}, {}", existing, via),
+        None => via,
+    };
+    response = response.with_header("Via", &via);
+
+    // 1xx, 204 and 304 responses never have a body, whatever their headers say
+    let has_body = request.method != Method::HEAD
+        && !matches!(code, 100..200 | 204 | 304)
+        && content_length != Some(0);
+    Ok(match (has_body, content_length) {
+        // HEAD is answered with the length a GET would have had
+        (false, Some(len)) if request.method == Method::HEAD => response
+            .with_body(Vec::new())
+            .with_header("Content-Length", &len.to_string()),
+        (false, _) => response.with_body(Vec::new()),
+        (true, Some(len)) => response.with_sized_body_reader(
+            Box::new(ExactReader {
+                inner: reader.take(len),
+            }),
+            len,
+        ),
+        // Without a length the upstream marks the end by closing the connection
+        (true, None) => response.with_body_reader(Box::new(reader)),
+    })
+}
+
+/// Upstream body of a known length. Ending early is an error rather than EOF,
+/// so the client's connection is dropped instead of waiting on missing bytes
+struct ExactReader<R> {
+    inner: io::Take<R>,
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upstream closed before the end of the body",
+            ));
+        }
+        Ok(read)
+    }
+}
+
+/// Serializes `request` for the upstream as HTTP/1.0, so the response is never
+/// chunked and the connection closes after it
+fn upstream_request(request: &Request, upstream: &str) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\n",
+        request.method, request.path, upstream
+    );
+
+    let mut forwarded_for = None;
+    let mut via = None;
+    for (name, value) in &request.headers {
+        let lower = name.to_ascii_lowercase();
+        match lower.as_str() {
+            "host" => head.push_str(&format!("X-Forwarded-Host: {}\r\n", value)),
+            "x-forwarded-for" => forwarded_for = Some(value.clone()),
+            "via" => via = Some(value.clone()),
+            "expect" => {}
+            _ if HOP_BY_HOP_HEADERS.contains(&lower.as_str()) => {}
+            _ => head.push_str(&format!("{}: {}\r\n", name, value)),
+        }
+    }
+
+    if let Some(peer_addr) = request.peer_addr {
+        let client = peer_addr.ip().to_string();
+        let forwarded_for = match forwarded_for {
+            Some(existing) => format!("{}, {}", existing, client),
+            None => client,
+        };
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    } else if let Some(existing) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", existing));
+    }
+
+    let hop = format!(
+        "{} {}",
+        request.version.as_str().trim_start_matches("HTTP/"),
+        VIA
+    );
+    let via = match via {
+        Some(existing) => format!("{}, {}", existing, hop),
+        None => hop,
+    };
+    head.push_str(&format!("Via: {}\r\n", via));
+
+    if !request.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
+
+/// Upstream header name in the spelling the server's own headers use, so that
+/// e.g. an upstream `date` replaces the `Date` header instead of going out beside it
+fn canonical_name(name: &str) -> String {
+    if let Some(irregular) = IRREGULAR_HEADER_NAMES
+        .iter()
+        .find(|irregular| irregular.eq_ignore_ascii_case(name))
+    {
+        return irregular.to_string();
+    }
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+            })
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .take(MAX_UPSTREAM_LINE_LENGTH as u64 + 2)
+        .read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(ServerError::HttpParse(String::from(
+            "upstream response line too long or truncated",
+        )));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ReverseProxy;
+    use crate::config::ProxyRule;
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+
+    fn proxy(upstream: String, timeout: Option<u64>) -> ReverseProxy {
+        ReverseProxy::new(vec![ProxyRule {
+            prefix: String::from("/api"),
+            upstream,
+            timeout,
+        }])
+    }
+
+    fn request(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        Request::from_stream(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_unproxied_path_is_skipped() {
+        let proxy = proxy(String::from("127.0.0.1:1"), None);
+        assert!(proxy.forward(&request("/apis")).is_none());
+        assert!(proxy.forward(&request("/index.html")).is_none());
+    }
+
+    #[test]
+    fn test_unnamed_upstream_status_relayed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(listener.local_addr().unwrap().to_string(), Some(5));
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            stream
+                .write_all(b"HTTP/1.1 409 Conflict\r\nContent-Length: 8\r\n\r\nconflict")
+                .unwrap();
+        });
+
+        let mut response = proxy.forward(&request("/api/items")).unwrap();
+        upstream.join().unwrap();
+        assert_eq!(response.status, StatusCode::Other(409));
+
+        let mut head = Vec::new();
+        response.write_head_to(&mut head).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 409 Conflict\r\n"), "{}", head);
+        let mut body = String::new();
+        response
+            .body_reader
+            .as_mut()
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "conflict");
+    }
+
+    #[test]
+    fn test_unreachable_upstream_is_bad_gateway() {
+        // Bound then dropped, so nothing is listening on the port
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let proxy = proxy(format!("127.0.0.1:{}", port), Some(1));
+
+        let response = proxy.forward(&request("/api/items")).unwrap();
+        assert_eq!(response.status, StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn test_silent_upstream_is_gateway_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(listener.local_addr().unwrap().to_string(), Some(1));
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            thread::sleep(Duration::from_secs(2));
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n");
+        });
+
+        let response = proxy.forward(&request("/api/slow")).unwrap();
+        assert_eq!(response.status, StatusCode::GatewayTimeout);
+        upstream.join().unwrap();
+    }
+
+    /// Proxies `request` to an upstream that answers with `raw`, returning the
+    /// relayed response head
+    fn relayed_head(request: Request, raw: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(listener.local_addr().unwrap().to_string(), Some(5));
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(raw).unwrap();
+        });
+
+        let response = proxy.forward(&request).unwrap();
+        upstream.join().unwrap();
+        let mut head = Vec::new();
+        response.write_head_to(&mut head).unwrap();
+        String::from_utf8(head).unwrap()
+    }
+
+    fn count_headers(head: &str, name: &str) -> usize {
+        head.lines()
+            .filter(|line| {
+                line.split_once(':')
+                    .is_some_and(|(field, _)| field.eq_ignore_ascii_case(name))
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_lowercase_and_repeated_upstream_headers() {
+        let head = relayed_head(
+            request("/api/items"),
+            b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\ndate: Mon, 01 Jan 2024 00:00:00 GMT\r\n\
+              set-cookie: a=1\r\nSet-Cookie: b=2\r\ncontent-type: text/plain\r\n\r\nok",
+        );
+
+        assert_eq!(count_headers(&head, "Date"), 1, "{}", head);
+        assert!(head.contains("Date: Mon, 01 Jan 2024 00:00:00 GMT\r\n"));
+        assert!(head.contains("Set-Cookie: a=1\r\n"), "{}", head);
+        assert!(head.contains("Set-Cookie: b=2\r\n"), "{}", head);
+        assert_eq!(count_headers(&head, "Content-Type"), 1, "{}", head);
+        assert_eq!(count_headers(&head, "Content-Length"), 1, "{}", head);
+        assert!(head.contains("Content-Length: 2\r\n"), "{}", head);
+        assert_eq!(count_headers(&head, "Transfer-Encoding"), 0, "{}", head);
+    }
+
+    #[test]
+    fn test_upstream_without_length_is_chunked() {
+        let head = relayed_head(
+            request("/api/items"),
+            b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil close",
+        );
+
+        assert_eq!(count_headers(&head, "Content-Length"), 0, "{}", head);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn test_too_many_upstream_headers_is_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(listener.local_addr().unwrap().to_string(), Some(5));
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let mut raw = String::from("HTTP/1.1 200 OK\r\n");
+            for i in 0..150 {
+                raw.push_str(&format!("X-Header-{}: value\r\n", i));
+            }
+            raw.push_str("\r\nbody");
+            let _ = stream.write_all(raw.as_bytes());
+        });
+
+        let response = proxy.forward(&request("/api/items")).unwrap();
+        upstream.join().unwrap();
+        assert_eq!(response.status, StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn test_chunked_upstream_is_bad_gateway() {
+        let head = relayed_head(
+            request("/api/items"),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        );
+
+        assert!(head.starts_with("HTTP/1.1 502 "), "{}", head);
+        assert!(!head.contains("chunked"), "{}", head);
+    }
+
+    #[test]
+    fn test_truncated_upstream_body_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(listener.local_addr().unwrap().to_string(), Some(5));
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort")
+                .unwrap();
+        });
+
+        let mut response = proxy.forward(&request("/api/items")).unwrap();
+        upstream.join().unwrap();
+        let mut body = Vec::new();
+        let err = response
+            .body_reader
+            .as_mut()
+            .unwrap()
+            .read_to_end(&mut body)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_head_relays_upstream_length() {
+        let raw = "HEAD /api/items HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::from_stream(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        let head = relayed_head(request, b"HTTP/1.1 200 OK\r\ncontent-length: 8\r\n\r\n");
+
+        assert_eq!(count_headers(&head, "Content-Length"), 1, "{}", head);
+        assert!(head.contains("Content-Length: 8\r\n"), "{}", head);
+    }
+}
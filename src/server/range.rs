@@ -0,0 +1,454 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode};
+
+/// Requests asking for more ranges than this get the full content, so a client
+/// can't make the server assemble thousands of tiny parts
+const MAX_RANGES: usize = 16;
+
+static BOUNDARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Narrows a full `200` response for a GET or HEAD with a `Range` header down to
+/// the requested bytes, so a HEAD describes the same part a GET would send.
+/// Malformed ranges and a stale `If-Range` leave the full response as is. Full
+/// responses advertise through `Accept-Ranges` whether they could be ranged
+pub fn apply(request: &Request, response: Response) -> Response {
+    if !matches!(request.method, Method::GET | Method::HEAD) || response.status != StatusCode::Ok {
+        return response;
+    }
+    if !is_rangeable(&response) {
+        return response.with_header("Accept-Ranges", "none");
+    }
+    let response = response.with_header("Accept-Ranges", "bytes");
+    let Some(header) = request.get_header("Range") else {
+        return response;
+    };
+    if request
+        .get_header("If-Range")
+        .is_some_and(|validator| !if_range_matches(validator, &response))
+    {
+        return response;
+    }
+
+    let total = response.body_len() as u64;
+    if header.split(',').count() > MAX_RANGES {
+        return response;
+    }
+    let Some(ranges) = parse_ranges(header, total) else {
+        return response;
+    };
+
+    match coalesce(ranges).as_slice() {
+        [] => Response::new()
+            .with_status(StatusCode::RangeNotSatisfiable)
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("Content-Range", &format!("bytes */{}", total))
+            .with_text(&StatusCode::RangeNotSatisfiable.status_text()),
+        &[(start, end)] => partial(response, start, end, total),
+        ranges => multipart(response, ranges, total),
+    }
+}
+
+/// Streamed bodies have no known length to range over, and offsets into an
+/// encoded body don't match the representation clients would resume
+fn is_rangeable(response: &Response) -> bool {
+    response.body_reader.is_none() && !response.headers.contains_key("Content-Encoding")
+}
+
+/// Whether the `If-Range` validator still describes the response. ETags must
+/// match strongly, so a weak one on either side never matches and the full
+/// response is sent. Dates must match exactly
+fn if_range_matches(validator: &str, response: &Response) -> bool {
+    let validator = validator.trim();
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return !validator.starts_with("W/")
+            && response
+                .headers
+                .get("ETag")
+                .is_some_and(|etag| etag == validator);
+    }
+
+    let Some(last_modified) = response.headers.get("Last-Modified") else {
+        return false;
+    };
+    match (
+        DateTime::parse_from_rfc2822(validator),
+        DateTime::parse_from_rfc2822(last_modified),
+    ) {
+        (Ok(since), Ok(modified)) => since == modified,
+        _ => false,
+    }
+}
+
+/// Byte ranges (inclusive) of a `total` byte body asked for by `header`, leaving
+/// out ranges that start past the end. `None` if the header is malformed or not
+/// in bytes, in which case it is ignored
+fn parse_ranges(header: &str, total: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            ("", "") => return None,
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && total > 0).then(|| (total.saturating_sub(suffix), total - 1))
+            }
+            (first, "") => {
+                let first: u64 = first.parse().ok()?;
+                (first < total).then(|| (first, total - 1))
+            }
+            (first, last) => {
+                let (first, last): (u64, u64) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                (first < total).then(|| (first, last.min(total - 1)))
+            }
+        };
+        ranges.extend(range);
+    }
+    Some(ranges)
+}
+
+/// Sorts `ranges` and merges the ones that overlap or touch
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn partial(mut response: Response, start: u64, end: u64, total: u64) -> Response {
+    let len = end - start + 1;
+    response = match response.body_file.take() {
+        Some(mut file) => match file.seek(SeekFrom::Start(start)) {
+            Ok(_) => response.with_body_file(file, len),
+            Err(_) => return response.with_body_file(file, total),
+        },
+        None => {
+            let body = response.body[start as usize..=end as usize].to_vec();
+            response.with_body(body)
+        }
+    };
+
+    response
+        .with_status(StatusCode::PartialContent)
+        .with_header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, end, total),
+        )
+}
+
+/// `206` with a `multipart/byteranges` body holding one part per range. File
+/// bodies are streamed from the file rather than read into memory
+fn multipart(mut response: Response, ranges: &[(u64, u64)], total: u64) -> Response {
+    let boundary = next_boundary();
+    let content_type = response
+        .headers
+        .get("Content-Type")
+        .cloned()
+        .unwrap_or_else(|| String::from("application/octet-stream"));
+
+    let mut segments = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    for (i, &(start, end)) in ranges.iter().enumerate() {
+        let part_head = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            content_type,
+            start,
+            end,
+            total
+        );
+        segments.push_back(Segment::Bytes(Cursor::new(part_head.into_bytes())));
+        segments.push_back(Segment::File {
+            start,
+            len: end - start + 1,
+        });
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    segments.push_back(Segment::Bytes(Cursor::new(closing.into_bytes())));
+
+    response = match response.body_file.take() {
+        Some(file) => response.with_body_reader(Box::new(MultipartReader { file, segments })),
+        None => {
+            let mut body = Vec::new();
+            for segment in segments {
+                match segment {
+                    Segment::Bytes(bytes) => body.extend_from_slice(bytes.get_ref()),
+                    Segment::File { start, len } => body
+                        .extend_from_slice(&response.body[start as usize..(start + len) as usize]),
+                }
+            }
+            response.with_body(body)
+        }
+    };
+
+    response
+        .with_status(StatusCode::PartialContent)
+        .with_content_type(&format!("multipart/byteranges; boundary={}", boundary))
+}
+
+/// Boundary that won't appear in the content by accident
+fn next_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    let count = BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("xener-{:016x}{:08x}", nanos, count)
+}
+
+/// Piece of a multipart body, either generated part headers or a range of the
+/// file being served
+enum Segment {
+    Bytes(Cursor<Vec<u8>>),
+    File { start: u64, len: u64 },
+}
+
+struct MultipartReader {
+    file: File,
+    segments: VecDeque<Segment>,
+}
+
+impl Read for MultipartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = match self.segments.front_mut() {
+                None => return Ok(0),
+                Some(Segment::Bytes(bytes)) => bytes.read(buf)?,
+                Some(Segment::File { len: 0, .. }) => 0,
+                Some(Segment::File { start, len }) => {
+                    self.file.seek(SeekFrom::Start(*start))?;
+                    let max = buf.len().min(*len as usize);
+                    let read = self.file.read(&mut buf[..max])?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "file shrank while it was being sent",
+                        ));
+                    }
+                    *start += read as u64;
+                    *len -= read as u64;
+                    read
+                }
+            };
+            if read > 0 {
+                return Ok(read);
+            }
+            self.segments.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Cursor, Read};
+
+    use tempfile::tempdir;
+
+    use super::{MAX_RANGES, apply, coalesce, parse_ranges};
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+    use crate::http::response::Response;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /file HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+        Request::from_stream(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    fn file_response() -> Response {
+        Response::new()
+            .with_text("0123456789")
+            .with_header("ETag", "\"abc-a\"")
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("bytes=0-4", 10), Some(vec![(0, 4)]));
+        assert_eq!(parse_ranges("bytes=5-", 10), Some(vec![(5, 9)]));
+        assert_eq!(parse_ranges("bytes=-3", 10), Some(vec![(7, 9)]));
+        assert_eq!(parse_ranges("bytes=8-20", 10), Some(vec![(8, 9)]));
+        assert_eq!(parse_ranges("bytes=10-", 10), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=5-2", 10), None);
+        assert_eq!(parse_ranges("items=0-4", 10), None);
+    }
+
+    #[test]
+    fn test_single_range() {
+        let response = apply(&request("Range: bytes=2-5\r\n"), file_response());
+
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(response.body, b"2345");
+        assert_eq!(
+            response.headers.get("Content-Range"),
+            Some(&"bytes 2-5/10".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unsatisfiable_range() {
+        let response = apply(&request("Range: bytes=20-\r\n"), file_response());
+
+        assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+        assert_eq!(
+            response.headers.get("Content-Range"),
+            Some(&"bytes */10".to_string())
+        );
+    }
+
+    #[test]
+    fn test_matching_if_range_serves_partial_content() {
+        let by_etag = apply(
+            &request("Range: bytes=0-1\r\nIf-Range: \"abc-a\"\r\n"),
+            file_response(),
+        );
+        assert_eq!(by_etag.status, StatusCode::PartialContent);
+        assert_eq!(by_etag.body, b"01");
+
+        let by_date = apply(
+            &request("Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n"),
+            file_response(),
+        );
+        assert_eq!(by_date.status, StatusCode::PartialContent);
+    }
+
+    #[test]
+    fn test_stale_if_range_serves_full_content() {
+        for validator in ["\"old-a\"", "W/\"abc-a\"", "Sat, 05 Nov 1994 08:49:37 GMT"] {
+            let response = apply(
+                &request(&format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", validator)),
+                file_response(),
+            );
+            assert_eq!(response.status, StatusCode::Ok, "{} matched", validator);
+            assert_eq!(response.body, b"0123456789");
+            assert!(!response.headers.contains_key("Content-Range"));
+        }
+    }
+
+    #[test]
+    fn test_coalesce_ranges() {
+        assert_eq!(
+            coalesce(vec![(50, 60), (0, 9), (5, 19), (20, 29)]),
+            vec![(0, 29), (50, 60)]
+        );
+    }
+
+    #[test]
+    fn test_multiple_ranges() {
+        let response = apply(&request("Range: bytes=0-1, 6-7\r\n"), file_response());
+
+        assert_eq!(response.status, StatusCode::PartialContent);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/html\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/html\r\nContent-Range: bytes 6-7/10\r\n\r\n67\r\n\
+             --{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(response.body).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_multiple_ranges_of_large_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        let content: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let mut response = apply(
+            &request("Range: bytes=70000-70009,10-19\r\n"),
+            Response::from_file(&path).unwrap(),
+        );
+        assert_eq!(response.status, StatusCode::PartialContent);
+
+        let mut body = Vec::new();
+        response
+            .body_reader
+            .take()
+            .unwrap()
+            .read_to_end(&mut body)
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let parts: Vec<&str> = body.split("\r\n\r\n").collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].ends_with("Content-Range: bytes 10-19/102400"));
+        assert!(parts[1].starts_with(&*String::from_utf8_lossy(&content[10..20])));
+        assert!(parts[1].ends_with("Content-Range: bytes 70000-70009/102400"));
+        assert!(parts[2].starts_with(&*String::from_utf8_lossy(&content[70000..70010])));
+    }
+
+    #[test]
+    fn test_too_many_ranges_serve_full_content() {
+        let specs: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i, i)).collect();
+        let response = apply(
+            &request(&format!("Range: bytes={}\r\n", specs.join(","))),
+            file_response(),
+        );
+
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.body, b"0123456789");
+    }
+
+    #[test]
+    fn test_file_response_accepts_ranges() {
+        let response = apply(&request(""), file_response());
+
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            response.headers.get("Accept-Ranges"),
+            Some(&"bytes".to_string())
+        );
+
+        let response = apply(&request("Range: bytes=2-5\r\n"), file_response());
+        assert_eq!(
+            response.headers.get("Accept-Ranges"),
+            Some(&"bytes".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encoded_response_refuses_ranges() {
+        let response = apply(
+            &request("Range: bytes=2-5\r\n"),
+            file_response().with_header("Content-Encoding", "gzip"),
+        );
+
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.body, b"0123456789");
+        assert_eq!(
+            response.headers.get("Accept-Ranges"),
+            Some(&"none".to_string())
+        );
+
+        let streamed = Response::new().with_body_reader(Box::new(Cursor::new(b"0123".to_vec())));
+        let response = apply(&request(""), streamed);
+        assert_eq!(
+            response.headers.get("Accept-Ranges"),
+            Some(&"none".to_string())
+        );
+    }
+}
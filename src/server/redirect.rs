@@ -0,0 +1,122 @@
+use tracing::warn;
+
+use crate::config::RedirectRule;
+use crate::http::StatusCode;
+use crate::http::response::Response;
+
+struct Redirect {
+    from: String,
+    to: String,
+    status: StatusCode,
+    prefix: bool,
+}
+
+/// Configured redirect rules, checked in order before any file is served
+pub struct Redirects {
+    rules: Vec<Redirect>,
+}
+
+impl Redirects {
+    pub fn new(rules: &[RedirectRule]) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let status = match rule.status {
+                    301 => StatusCode::MovedPermanently,
+                    302 => StatusCode::Found,
+                    307 => StatusCode::TemporaryRedirect,
+                    308 => StatusCode::PermanentRedirect,
+                    other => {
+                        warn!(
+                            "Unsupported redirect status {} for {}, using 301",
+                            other, rule.from
+                        );
+                        StatusCode::MovedPermanently
+                    }
+                };
+                Redirect {
+                    from: rule.from.clone(),
+                    to: rule.to.clone(),
+                    status,
+                    prefix: rule.prefix,
+                }
+            })
+            .collect();
+
+        Redirects { rules }
+    }
+
+    /// Returns the redirect response for the first rule matching `path`
+    pub fn resolve(&self, path: &str) -> Option<Response> {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+
+        self.rules.iter().find_map(|rule| {
+            let mut location = if rule.prefix {
+                let suffix = path.strip_prefix(rule.from.as_str())?;
+                // "/docs" must not match "/docs-old"
+                if !rule.from.ends_with('/') && !suffix.is_empty() && !suffix.starts_with('/') {
+                    return None;
+                }
+                format!("{}{}", rule.to, suffix)
+            } else if path == rule.from {
+                rule.to.clone()
+            } else {
+                return None;
+            };
+
+            if let Some(query) = query {
+                location.push('?');
+                location.push_str(query);
+            }
+
+            Some(Response::redirect(&location, rule.status))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redirects;
+    use crate::config::RedirectRule;
+    use crate::http::StatusCode;
+
+    fn rule(from: &str, to: &str, status: u16, prefix: bool) -> RedirectRule {
+        RedirectRule {
+            from: from.to_string(),
+            to: to.to_string(),
+            status,
+            prefix,
+        }
+    }
+
+    #[test]
+    fn test_exact_redirect() {
+        let redirects = Redirects::new(&[rule("/old.html", "/new.html", 301, false)]);
+
+        let response = redirects.resolve("/old.html").unwrap();
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&"/new.html".to_string())
+        );
+
+        assert!(redirects.resolve("/old.html/more").is_none());
+    }
+
+    #[test]
+    fn test_prefix_redirect_preserves_suffix() {
+        let redirects = Redirects::new(&[rule("/blog", "https://blog.example.com", 302, true)]);
+
+        let response = redirects.resolve("/blog/2024/post?ref=home").unwrap();
+        assert_eq!(response.status, StatusCode::Found);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&"https://blog.example.com/2024/post?ref=home".to_string())
+        );
+
+        assert!(redirects.resolve("/blogroll").is_none());
+    }
+}
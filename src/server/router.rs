@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::config::ServerConfig;
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::{Method, StatusCode, Version};
+use crate::server::auth::BasicAuth;
+use crate::server::cors::CorsPolicy;
+use crate::server::handler::RequestHandler;
+use crate::server::metrics::Metrics;
+use crate::server::proxy::ReverseProxy;
+use crate::server::redirect::Redirects;
+use crate::server::trusted_proxy::TrustedProxies;
+use crate::server::virtual_host::{VirtualHosts, host_name};
+
+const DEFAULT_HEALTH_CHECK_PATH: &str = "/healthz";
+
+/// Methods always answered, in the order they're listed in `Allow`
+const BUILTIN_METHODS: [Method; 3] = [Method::GET, Method::HEAD, Method::OPTIONS];
+
+/// Methods that can only be served by a registered handler
+const HANDLER_METHODS: [Method; 6] = [
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::PATCH,
+    Method::TRACE,
+    Method::CONNECT,
+];
+
+/// Everything needed to turn a parsed request into a response, shared by all workers
+pub struct Router {
+    virtual_hosts: VirtualHosts,
+    cors: CorsPolicy,
+    redirects: Redirects,
+    proxy: ReverseProxy,
+    auth: BasicAuth,
+    allowed_hosts: Vec<String>,
+    trusted_proxies: TrustedProxies,
+    health_check_path: String,
+    metrics_path: Option<String>,
+    metrics: Arc<Metrics>,
+    handlers: HashMap<Method, Box<dyn RequestHandler>>,
+}
+
+impl Router {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Router {
+            cors: CorsPolicy::new(config.cors.clone()),
+            redirects: Redirects::new(&config.redirects),
+            proxy: ReverseProxy::new(config.proxies.clone()),
+            auth: BasicAuth::new(config.auth.clone()),
+            allowed_hosts: config
+                .allowed_hosts
+                .iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+            health_check_path: config
+                .health_check_path
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_HEALTH_CHECK_PATH)),
+            metrics_path: config.metrics_path.clone(),
+            metrics: Arc::new(Metrics::new()),
+            virtual_hosts: VirtualHosts::new(config),
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Dispatches requests with `method` to `handler`, replacing any previous one
+    pub fn register(&mut self, method: Method, handler: Box<dyn RequestHandler>) {
+        self.handlers.insert(method, handler);
+    }
+
+    /// The client a trusted proxy forwarded `request` for, `None` when it came
+    /// straight from the client
+    pub fn forwarded_client(&self, request: &Request) -> Option<IpAddr> {
+        self.trusted_proxies.client_ip(
+            request.peer_addr?.ip(),
+            request.get_header("X-Forwarded-For").map(String::as_str),
+        )
+    }
+
+    /// Counters shared with connections, which record each request they serve
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Value for the `Allow` header
+    pub fn allowed_methods(&self) -> String {
+        BUILTIN_METHODS
+            .iter()
+            .chain(
+                HANDLER_METHODS
+                    .iter()
+                    .filter(|method| self.handlers.contains_key(method)),
+            )
+            .map(|method| method.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn route(&self, request: &Request) -> Response {
+        // Every rule below matches on the path, so `//admin` or `/x/../admin` must
+        // not slip past one protecting `/admin`
+        let normalized;
+        let request = match normalize_path(&request.path) {
+            Some(path) if path == request.path => request,
+            Some(path) => {
+                normalized = Request {
+                    path,
+                    ..request.clone()
+                };
+                &normalized
+            }
+            None => {
+                return Response::new()
+                    .with_status(StatusCode::BadRequest)
+                    .with_text(&format!(
+                        "{} - Path escapes the root",
+                        StatusCode::BadRequest.status_text()
+                    ));
+            }
+        };
+
+        if self.is_health_check(request) {
+            return Response::new()
+                .with_status(StatusCode::Ok)
+                .with_content_type("text/plain")
+                .with_text("ok");
+        }
+
+        if let Some(rejection) = self.check_host(request) {
+            return rejection;
+        }
+
+        if let Some(preflight) = self.cors.preflight(request) {
+            return preflight;
+        }
+
+        if let Some(challenge) = self.auth.check(request) {
+            return self.cors.apply(request, challenge);
+        }
+
+        // After auth, so the endpoint can be protected with an auth rule
+        if self
+            .metrics_path
+            .as_deref()
+            .is_some_and(|path| is_get_for(request, path))
+        {
+            return Response::new()
+                .with_status(StatusCode::Ok)
+                .with_content_type("text/plain; version=0.0.4")
+                .with_text(&self.metrics.render());
+        }
+
+        if let Some(redirect) = self.redirects.resolve(&request.path) {
+            return self.cors.apply(request, redirect);
+        }
+
+        if let Some(proxied) = self.proxy.forward(request) {
+            return self.cors.apply(request, proxied);
+        }
+
+        let response = match self.handlers.get(&request.method) {
+            Some(handler) => handler.handle(request),
+            None => self.fallback(request),
+        };
+
+        self.cors.apply(request, response)
+    }
+
+    /// HTTP/1.1 requires a `Host` header, and when an allowlist is configured the
+    /// host must be on it. An absolute-form target's authority takes precedence
+    /// over the header
+    fn check_host(&self, request: &Request) -> Option<Response> {
+        let host = request
+            .authority
+            .as_deref()
+            .or_else(|| request.get_header("host").map(String::as_str));
+
+        let reason = match host {
+            None if request.version == Version::HTTP1_1 => "Missing Host header",
+            Some(host)
+                if !self.allowed_hosts.is_empty()
+                    && !self.allowed_hosts.contains(&host_name(host)) =>
+            {
+                "Unexpected Host header"
+            }
+            _ => return None,
+        };
+
+        Some(
+            Response::new()
+                .with_status(StatusCode::BadRequest)
+                .with_text(&format!(
+                    "{} - {}",
+                    StatusCode::BadRequest.status_text(),
+                    reason
+                )),
+        )
+    }
+
+    /// Probes skip auth, redirects and handlers so they succeed regardless of
+    /// site configuration
+    fn is_health_check(&self, request: &Request) -> bool {
+        is_get_for(request, &self.health_check_path)
+    }
+
+    fn fallback(&self, request: &Request) -> Response {
+        match request.method {
+            Method::GET | Method::HEAD => {
+                match self
+                    .virtual_hosts
+                    .resolve(request.authority.as_ref().or(request.get_header("host")))
+                {
+                    Some(static_handler) => static_handler.handle(request),
+                    None => Response::new()
+                        .with_status(StatusCode::MisdirectedRequest)
+                        .with_text(&StatusCode::MisdirectedRequest.status_text()),
+                }
+            }
+            Method::OPTIONS => Response::new()
+                .with_status(StatusCode::NoContent)
+                .with_header("Allow", &self.allowed_methods()),
+            // A method token we don't recognise at all, rather than one we don't allow
+            Method::UNKNOWN => Response::new()
+                .with_status(StatusCode::NotImplemented)
+                .with_text(&StatusCode::NotImplemented.status_text()),
+            _ => Response::new()
+                .with_status(StatusCode::MethodNotAllowed)
+                .with_header("Allow", &self.allowed_methods())
+                .with_text(&StatusCode::MethodNotAllowed.status_text()),
+        }
+    }
+}
+
+/// `target` with empty and `.` segments dropped and `..` segments resolved,
+/// keeping a trailing slash and the query. `None` when `..` climbs above `/`
+fn normalize_path(target: &str) -> Option<String> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    // The asterisk-form target of `OPTIONS *`
+    if !path.starts_with('/') {
+        return Some(target.to_string());
+    }
+
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    let is_dir = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if is_dir && !segments.is_empty() {
+        normalized.push('/');
+    }
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    Some(normalized)
+}
+
+/// Whether `request` is a GET or HEAD for exactly `path`, ignoring the query
+fn is_get_for(request: &Request, path: &str) -> bool {
+    matches!(request.method, Method::GET | Method::HEAD)
+        && request.path.split('?').next() == Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::{Router, normalize_path};
+    use crate::config::{AuthRule, AuthUser, ServerConfig};
+    use crate::http::StatusCode;
+    use crate::http::request::Request;
+
+    fn route(config: ServerConfig, raw: &str) -> StatusCode {
+        let router = Router::new(Arc::new(config));
+        let request = Request::from_stream(&mut Cursor::new(raw.as_bytes().to_vec())).unwrap();
+        router.route(&request).status
+    }
+
+    #[test]
+    fn test_missing_host_is_rejected() {
+        let config = ServerConfig::default();
+        assert_eq!(
+            route(config, "GET / HTTP/1.1\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+
+        // HTTP/1.0 clients aren't required to send one
+        let config = ServerConfig::default();
+        assert_ne!(
+            route(config, "GET / HTTP/1.0\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let config = || ServerConfig {
+            allowed_hosts: vec![String::from("Example.com")],
+            ..ServerConfig::default()
+        };
+
+        let allowed = "GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+        assert_ne!(route(config(), allowed), StatusCode::BadRequest);
+
+        let disallowed = "GET / HTTP/1.1\r\nHost: attacker.test\r\n\r\n";
+        assert_eq!(route(config(), disallowed), StatusCode::BadRequest);
+
+        // The authority of an absolute-form target wins over the header
+        let absolute = "GET http://attacker.test/ HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(route(config(), absolute), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_absolute_form_target_serves_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("page"), "page").unwrap();
+        let config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &temp_dir.path().to_string_lossy());
+
+        let raw = "GET http://example.com/page HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(route(config, raw), StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("//admin//a").as_deref(), Some("/admin/a"));
+        assert_eq!(normalize_path("/./admin/").as_deref(), Some("/admin/"));
+        assert_eq!(normalize_path("/x/../admin").as_deref(), Some("/admin"));
+        assert_eq!(normalize_path("/admin/..").as_deref(), Some("/"));
+        assert_eq!(
+            normalize_path("/a/./b/.?q=/../").as_deref(),
+            Some("/a/b/?q=/../")
+        );
+        assert_eq!(normalize_path("*").as_deref(), Some("*"));
+        assert_eq!(normalize_path("/.."), None);
+        assert_eq!(normalize_path("/a/../../b"), None);
+    }
+
+    #[test]
+    fn test_auth_applies_to_unnormalized_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(temp_dir.path().join("admin")).unwrap();
+        std::fs::write(temp_dir.path().join("admin/secret.txt"), "secret").unwrap();
+        let config = || {
+            let mut config =
+                ServerConfig::with_params("127.0.0.1", 8080, 1, &temp_dir.path().to_string_lossy());
+            config.auth = vec![AuthRule {
+                prefix: String::from("/admin"),
+                realm: String::from("Admin Area"),
+                users: vec![AuthUser {
+                    username: String::from("alice"),
+                    password: String::from("wonderland"),
+                }],
+            }];
+            config
+        };
+
+        for path in [
+            "/admin/secret.txt",
+            "//admin/secret.txt",
+            "/./admin/secret.txt",
+            "/x/../admin/secret.txt",
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            assert_eq!(route(config(), &raw), StatusCode::Unauthorized, "{}", path);
+        }
+
+        let raw = "GET /../admin/secret.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(route(config(), raw), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_unknown_method_is_not_implemented() {
+        let raw = "FOOBAR / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            route(ServerConfig::default(), raw),
+            StatusCode::NotImplemented
+        );
+
+        let raw = "DELETE / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            route(ServerConfig::default(), raw),
+            StatusCode::MethodNotAllowed
+        );
+    }
+}
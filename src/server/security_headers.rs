@@ -0,0 +1,119 @@
+use crate::config::SecurityHeadersConfig;
+use crate::http::response::Response;
+
+/// Adds the configured security headers to every response, leaving any a handler
+/// already set alone. Does nothing when security headers aren't configured.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    config: Option<SecurityHeadersConfig>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: Option<SecurityHeadersConfig>) -> Self {
+        SecurityHeaders { config }
+    }
+
+    /// `Strict-Transport-Security` is only sent over TLS, browsers ignore it on
+    /// plain HTTP anyway
+    pub fn apply(&self, response: Response, is_secure: bool) -> Response {
+        let Some(config) = &self.config else {
+            return response;
+        };
+
+        let headers = [
+            (
+                "Strict-Transport-Security",
+                config
+                    .strict_transport_security
+                    .as_deref()
+                    .filter(|_| is_secure),
+            ),
+            (
+                "X-Content-Type-Options",
+                config.content_type_options.as_deref(),
+            ),
+            ("X-Frame-Options", config.frame_options.as_deref()),
+            ("Referrer-Policy", config.referrer_policy.as_deref()),
+            (
+                "Content-Security-Policy",
+                config.content_security_policy.as_deref(),
+            ),
+        ];
+
+        let missing: Vec<(String, String)> = headers
+            .into_iter()
+            .filter(|(name, _)| !response.headers.contains_key(*name))
+            .filter_map(|(name, value)| {
+                let value = value.filter(|value| !value.is_empty())?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        response.with_headers(missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecurityHeaders;
+    use crate::config::SecurityHeadersConfig;
+    use crate::http::response::Response;
+
+    #[test]
+    fn test_security_headers_applied() {
+        let headers = SecurityHeaders::new(Some(SecurityHeadersConfig {
+            content_security_policy: Some(String::from("default-src 'self'")),
+            frame_options: None,
+            ..Default::default()
+        }));
+
+        let response = headers.apply(Response::new(), true);
+
+        assert_eq!(
+            response.headers.get("Strict-Transport-Security"),
+            Some(&"max-age=31536000; includeSubDomains".to_string())
+        );
+        assert_eq!(
+            response.headers.get("X-Content-Type-Options"),
+            Some(&"nosniff".to_string())
+        );
+        assert_eq!(
+            response.headers.get("Content-Security-Policy"),
+            Some(&"default-src 'self'".to_string())
+        );
+        assert!(response.headers.contains_key("Referrer-Policy"));
+        assert!(!response.headers.contains_key("X-Frame-Options"));
+    }
+
+    #[test]
+    fn test_hsts_omitted_on_plaintext() {
+        let headers = SecurityHeaders::new(Some(SecurityHeadersConfig::default()));
+
+        let response = headers.apply(Response::new(), false);
+
+        assert!(!response.headers.contains_key("Strict-Transport-Security"));
+        assert!(response.headers.contains_key("X-Content-Type-Options"));
+    }
+
+    #[test]
+    fn test_handler_headers_kept() {
+        let headers = SecurityHeaders::new(Some(SecurityHeadersConfig::default()));
+
+        let response = headers.apply(
+            Response::new().with_header("X-Frame-Options", "SAMEORIGIN"),
+            false,
+        );
+
+        assert_eq!(
+            response.headers.get("X-Frame-Options"),
+            Some(&"SAMEORIGIN".to_string())
+        );
+    }
+
+    #[test]
+    fn test_disabled_adds_nothing() {
+        let response = SecurityHeaders::new(None).apply(Response::new(), true);
+
+        assert!(!response.headers.contains_key("X-Content-Type-Options"));
+        assert!(!response.headers.contains_key("Strict-Transport-Security"));
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::time::Instant;
+
+use crate::error::{Result, ServerError};
+
+/// Largest transfer asked of a single `sendfile` call, so the stream deadline is
+/// checked regularly
+const MAX_SENDFILE_CHUNK: usize = 1024 * 1024;
+
+/// Copies `len` bytes from the current position of `file` to `socket` inside the
+/// kernel, without passing them through a user space buffer
+pub fn send_file(
+    socket: &TcpStream,
+    file: &File,
+    len: u64,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let mut remaining = len;
+
+    while remaining > 0 {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Err(ServerError::Timeout(
+                "response body exceeded the maximum stream duration".to_string(),
+            ));
+        }
+
+        let count = remaining.min(MAX_SENDFILE_CHUNK as u64) as usize;
+        // SAFETY: both descriptors stay open for the duration of the call. A null
+        // offset reads from, and advances, the file's own position
+        let sent = unsafe {
+            libc::sendfile(
+                socket.as_raw_fd(),
+                file.as_raw_fd(),
+                std::ptr::null_mut(),
+                count,
+            )
+        };
+
+        match sent {
+            0 => {
+                return Err(ServerError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while it was being sent",
+                )));
+            }
+            sent if sent > 0 => remaining -= sent as u64,
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(ServerError::Io(err));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
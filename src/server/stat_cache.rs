@@ -0,0 +1,146 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::ServerConfig;
+use crate::server::expiring_map::ExpiringMap;
+
+/// What routing a request needs to know about a path before opening it. Size
+/// and modification time are left out on purpose, they'd be stale by the time
+/// the file is served and are read from the opened file instead
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStat {
+    pub is_dir: bool,
+}
+
+impl From<&Metadata> for FileStat {
+    fn from(metadata: &Metadata) -> Self {
+        FileStat {
+            is_dir: metadata.is_dir(),
+        }
+    }
+}
+
+type Lookup = Box<dyn Fn(&Path) -> io::Result<FileStat> + Send + Sync>;
+
+/// Remembers whether a path is a file, a directory or doesn't exist for `ttl` so
+/// assets requested over and over aren't stat'ed every time. Changes to a path
+/// show up once its entry expires. Lookups share a read lock, only refreshing
+/// an entry takes the write lock
+pub struct StatCache {
+    entries: RwLock<ExpiringMap<Option<FileStat>>>,
+    lookup: Lookup,
+}
+
+impl StatCache {
+    pub fn new(ttl: Duration) -> Self {
+        StatCache {
+            entries: RwLock::new(ExpiringMap::new(ttl)),
+            lookup: Box::new(|path| fs::metadata(path).map(|metadata| FileStat::from(&metadata))),
+        }
+    }
+
+    /// The cache shared by every static file handler, `None` unless enabled
+    pub fn from_config(config: &ServerConfig) -> Option<Arc<Self>> {
+        config
+            .stat_cache_ttl_ms
+            .map(|ttl| Arc::new(StatCache::new(Duration::from_millis(ttl))))
+    }
+
+    /// Reads metadata with `lookup` instead of from the filesystem
+    pub fn with_lookup(
+        mut self,
+        lookup: impl Fn(&Path) -> io::Result<FileStat> + Send + Sync + 'static,
+    ) -> Self {
+        self.lookup = Box::new(lookup);
+        self
+    }
+
+    /// Metadata of `path`, an error of kind `NotFound` if it doesn't exist. Other
+    /// errors aren't remembered
+    pub fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        let cached = self
+            .entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(path)
+            .copied();
+
+        let stat = match cached {
+            Some(stat) => stat,
+            None => {
+                let stat = match (self.lookup)(path) {
+                    Ok(stat) => Some(stat),
+                    Err(e) if is_missing(&e) => None,
+                    Err(e) => return Err(e),
+                };
+                self.entries
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(path, stat);
+                stat
+            }
+        };
+        stat.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+/// A path through a regular file is as missing as one that doesn't exist
+fn is_missing(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{FileStat, StatCache};
+
+    fn counting(ttl: Duration) -> (StatCache, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let cache = StatCache::new(ttl).with_lookup(move |path: &Path| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            match path.to_str() {
+                Some("/missing") => Err(io::Error::from(io::ErrorKind::NotFound)),
+                _ => Ok(FileStat { is_dir: false }),
+            }
+        });
+        (cache, calls)
+    }
+
+    #[test]
+    fn test_stat_cached_until_ttl() {
+        let (cache, calls) = counting(Duration::from_millis(100));
+
+        for _ in 0..3 {
+            assert!(!cache.stat(Path::new("/app.js")).unwrap().is_dir);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        thread::sleep(Duration::from_millis(150));
+        cache.stat(Path::new("/app.js")).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_missing_path_cached() {
+        let (cache, calls) = counting(Duration::from_secs(60));
+
+        for _ in 0..3 {
+            let err = cache.stat(Path::new("/missing")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
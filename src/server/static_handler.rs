@@ -1,49 +1,335 @@
-use std::fs::File;
-use std::io::{Read, Result};
+use chrono::{TimeDelta, Utc};
+use regex::Regex;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tracing::error;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tracing::{error, warn};
 
-use crate::config::ServerConfig;
-use crate::http::{StatusCode, response::Response};
+use crate::config::{CacheRule, ServerConfig};
+use crate::http::StatusCode;
+use crate::http::mime::{self, DEFAULT_CONTENT_TYPE, SNIFF_LENGTH, content_type_for};
+use crate::http::request::Request;
+use crate::http::response::{Response, format_http_date};
+use crate::server::compression::Gzip;
+use crate::server::file_cache::FileCache;
+use crate::server::negative_cache::NegativeCache;
+use crate::server::stat_cache::{FileStat, StatCache};
+
+/// Fingerprinted files change name whenever their content does, so clients can
+/// keep them as long as they like
+const FINGERPRINTED_MAX_AGE: u64 = 31536000;
+static FINGERPRINTED_CACHE_CONTROL: LazyLock<String> =
+    LazyLock::new(|| format!("public, max-age={}, immutable", FINGERPRINTED_MAX_AGE));
 
 pub struct StaticFileHandler {
     root_dir: PathBuf,
-    default_index: String,
+    /// The root is a single file, served whatever the path
+    single_file: bool,
+    default_index: Vec<String>,
+    try_extensions: Vec<String>,
+    spa_fallback: bool,
+    deny_patterns: Vec<String>,
+    /// Patterns with the `Cache-Control` value for files they match, and the max
+    /// age `Expires` is derived from
+    cache_rules: Vec<(String, String, Option<u64>)>,
+    fingerprint: Option<Regex>,
+    not_found_page: Option<String>,
+    mime_sniffing: bool,
+    gzip: Gzip,
+    cache: Option<Arc<FileCache>>,
+    stats: Option<Arc<StatCache>>,
+    missing: Option<NegativeCache>,
 }
 
 impl StaticFileHandler {
     pub fn new(config: Arc<ServerConfig>) -> Self {
         StaticFileHandler {
             root_dir: PathBuf::from(&config.doc_root),
+            single_file: Path::new(&config.doc_root).is_file(),
             default_index: config.default_index.clone(),
+            try_extensions: config.try_extensions.clone(),
+            spa_fallback: config.spa_fallback,
+            deny_patterns: config.deny_patterns.clone(),
+            cache_rules: config
+                .cache_rules
+                .iter()
+                .map(|rule| {
+                    let max_age = rule.max_age.filter(|_| !rule.no_cache);
+                    (rule.pattern.clone(), cache_control(rule), max_age)
+                })
+                .collect(),
+            fingerprint: config.fingerprint_pattern.as_deref().and_then(|pattern| {
+                Regex::new(pattern)
+                    .inspect_err(|e| warn!("Ignoring invalid fingerprint pattern: {}", e))
+                    .ok()
+            }),
+            not_found_page: config.not_found_page.clone(),
+            mime_sniffing: config.sniffs_content(),
+            gzip: Gzip::new(&config),
+            cache: None,
+            stats: None,
+            missing: config
+                .negative_cache_ttl
+                .map(|ttl| NegativeCache::new(Duration::from_secs(ttl))),
         }
     }
 
+    /// Serves files from `root_dir` instead of the configured document root
+    pub fn with_root_dir(mut self, root_dir: &str) -> Self {
+        self.root_dir = PathBuf::from(root_dir);
+        self.single_file = self.root_dir.is_file();
+        self
+    }
+
+    /// Serves file contents through `cache`, which may be shared between handlers
+    pub fn with_cache(mut self, cache: Option<Arc<FileCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Looks up file metadata through `stats`, which may be shared between handlers
+    pub fn with_stat_cache(mut self, stats: Option<Arc<StatCache>>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Gzips `response` to `request` when configured, see `Gzip::apply`
+    pub fn compress(&self, request: &Request, response: Response) -> Response {
+        self.gzip.apply(request, response)
+    }
+
     pub fn serve(&self, path: &str) -> Response {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        if self.single_file {
+            return self.serve_file(&self.root_dir, &self.root_dir);
+        }
         let normalized_path = self.normalize_path(path);
-        let file_path = self.root_dir.join(normalized_path);
+        let file_path = self.root_dir.join(&normalized_path);
+        if self
+            .missing
+            .as_ref()
+            .is_some_and(|missing| missing.is_missing(&file_path))
+        {
+            return self.not_found();
+        }
+
+        let requested_path = file_path.clone();
+        let file_path = if self.is_dir(&file_path) {
+            // Relative links in the index only resolve inside the directory when
+            // its URL ends with a slash
+            if !path.ends_with('/') && !self.is_denied(&file_path) {
+                return directory_redirect(&normalized_path, query);
+            }
+            self.find_index(&file_path)
+        } else {
+            file_path
+        };
+        let file_path = self.resolve_file(file_path);
 
-        match self.read_file(&file_path) {
-            Ok((content, content_type)) => Response::new()
-                .with_status(StatusCode::Ok)
-                .with_content_type(&content_type)
-                .with_body(content),
+        if self.is_denied(&file_path) {
+            // Same response as a missing file so existence isn't leaked
+            error!("Denied access to {}", file_path.display());
+            return self.not_found();
+        }
+
+        self.serve_file(&file_path, &requested_path)
+    }
+
+    /// Response for the resolved `file_path`, remembering `requested_path` as
+    /// missing when it doesn't exist
+    fn serve_file(&self, file_path: &Path, requested_path: &Path) -> Response {
+        match self.load(file_path) {
+            Ok(mut response) => {
+                if let Some(content_type) = self.sniffed_type(&response, file_path) {
+                    response = response.with_content_type(content_type);
+                }
+                match self.cache_control_for(file_path) {
+                    Some((value, max_age)) => {
+                        with_expires(response.with_header("Cache-Control", value), max_age)
+                    }
+                    None => response,
+                }
+            }
             Err(e) => {
-                error!("Error Serving file: {}", e);
-                Response::new()
-                    .with_status(StatusCode::NotFound)
-                    .with_text(&StatusCode::NotFound.status_text())
+                error!("Error Serving file {}: {}", file_path.display(), e);
+                match e.kind() {
+                    io::ErrorKind::NotFound => {
+                        if let Some(missing) = &self.missing {
+                            missing.insert(requested_path);
+                        }
+                        self.not_found()
+                    }
+                    io::ErrorKind::NotADirectory | io::ErrorKind::IsADirectory => self.not_found(),
+                    // The file is there, the server just isn't allowed to read it
+                    io::ErrorKind::PermissionDenied => status_response(StatusCode::Forbidden),
+                    _ => status_response(StatusCode::InternalServerError),
+                }
             }
         }
     }
 
-    fn normalize_path(&self, path: &str) -> String {
-        let path = path.trim_start_matches('/');
+    /// The file at `file_path`, through the content cache if enabled. A path the
+    /// stat cache knows is missing or a directory isn't opened at all
+    fn load(&self, file_path: &Path) -> io::Result<Response> {
+        let is_dir = match &self.stats {
+            Some(stats) => stats.stat(file_path)?.is_dir,
+            None => false,
+        };
+        if is_dir {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        }
+        match &self.cache {
+            Some(cache) => cache.load(file_path),
+            None => Response::from_file(file_path),
+        }
+    }
+
+    fn stat(&self, path: &Path) -> Option<FileStat> {
+        match &self.stats {
+            Some(stats) => stats.stat(path).ok(),
+            None => fs::metadata(path)
+                .ok()
+                .map(|metadata| FileStat::from(&metadata)),
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.stat(path).is_some_and(|stat| stat.is_dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.stat(path).is_some_and(|stat| !stat.is_dir)
+    }
+
+    /// `404` with the configured not found page as its body, or the status text
+    /// when there's no page or it can't be read
+    fn not_found(&self) -> Response {
+        let Some(page) = &self.not_found_page else {
+            return not_found();
+        };
+        let page_path = self.root_dir.join(self.normalize_path(page));
+        match fs::read(&page_path) {
+            Ok(body) => Response::new()
+                .with_status(StatusCode::NotFound)
+                .with_content_type(content_type_for(&page_path))
+                .with_body(body),
+            Err(e) => {
+                error!(
+                    "Error serving not found page {}: {}",
+                    page_path.display(),
+                    e
+                );
+                not_found()
+            }
+        }
+    }
+
+    /// Falls back to the configured extensions for clean URLs, then to the SPA
+    /// index, only when nothing exists at the exact path
+    fn resolve_file(&self, file_path: PathBuf) -> PathBuf {
+        if self.stat(&file_path).is_some() || file_path.extension().is_some() {
+            return file_path;
+        }
+
+        let candidate = self
+            .try_extensions
+            .iter()
+            .map(|ext| file_path.with_extension(ext.trim_start_matches('.')))
+            .find(|candidate| self.is_file(candidate));
 
-        if path.is_empty() {
-            return self.default_index.clone();
+        match candidate {
+            Some(candidate) => candidate,
+            // Missing assets (paths with an extension) still 404 above
+            None if self.spa_fallback => self.find_index(&self.root_dir),
+            None => file_path,
         }
+    }
+
+    /// First index candidate that exists in `dir`, or the first candidate so the
+    /// request 404s when none do
+    fn find_index(&self, dir: &Path) -> PathBuf {
+        self.default_index
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|candidate| self.is_file(candidate))
+            .or_else(|| self.default_index.first().map(|name| dir.join(name)))
+            .unwrap_or_else(|| dir.to_path_buf())
+    }
+
+    fn is_denied(&self, file_path: &Path) -> bool {
+        let relative = file_path.strip_prefix(&self.root_dir).unwrap_or(file_path);
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+
+        if components.iter().any(|c| c.starts_with('.')) {
+            return true;
+        }
+
+        let relative = components.join("/");
+        self.deny_patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), &relative)
+            } else {
+                components.iter().any(|c| glob_match(pattern, c))
+            }
+        })
+    }
+
+    /// Content type from the first bytes of a file whose extension gave none
+    fn sniffed_type(&self, response: &Response, file_path: &Path) -> Option<&'static str> {
+        if !self.mime_sniffing
+            || response.headers.get("Content-Type").map(String::as_str)
+                != Some(DEFAULT_CONTENT_TYPE)
+        {
+            return None;
+        }
+        if response.body_file.is_none() {
+            return mime::sniff(&response.body);
+        }
+
+        // Streamed files are read from their own handle, keeping the body's position
+        let mut start = Vec::with_capacity(SNIFF_LENGTH);
+        File::open(file_path)
+            .and_then(|file| file.take(SNIFF_LENGTH as u64).read_to_end(&mut start))
+            .ok()?;
+        mime::sniff(&start)
+    }
+
+    /// `Cache-Control` value for `file_path`, with the max age it allows caching
+    /// for, if any
+    fn cache_control_for(&self, file_path: &Path) -> Option<(&str, Option<u64>)> {
+        let relative = file_path.strip_prefix(&self.root_dir).unwrap_or(file_path);
+        let file_name = relative.file_name()?.to_string_lossy();
+        if self
+            .fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| fingerprint.is_match(&file_name))
+        {
+            return Some((&FINGERPRINTED_CACHE_CONTROL, Some(FINGERPRINTED_MAX_AGE)));
+        }
+        let relative = relative.to_string_lossy().replace('\\', "/");
+
+        self.cache_rules
+            .iter()
+            .find(|(pattern, _, _)| {
+                if pattern.contains('/') {
+                    glob_match(pattern.trim_start_matches('/'), &relative)
+                } else {
+                    glob_match(pattern, &file_name)
+                }
+            })
+            .map(|(_, value, max_age)| (value.as_str(), *max_age))
+    }
+
+    fn normalize_path(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
 
         let path = Path::new(path);
         let mut normalized = PathBuf::new();
@@ -55,47 +341,106 @@ impl StaticFileHandler {
             }
         }
 
-        if normalized.to_string_lossy().ends_with('/') || normalized.to_string_lossy().is_empty() {
-            normalized.push(self.default_index.clone());
-        }
-
         normalized.to_string_lossy().to_string()
     }
+}
 
-    fn read_file(&self, path: &Path) -> Result<(Vec<u8>, String)> {
-        let mut file = File::open(path)?;
-        let mut content = Vec::new();
+/// `301` to the directory at `normalized_path` with a trailing slash
+fn directory_redirect(normalized_path: &str, query: Option<&str>) -> Response {
+    let mut location = String::from("/");
+    for segment in normalized_path.split(['/', '\\']).filter(|s| !s.is_empty()) {
+        location.push_str(segment);
+        location.push('/');
+    }
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(query);
+    }
+    Response::redirect(&location, StatusCode::MovedPermanently)
+}
 
-        file.read_to_end(&mut content)?;
-        let content_type = self.get_content_type(path);
+/// `Expires` matching `max_age`, for caches that predate `Cache-Control`
+fn with_expires(response: Response, max_age: Option<u64>) -> Response {
+    let Some(expires) = max_age
+        .and_then(|max_age| i64::try_from(max_age).ok())
+        .and_then(TimeDelta::try_seconds)
+        .and_then(|max_age| Utc::now().checked_add_signed(max_age))
+    else {
+        return response;
+    };
+    response.with_header("Expires", &format_http_date(expires))
+}
 
-        Ok((content, content_type))
+/// `Cache-Control` value for `rule`, e.g. `public, max-age=31536000, immutable`
+fn cache_control(rule: &CacheRule) -> String {
+    let mut directives = vec![String::from(if rule.no_cache {
+        "no-cache"
+    } else {
+        "public"
+    })];
+    if let Some(max_age) = rule.max_age {
+        directives.push(format!("max-age={}", max_age));
+    }
+    if rule.immutable {
+        directives.push(String::from("immutable"));
     }
+    directives.join(", ")
+}
+
+fn not_found() -> Response {
+    status_response(StatusCode::NotFound)
+}
+
+fn status_response(status: StatusCode) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_text(&status.status_text())
+}
+
+/// Matches `text` against a glob supporting `*` (any run of characters) and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
 
-    fn get_content_type(&self, path: &Path) -> String {
-        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
 
-        match extension.to_lowercase().as_str() {
-            "html" | "htm" => String::from("text/html"),
-            "css" => String::from("text/css"),
-            "js" => String::from("application/javascript"),
-            "jpg" | "jpeg" => String::from("image/jpeg"),
-            "png" => String::from("image/png"),
-            "gif" => String::from("image/gif"),
-            "svg" => String::from("image/svg+xml"),
-            "json" => String::from("application/json"),
-            "txt" => String::from("text/plain"),
-            _ => String::from("application/octet-stream"),
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
         }
     }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::PathBuf, sync::Arc};
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::UNIX_EPOCH;
+    use std::{fs, path::Path, path::PathBuf, sync::Arc, time::Duration};
 
-    use super::StaticFileHandler;
-    use crate::{config::ServerConfig, http::StatusCode};
+    use super::{StaticFileHandler, glob_match};
+    use crate::http::response::format_http_date;
+    use crate::server::file_cache::FileCache;
+    use crate::server::stat_cache::{FileStat, StatCache};
+    use crate::{
+        config::{CacheRule, SecurityHeadersConfig, ServerConfig},
+        http::StatusCode,
+    };
 
     fn setup(path: Option<PathBuf>, file_name: &str, file_content: &str) -> PathBuf {
         let temp_dir = tempfile::tempdir().unwrap().path().to_path_buf();
@@ -157,6 +502,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_serve_first_existing_index_candidate() {
+        let root_path = setup(
+            Some(PathBuf::from("docs")),
+            "index.htm",
+            "<html>docs</html>",
+        );
+        fs::write(root_path.join("index.htm"), "<html>root</html>").unwrap();
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.default_index = vec![String::from("index.html"), String::from("index.htm")];
+
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/");
+        assert_eq!(response.status, StatusCode::Ok, "index candidate not found");
+        assert_eq!(response.body, b"<html>root</html>", "content mismatch");
+
+        let response = handler.serve("/docs/");
+        assert_eq!(
+            response.status,
+            StatusCode::Ok,
+            "subdirectory index not found"
+        );
+        assert_eq!(response.body, b"<html>docs</html>", "content mismatch");
+    }
+
     #[test]
     fn test_prevent_directory_traversal() {
         let root_path = setup(
@@ -185,4 +557,413 @@ mod tests {
             "directory traversal is allowed"
         );
     }
+
+    #[test]
+    fn test_serve_clean_url_with_try_extensions() {
+        let root_path = setup(None, "about.html", "<html>about</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.try_extensions = vec![String::from("htm"), String::from("html")];
+
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/about");
+        assert_eq!(response.status, StatusCode::Ok, "clean url not resolved");
+        assert_eq!(response.body, b"<html>about</html>", "content mismatch");
+
+        let response = handler.serve("/contact");
+        assert_eq!(response.status, StatusCode::NotFound, "missing page served");
+    }
+
+    #[test]
+    fn test_spa_fallback() {
+        let root_path = setup(None, "index.html", "<html>app</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.spa_fallback = true;
+
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/dashboard/settings");
+        assert_eq!(response.status, StatusCode::Ok, "client route not served");
+        assert_eq!(response.body, b"<html>app</html>", "content mismatch");
+
+        let response = handler.serve("/missing.js");
+        assert_eq!(
+            response.status,
+            StatusCode::NotFound,
+            "missing asset served"
+        );
+    }
+
+    #[test]
+    fn test_deny_dotfiles_and_patterns() {
+        let root_path = setup(None, ".env", "SECRET=1");
+        fs::write(root_path.join("db.bak"), "backup").unwrap();
+        fs::write(root_path.join("index.html"), "<html>ok</html>").unwrap();
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.deny_patterns = vec![String::from("*.bak")];
+
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        assert_eq!(handler.serve("/.env").status, StatusCode::NotFound);
+        assert_eq!(handler.serve("/db.bak").status, StatusCode::NotFound);
+        assert_eq!(handler.serve("/index.html").status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_negative_cache_remembers_missing_file() {
+        let root_path = setup(None, "index.html", "<html>ok</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.negative_cache_ttl = Some(1);
+
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        assert_eq!(handler.serve("/late.txt").status, StatusCode::NotFound);
+        fs::write(root_path.join("late.txt"), "here now").unwrap();
+        assert_eq!(
+            handler.serve("/late.txt").status,
+            StatusCode::NotFound,
+            "missing file not answered from the negative cache"
+        );
+
+        std::thread::sleep(Duration::from_millis(1100));
+        let response = handler.serve("/late.txt");
+        assert_eq!(
+            response.status,
+            StatusCode::Ok,
+            "file still cached as missing"
+        );
+        assert_eq!(response.body, b"here now");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.bak", "db.bak"));
+        assert!(glob_match("backup/*", "backup/db.sql"));
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(!glob_match("*.bak", "db.bak.txt"));
+    }
+
+    #[test]
+    fn test_directory_without_trailing_slash_redirects() {
+        let root_path = setup(
+            Some(PathBuf::from("docs")),
+            "index.html",
+            "<html>docs</html>",
+        );
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/docs");
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&"/docs/".to_string())
+        );
+
+        let response = handler.serve("/docs?page=2");
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&"/docs/?page=2".to_string())
+        );
+
+        let response = handler.serve("/docs/");
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.body, b"<html>docs</html>", "content mismatch");
+    }
+
+    #[test]
+    fn test_cache_rules() {
+        let root_path = setup(Some(PathBuf::from("fonts")), "body.woff2", "font");
+        fs::write(root_path.join("index.html"), "<html>home</html>").unwrap();
+        fs::write(root_path.join("notes.txt"), "notes").unwrap();
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.cache_rules = vec![
+            CacheRule {
+                pattern: String::from("*.woff2"),
+                max_age: Some(31536000),
+                immutable: true,
+                no_cache: false,
+            },
+            CacheRule {
+                pattern: String::from("*.html"),
+                max_age: None,
+                immutable: false,
+                no_cache: true,
+            },
+        ];
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/fonts/body.woff2");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=31536000, immutable".to_string())
+        );
+
+        let response = handler.serve("/");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"no-cache".to_string())
+        );
+
+        let response = handler.serve("/notes.txt");
+        assert!(!response.headers.contains_key("Cache-Control"));
+    }
+
+    #[test]
+    fn test_default_cache_rules() {
+        let root_path = setup(None, "style.css", "body {}");
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/style.css");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=3600".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fingerprinted_files_cached_immutable() {
+        let root_path = setup(None, "app.3f9a2b.js", "fingerprinted");
+        fs::write(root_path.join("app.js"), "plain").unwrap();
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.fingerprint_pattern = Some(String::from(r"\.[0-9a-f]{6,}\."));
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/app.3f9a2b.js");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=31536000, immutable".to_string())
+        );
+
+        let response = handler.serve("/app.js");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=3600".to_string())
+        );
+    }
+
+    #[test]
+    fn test_custom_not_found_page() {
+        let root_path = setup(None, "404.html", "<html>lost</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.not_found_page = Some(String::from("404.html"));
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/missing.html");
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(response.body, b"<html>lost</html>", "content mismatch");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some(&"text/html".to_string())
+        );
+    }
+
+    #[test]
+    fn test_missing_not_found_page_falls_back() {
+        let root_path = setup(None, "index.html", "<html>home</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.not_found_page = Some(String::from("404.html"));
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/missing.html");
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(
+            response.body,
+            StatusCode::NotFound.status_text().as_bytes(),
+            "content mismatch"
+        );
+    }
+
+    #[test]
+    fn test_mime_sniffing() {
+        let root_path = setup(None, "about", "<!DOCTYPE html><html>about</html>");
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.mime_sniffing = true;
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/about");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some(&"text/html".to_string())
+        );
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.mime_sniffing = true;
+        server_config.security_headers = Some(SecurityHeadersConfig::default());
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/about");
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some(&"application/octet-stream".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stat_cache_skips_repeated_stats() {
+        let root_path = setup(None, "app.js", "console.log(1)");
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let stats = StatCache::new(Duration::from_secs(60)).with_lookup(move |path: &Path| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            fs::metadata(path).map(|metadata| FileStat::from(&metadata))
+        });
+        let handler =
+            StaticFileHandler::new(Arc::new(server_config)).with_stat_cache(Some(Arc::new(stats)));
+
+        for _ in 0..3 {
+            let response = handler.serve("/app.js");
+            assert_eq!(response.status, StatusCode::Ok);
+            assert_eq!(response.body, b"console.log(1)", "content mismatch");
+            assert!(response.headers.contains_key("Last-Modified"));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        for _ in 0..3 {
+            assert_eq!(handler.serve("/missing.js").status, StatusCode::NotFound);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_changed_file_served_with_fresh_metadata() {
+        let root_path = setup(None, "app.js", "console.log(1)");
+        let file_path = root_path.join("app.js");
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        let server_config = Arc::new(server_config);
+        let stats = Arc::new(StatCache::new(Duration::from_secs(60)));
+        let handlers = [
+            StaticFileHandler::new(Arc::clone(&server_config)).with_stat_cache(Some(stats)),
+            StaticFileHandler::new(server_config)
+                .with_stat_cache(Some(Arc::new(StatCache::new(Duration::from_secs(60)))))
+                .with_cache(Some(Arc::new(FileCache::new(1024, 1024)))),
+        ];
+
+        for handler in handlers {
+            fs::write(&file_path, "console.log(1)").unwrap();
+            assert_eq!(handler.serve("/app.js").status, StatusCode::Ok);
+
+            let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+            fs::write(&file_path, "console.log('changed')").unwrap();
+            File::options()
+                .write(true)
+                .open(&file_path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+
+            let response = handler.serve("/app.js");
+            assert_eq!(response.body, b"console.log('changed')");
+            assert_eq!(
+                response.headers.get("Last-Modified"),
+                Some(&format_http_date(DateTime::<Utc>::from(modified)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_file_root_served_for_any_path() {
+        let root_path = setup(None, "report.pdf", "%PDF-1.4 report");
+        let file_path = root_path.join("report.pdf");
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &file_path.to_string_lossy());
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        for path in ["/", "/report.pdf", "/any/other/path.html", "/?download=1"] {
+            let response = handler.serve(path);
+            assert_eq!(response.status, StatusCode::Ok, "{}", path);
+            assert_eq!(
+                response.headers.get("Content-Type").map(String::as_str),
+                Some("application/pdf"),
+                "{}",
+                path
+            );
+            assert_eq!(response.body, b"%PDF-1.4 report", "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_expires_matches_max_age() {
+        let root_path = setup(None, "app.js", "console.log(1)");
+        fs::write(root_path.join("index.html"), "<html>home</html>").unwrap();
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        server_config.cache_rules = vec![
+            CacheRule {
+                pattern: String::from("*.js"),
+                max_age: Some(3600),
+                immutable: false,
+                no_cache: false,
+            },
+            CacheRule {
+                pattern: String::from("*.html"),
+                max_age: Some(3600),
+                immutable: false,
+                no_cache: true,
+            },
+        ];
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        let response = handler.serve("/app.js");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"public, max-age=3600".to_string())
+        );
+        let expires = NaiveDateTime::parse_from_str(
+            &response.headers["Expires"],
+            "%a, %d %b %Y %H:%M:%S GMT",
+        )
+        .unwrap()
+        .and_utc();
+        let offset = (expires - Utc::now()).num_seconds();
+        assert!(
+            (3590..=3600).contains(&offset),
+            "Expires off by {}s",
+            offset
+        );
+
+        let response = handler.serve("/");
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some(&"no-cache, max-age=3600".to_string())
+        );
+        assert!(!response.headers.contains_key("Expires"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_file_forbidden() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_path = setup(None, "secret.txt", "secret");
+        let file_path = root_path.join("secret.txt");
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads files regardless of their permissions
+        if fs::read(&file_path).is_ok() {
+            return;
+        }
+        let server_config =
+            ServerConfig::with_params("127.0.0.1", 8080, 1, &root_path.to_string_lossy());
+        let handler = StaticFileHandler::new(Arc::new(server_config));
+
+        assert_eq!(handler.serve("/secret.txt").status, StatusCode::Forbidden);
+        assert_eq!(handler.serve("/missing.txt").status, StatusCode::NotFound);
+    }
 }
@@ -1,17 +1,24 @@
 #[cfg(test)]
 mod tests {
     use super::super::*;
+    use crate::config::{ProxyRule, TlsCertificate, TlsConfig};
+    use crate::http;
     use reqwest::blocking::Client;
     use std::io::{Read, Write};
+    use std::net::TcpListener;
     use std::path::PathBuf;
     use std::sync::Barrier;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use std::{fs, thread};
     use tempfile::tempdir;
 
     fn start_test_server(ip: &str, port: u16, root_dir: PathBuf) -> thread::JoinHandle<()> {
         let root_dir = root_dir.to_string_lossy().to_string();
-        let server_config = Arc::new(ServerConfig::with_params(ip, port, 1, &root_dir));
+        start_test_server_with_config(ServerConfig::with_params(ip, port, 1, &root_dir))
+    }
+
+    fn start_test_server_with_config(server_config: ServerConfig) -> thread::JoinHandle<()> {
+        let server_config = Arc::new(server_config);
 
         let handle = thread::spawn(move || {
             let server = Server::new(server_config);
@@ -22,6 +29,72 @@ mod tests {
         return handle;
     }
 
+    fn tls_fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Serves the `localhost` fixture certificate, tests override what they need
+    fn tls_config() -> TlsConfig {
+        TlsConfig {
+            cert_path: tls_fixture("localhost.crt"),
+            key_path: tls_fixture("localhost.key"),
+            min_version: None,
+            certificates: Vec::new(),
+            reload_interval: None,
+            client_ca_path: None,
+        }
+    }
+
+    /// Client trusting the test CA that signed the fixture certificates
+    fn tls_client(
+        port: u16,
+        server_name: &str,
+    ) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        tls_client_with_certificate(port, server_name, None)
+    }
+
+    /// Like `tls_client`, presenting the fixture certificate `identity` if given
+    fn tls_client_with_certificate(
+        port: u16,
+        server_name: &str,
+        identity: Option<&str>,
+    ) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(tls_fixture("ca.crt")).unwrap())
+            .unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+        let config = match identity {
+            Some(identity) => config
+                .with_client_auth_cert(
+                    vec![
+                        CertificateDer::from_pem_file(tls_fixture(&format!("{}.crt", identity)))
+                            .unwrap(),
+                    ],
+                    PrivateKeyDer::from_pem_file(tls_fixture(&format!("{}.key", identity)))
+                        .unwrap(),
+                )
+                .unwrap(),
+            None => config.with_no_client_auth(),
+        };
+
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        rustls::StreamOwned::new(connection, socket)
+    }
+
     #[test]
     fn test_server_responds_to_request() {
         let temp_dir = tempdir().unwrap();
@@ -144,6 +217,1374 @@ mod tests {
                 headers.contains_key("connection"),
                 "Response missing Connection header"
             );
+
+            // Reading the body hands the connection back to the client's pool, an
+            // unread one is dropped and the next request races its close for the
+            // server's single connection slot
+            assert_eq!(response.text().unwrap(), "Hello From Xener Server!");
+        }
+    }
+
+    #[test]
+    fn test_unknown_host_is_misdirected_in_strict_mode() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        fs::write(temp_dir.path().join("index.html"), "default").unwrap();
+
+        let mut config = ServerConfig::with_params("127.0.0.1", 8084, 1, &root_dir);
+        config.virtual_hosts = vec![crate::config::VirtualHost {
+            host: String::from("example.com"),
+            doc_root: root_dir.clone(),
+        }];
+        config.strict_vhost = true;
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = TcpStream::connect("127.0.0.1:8084").unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: unknown.com\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 421 Misdirected Request"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_options_request_lists_allowed_methods() {
+        let _ = start_test_server("127.0.0.1", 8085, tempdir().unwrap().path().to_path_buf());
+
+        let mut stream = TcpStream::connect("127.0.0.1:8085").unwrap();
+        stream
+            .write_all(b"OPTIONS / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 204 No Content"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(
+            response.contains("Allow: GET, HEAD, OPTIONS\r\n"),
+            "Response missing Allow header"
+        );
+    }
+
+    #[test]
+    fn test_registered_handler_echoes_post_body() {
+        let root_dir = tempdir().unwrap();
+        let server_config = Arc::new(ServerConfig::with_params(
+            "127.0.0.1",
+            8086,
+            1,
+            &root_dir.path().to_string_lossy(),
+        ));
+        thread::spawn(move || {
+            let server = Server::new(server_config).with_handler(
+                Method::POST,
+                |request: &http::request::Request| {
+                    http::response::Response::new()
+                        .with_status(http::StatusCode::Ok)
+                        .with_body(request.body.clone())
+                },
+            );
+            let _ = server.run();
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect("127.0.0.1:8086").unwrap();
+        stream
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello xener",
+            )
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nhello xener"));
+    }
+
+    #[test]
+    fn test_response_carries_request_id() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let _ = start_test_server("127.0.0.1", 8087, temp_dir.path().to_path_buf());
+
+        let client = Client::new();
+        let response = client.get("http://127.0.0.1:8087/").send().unwrap();
+        let generated = response.headers().get("x-request-id").unwrap();
+        assert!(!generated.is_empty(), "empty request id");
+
+        let response = client
+            .get("http://127.0.0.1:8087/")
+            .header("X-Request-Id", "client-supplied-42")
+            .send()
+            .unwrap();
+        assert_eq!(
+            response.headers().get("x-request-id").unwrap(),
+            "client-supplied-42"
+        );
+    }
+
+    #[test]
+    fn test_invalid_request_id_replaced_in_access_log() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8118, 4, &temp_dir.path().to_string_lossy());
+        server_config.access_log_path = log_path.to_string_lossy().to_string();
+        server_config.access_log_flush_lines = Some(1);
+        server_config.log_format = Some(String::from("json"));
+        let _ = start_test_server_with_config(server_config);
+
+        let response = Client::new()
+            .get("http://127.0.0.1:8118/index.html")
+            .header("X-Request-Id", "not valid")
+            .send()
+            .unwrap();
+        let generated = response.headers().get("x-request-id").unwrap().clone();
+        assert_ne!(generated, "not valid");
+        thread::sleep(Duration::from_millis(100));
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(entry["request_id"], generated.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_idle_keep_alive_connection_is_closed() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8088, 1, &temp_dir.path().to_string_lossy());
+        config.keep_alive_timeout = Some(1);
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = TcpStream::connect("127.0.0.1:8088").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        while !response.ends_with(b"hello") {
+            let read = stream.read(&mut buffer).unwrap();
+            assert!(read > 0, "connection closed before the response");
+            response.extend_from_slice(&buffer[..read]);
+        }
+
+        let idle_start = std::time::Instant::now();
+        let read = stream
+            .read(&mut buffer)
+            .expect("idle connection was not closed");
+        assert_eq!(read, 0, "unexpected data on idle connection");
+        assert!(
+            idle_start.elapsed() < Duration::from_secs(4),
+            "idle connection closed too late: {:?}",
+            idle_start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_health_check_endpoint() {
+        let _ = start_test_server(
+            "127.0.0.1",
+            8089,
+            PathBuf::from("/nonexistent/xener/doc_root"),
+        );
+
+        let response = Client::new()
+            .get("http://127.0.0.1:8089/healthz")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().unwrap(), "ok");
+    }
+
+    #[test]
+    fn test_health_check_path_is_configurable() {
+        let mut config = ServerConfig::with_params(
+            "127.0.0.1",
+            8090,
+            1,
+            &tempdir().unwrap().path().to_string_lossy(),
+        );
+        config.health_check_path = Some(String::from("/status/live"));
+        let _ = start_test_server_with_config(config);
+
+        let client = Client::new();
+        let response = client
+            .get("http://127.0.0.1:8090/status/live")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().unwrap(), "ok");
+
+        let response = client.get("http://127.0.0.1:8090/healthz").send().unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8091, 1, &temp_dir.path().to_string_lossy());
+        config.metrics_path = Some(String::from("/metrics"));
+        let _ = start_test_server_with_config(config);
+
+        let client = Client::new();
+        client.get("http://127.0.0.1:8091/").send().unwrap();
+
+        let response = client.get("http://127.0.0.1:8091/metrics").send().unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().unwrap();
+        assert!(
+            body.contains("# TYPE xener_requests_total counter"),
+            "unexpected metrics: {}",
+            body
+        );
+        assert!(
+            body.contains("xener_requests_total 1\n"),
+            "unexpected metrics: {}",
+            body
+        );
+        assert!(body.contains("xener_responses_total{code=\"200\"} 1\n"));
+    }
+
+    #[test]
+    fn test_expect_continue_sends_interim_response() {
+        let _ = start_test_server("127.0.0.1", 8092, tempdir().unwrap().path().to_path_buf());
+
+        let mut stream = TcpStream::connect("127.0.0.1:8092").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(
+                b"PUT /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        stream.write_all(b"hello").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_back_to_back_requests_in_one_write() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("a.txt"), "first").unwrap();
+        fs::write(temp_dir.path().join("b.txt"), "second").unwrap();
+        let _ = start_test_server("127.0.0.1", 8093, temp_dir.path().to_path_buf());
+
+        let mut stream = TcpStream::connect("127.0.0.1:8093").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(
+                b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /b.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK").count(),
+            2,
+            "unexpected response: {}",
+            response
+        );
+        let first = response.find("first").expect("first response missing");
+        let second = response.find("second").expect("second response missing");
+        assert!(first < second, "responses out of order");
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_answered_in_order() {
+        let temp_dir = tempdir().unwrap();
+        for name in ["one", "two", "three"] {
+            fs::write(temp_dir.path().join(format!("{}.txt", name)), name).unwrap();
+        }
+        let _ = start_test_server("127.0.0.1", 8094, temp_dir.path().to_path_buf());
+
+        let mut stream = TcpStream::connect("127.0.0.1:8094").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(
+                b"GET /one.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /two.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /three.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let bodies: Vec<&str> = response
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .filter_map(|part| part.split("\r\n\r\n").nth(1))
+            .collect();
+        assert_eq!(
+            bodies,
+            vec!["one", "two", "three"],
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_slow_headers_get_request_timeout() {
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8095, 1, &temp_dir.path().to_string_lossy());
+        config.header_read_timeout = Some(1);
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = TcpStream::connect("127.0.0.1:8095").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Each byte arrives well within the read timeout, the headers never finish
+        let start = std::time::Instant::now();
+        stream.write_all(b"GET / HTTP/1.1\r\nHo").unwrap();
+        for byte in b"st:" {
+            thread::sleep(Duration::from_millis(300));
+            stream.write_all(&[*byte]).unwrap();
+        }
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(4),
+            "connection closed too late: {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_large_file_sent_intact() {
+        let temp_dir = tempdir().unwrap();
+        let content: Vec<u8> = (0..3 * 1024 * 1024u32)
+            .map(|i| (i.wrapping_mul(31) >> 3) as u8)
+            .collect();
+        fs::write(temp_dir.path().join("large.bin"), &content).unwrap();
+        let _ = start_test_server("127.0.0.1", 8096, temp_dir.path().to_path_buf());
+
+        let mut stream = TcpStream::connect("127.0.0.1:8096").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("no end of headers")
+            + 4;
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        assert!(
+            head.starts_with("HTTP/1.1 200 OK"),
+            "unexpected head: {}",
+            head
+        );
+        assert!(head.contains(&format!("Content-Length: {}", content.len())));
+        assert!(response[head_end..] == content[..], "file content mismatch");
+    }
+
+    #[test]
+    fn test_download_paced_by_bandwidth_limit() {
+        let temp_dir = tempdir().unwrap();
+        // Past the in-memory size, so it's sent from the open file, and under it
+        let large: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let small: Vec<u8> = (0..48 * 1024u32).map(|i| (i % 241) as u8).collect();
+        fs::write(temp_dir.path().join("large.bin"), &large).unwrap();
+        fs::write(temp_dir.path().join("small.bin"), &small).unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        let mut config = ServerConfig::with_params("127.0.0.1", 8125, 1, &root_dir);
+        config.max_bytes_per_sec = Some(128 * 1024);
+        let _ = start_test_server_with_config(config);
+
+        let client = Client::new();
+        for (path, content, min_elapsed) in [
+            ("large.bin", &large, Duration::from_millis(1500)),
+            ("small.bin", &small, Duration::from_millis(200)),
+        ] {
+            let start = Instant::now();
+            let body = client
+                .get(format!("http://127.0.0.1:8125/{}", path))
+                .send()
+                .unwrap()
+                .bytes()
+                .unwrap();
+            let elapsed = start.elapsed();
+
+            assert!(body[..] == content[..], "{} content mismatch", path);
+            assert!(
+                elapsed >= min_elapsed,
+                "{} downloaded too quickly: {:?}",
+                path,
+                elapsed
+            );
         }
     }
+
+    #[test]
+    fn test_serve_file_over_tls() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("secure.txt"), "over tls").unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8097, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(TlsConfig {
+            min_version: Some(String::from("1.2")),
+            ..tls_config()
+        });
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = tls_client(8097, "localhost");
+        stream
+            .write_all(b"GET /secure.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nover tls"));
+    }
+
+    #[test]
+    fn test_plain_http_redirected_to_https() {
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8098, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(tls_config());
+        config.redirect_http_to_https = true;
+        config.http_port = Some(8099);
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = TcpStream::connect("127.0.0.1:8099").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /docs/page?lang=en HTTP/1.1\r\nHost: localhost:8099\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 301 Moved Permanently"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.contains("Location: https://localhost:8098/docs/page?lang=en\r\n"));
+    }
+
+    #[test]
+    fn test_stalled_client_does_not_hold_up_https_redirect() {
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8119, 2, &temp_dir.path().to_string_lossy());
+        config.tls = Some(tls_config());
+        config.redirect_http_to_https = true;
+        config.http_port = Some(8120);
+        let _ = start_test_server_with_config(config);
+
+        let _stalled = TcpStream::connect("127.0.0.1:8120").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let mut stream = TcpStream::connect("127.0.0.1:8120").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 301 Moved Permanently"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_https_redirects_in_flight_bounded() {
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8121, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(tls_config());
+        config.redirect_http_to_https = true;
+        config.http_port = Some(8122);
+        let _ = start_test_server_with_config(config);
+
+        let redirect = || {
+            let mut stream = TcpStream::connect("127.0.0.1:8122").unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response
+        };
+
+        let stalled = TcpStream::connect("127.0.0.1:8122").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(redirect(), "", "connection over the limit was served");
+
+        drop(stalled);
+        thread::sleep(Duration::from_millis(50));
+        let response = redirect();
+        assert!(
+            response.starts_with("HTTP/1.1 301 Moved Permanently"),
+            "unexpected response: {}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_security_headers_depend_on_transport() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+
+        let mut plain = ServerConfig::with_params("127.0.0.1", 8100, 1, &root_dir);
+        plain.security_headers = Some(crate::config::SecurityHeadersConfig::default());
+        let _ = start_test_server_with_config(plain);
+
+        let mut secure = ServerConfig::with_params("127.0.0.1", 8101, 1, &root_dir);
+        secure.security_headers = Some(crate::config::SecurityHeadersConfig::default());
+        secure.tls = Some(tls_config());
+        let _ = start_test_server_with_config(secure);
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        let mut stream = TcpStream::connect("127.0.0.1:8100").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.contains("X-Content-Type-Options: nosniff\r\n"));
+        assert!(!response.contains("Strict-Transport-Security"));
+
+        let mut stream = tls_client(8101, "localhost");
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.contains("X-Content-Type-Options: nosniff\r\n"));
+        assert!(
+            response.contains("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n")
+        );
+    }
+
+    #[test]
+    fn test_security_headers_on_malformed_request() {
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8123, 1, &temp_dir.path().to_string_lossy());
+        config.security_headers = Some(crate::config::SecurityHeadersConfig::default());
+        let _ = start_test_server_with_config(config);
+
+        let mut stream = TcpStream::connect("127.0.0.1:8123").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(b"NOT A REQUEST\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.contains("X-Content-Type-Options: nosniff\r\n"));
+    }
+
+    #[test]
+    fn test_certificate_selected_by_sni() {
+        use rustls::pki_types::CertificateDer;
+        use rustls::pki_types::pem::PemObject;
+
+        let temp_dir = tempdir().unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8102, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(TlsConfig {
+            certificates: vec![TlsCertificate {
+                host: String::from("example.test"),
+                cert_path: tls_fixture("example.test.crt"),
+                key_path: tls_fixture("example.test.key"),
+            }],
+            ..tls_config()
+        });
+        let _ = start_test_server_with_config(config);
+
+        for server_name in ["example.test", "localhost"] {
+            let expected =
+                CertificateDer::from_pem_file(tls_fixture(&format!("{}.crt", server_name)))
+                    .unwrap();
+
+            let mut stream = tls_client(8102, server_name);
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+
+            let served = stream.conn.peer_certificates().unwrap();
+            assert_eq!(served[0], expected, "wrong certificate for {}", server_name);
+        }
+    }
+
+    #[test]
+    fn test_tls_certificates_reloaded() {
+        use rustls::pki_types::CertificateDer;
+        use rustls::pki_types::pem::PemObject;
+
+        fn served_certificate(server_name: &str) -> Option<CertificateDer<'static>> {
+            let mut stream = tls_client(8103, server_name);
+            stream.conn.complete_io(&mut stream.sock).ok()?;
+            let certs = stream.conn.peer_certificates()?;
+            Some(certs[0].clone().into_owned())
+        }
+
+        let temp_dir = tempdir().unwrap();
+        let cert_path = temp_dir.path().join("server.crt");
+        let key_path = temp_dir.path().join("server.key");
+        fs::copy(tls_fixture("localhost.crt"), &cert_path).unwrap();
+        fs::copy(tls_fixture("localhost.key"), &key_path).unwrap();
+
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8103, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(TlsConfig {
+            cert_path: cert_path.to_string_lossy().to_string(),
+            key_path: key_path.to_string_lossy().to_string(),
+            reload_interval: Some(1),
+            ..tls_config()
+        });
+        let _ = start_test_server_with_config(config);
+
+        let localhost = CertificateDer::from_pem_file(tls_fixture("localhost.crt")).unwrap();
+        assert_eq!(served_certificate("localhost"), Some(localhost));
+
+        fs::copy(tls_fixture("example.test.key"), &key_path).unwrap();
+        fs::copy(tls_fixture("example.test.crt"), &cert_path).unwrap();
+
+        let renewed = CertificateDer::from_pem_file(tls_fixture("example.test.crt")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let served = loop {
+            match served_certificate("example.test") {
+                Some(cert) => break cert,
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(200)),
+                None => panic!("renewed certificate was never served"),
+            }
+        };
+        assert_eq!(served, renewed);
+    }
+
+    #[test]
+    fn test_client_certificate_required() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "trusted").unwrap();
+        let mut config =
+            ServerConfig::with_params("127.0.0.1", 8104, 1, &temp_dir.path().to_string_lossy());
+        config.tls = Some(TlsConfig {
+            client_ca_path: Some(tls_fixture("ca.crt")),
+            ..tls_config()
+        });
+        let _ = start_test_server_with_config(config);
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        let mut stream = tls_client_with_certificate(8104, "localhost", Some("client"));
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK"),
+            "unexpected response: {}",
+            response
+        );
+
+        for identity in [Some("untrusted-client"), None] {
+            let mut stream = tls_client_with_certificate(8104, "localhost", identity);
+            let mut response = Vec::new();
+            let result = stream
+                .write_all(request)
+                .and_then(|_| stream.read_to_end(&mut response));
+            assert!(
+                result.is_err(),
+                "client {:?} was not rejected: {}",
+                identity,
+                String::from_utf8_lossy(&response)
+            );
+        }
+    }
+
+    #[test]
+    fn test_connection_close_hook_receives_stats() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let server_config = Arc::new(ServerConfig::with_params(
+            "127.0.0.1",
+            8105,
+            1,
+            &temp_dir.path().to_string_lossy(),
+        ));
+
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let collector = Arc::clone(&closed);
+        thread::spawn(move || {
+            let server = Server::new(server_config).with_connection_close_hook(
+                move |stats: &ConnectionStats, peer_addr| {
+                    collector.lock().unwrap().push((stats.clone(), peer_addr));
+                },
+            );
+            let _ = server.run();
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect("127.0.0.1:8105").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while closed.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let closed = closed.lock().unwrap();
+        assert_eq!(closed.len(), 1, "close hook was not called once");
+        let (stats, peer_addr) = &closed[0];
+        assert_eq!(*peer_addr, client_addr);
+        assert_eq!(stats.requests_handled, 3);
+        assert_eq!(stats.bytes_sent, response.len());
+    }
+
+    #[test]
+    fn test_text_served_with_default_charset() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "<p>héllo</p>").unwrap();
+        fs::write(temp_dir.path().join("logo.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        let _ = start_test_server("127.0.0.1", 8106, temp_dir.path().to_path_buf());
+
+        let client = Client::new();
+        let response = client
+            .get("http://127.0.0.1:8106/index.html")
+            .send()
+            .unwrap();
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.text().unwrap(), "<p>héllo</p>");
+
+        let response = client.get("http://127.0.0.1:8106/logo.png").send().unwrap();
+        assert_eq!(response.headers()["content-type"], "image/png");
+    }
+
+    #[test]
+    fn test_gzip_skipped_above_compress_cap() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("small.txt"), "hello\n".repeat(200)).unwrap();
+        fs::write(temp_dir.path().join("large.txt"), "hello\n".repeat(2000)).unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        let mut config = ServerConfig::with_params("127.0.0.1", 8124, 1, &root_dir);
+        config.gzip = true;
+        config.max_compress_size = Some(4096);
+        let _ = start_test_server_with_config(config);
+
+        let client = Client::new();
+        let response = client
+            .get("http://127.0.0.1:8124/small.txt")
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+
+        let response = client
+            .get("http://127.0.0.1:8124/large.txt")
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .unwrap();
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+        assert_eq!(response.text().unwrap(), "hello\n".repeat(2000));
+    }
+
+    #[test]
+    fn test_if_range_on_large_file() {
+        let temp_dir = tempdir().unwrap();
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(temp_dir.path().join("video.bin"), &content).unwrap();
+        let _ = start_test_server("127.0.0.1", 8107, temp_dir.path().to_path_buf());
+
+        let client = Client::new();
+        let url = "http://127.0.0.1:8107/video.bin";
+        let full = client.get(url).send().unwrap();
+        let etag = full.headers()["etag"].to_str().unwrap().to_string();
+        let last_modified = full.headers()["last-modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(full.bytes().unwrap().len(), content.len());
+
+        // Streamed files only have a weak ETag, which can't validate a range
+        assert!(etag.starts_with("W/"), "unexpected ETag {}", etag);
+        let response = client
+            .get(url)
+            .header("Range", "bytes=100000-100009")
+            .header("If-Range", &etag)
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.bytes().unwrap().len(), content.len());
+
+        let response = client
+            .get(url)
+            .header("Range", "bytes=100000-100009")
+            .header("If-Range", &last_modified)
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 206);
+        assert_eq!(
+            response.headers()["content-range"],
+            format!("bytes 100000-100009/{}", content.len()).as_str()
+        );
+        assert_eq!(response.bytes().unwrap()[..], content[100000..100010]);
+
+        let response = client
+            .get(url)
+            .header("Range", "bytes=100000-100009")
+            .header("If-Range", "\"stale\"")
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.bytes().unwrap()[..], content[..]);
+    }
+
+    #[test]
+    fn test_reverse_proxy_forwards_to_upstream() {
+        let temp_dir = tempdir().unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let stub = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 13\r\nX-Upstream: stub\r\n\r\nfrom upstream")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8108, 4, &temp_dir.path().to_string_lossy());
+        server_config.proxies = vec![ProxyRule {
+            prefix: String::from("/api"),
+            upstream: upstream_addr,
+            timeout: Some(5),
+        }];
+        let _ = start_test_server_with_config(server_config);
+
+        let response = Client::new()
+            .get("http://127.0.0.1:8108/api/items?page=2")
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["x-upstream"], "stub");
+        assert_eq!(response.headers()["via"], "1.1 xener");
+        assert_eq!(response.text().unwrap(), "from upstream");
+
+        let forwarded = stub.join().unwrap();
+        assert!(forwarded.starts_with("GET /api/items?page=2 HTTP/1.0\r\n"));
+        assert!(forwarded.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(forwarded.contains("Via: 1.1 xener\r\n"));
+    }
+
+    #[test]
+    fn test_access_log_uses_forwarded_client() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8109, 4, &temp_dir.path().to_string_lossy());
+        server_config.access_log_path = log_path.to_string_lossy().to_string();
+        server_config.access_log_flush_lines = Some(1);
+        server_config.trusted_proxies = vec![String::from("127.0.0.0/8")];
+        let _ = start_test_server_with_config(server_config);
+
+        let response = Client::new()
+            .get("http://127.0.0.1:8109/index.html")
+            .header("X-Forwarded-For", "198.51.100.1, 203.0.113.7")
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        thread::sleep(Duration::from_millis(100));
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert!(
+            log.starts_with("203.0.113.7 "),
+            "unexpected log line {:?}",
+            log
+        );
+    }
+
+    #[test]
+    fn test_access_log_records_duration() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let log_path = temp_dir.path().join("access.log");
+
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8110, 4, &temp_dir.path().to_string_lossy());
+        server_config.access_log_path = log_path.to_string_lossy().to_string();
+        server_config.access_log_flush_lines = Some(1);
+        server_config.log_format = Some(String::from("json"));
+        let _ = start_test_server_with_config(server_config);
+
+        let response = Client::new()
+            .get("http://127.0.0.1:8110/index.html")
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        thread::sleep(Duration::from_millis(100));
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(entry["path"], "/index.html");
+        assert!(
+            entry["duration_ms"].as_f64().unwrap() > 0.0,
+            "unexpected entry {}",
+            entry
+        );
+    }
+
+    #[test]
+    fn test_connection_slot_returned_when_job_panics() {
+        let count = Arc::new(Mutex::new(1));
+        let metrics = Arc::new(Metrics::new());
+        let slot = ConnectionSlot::new(Arc::clone(&count), Arc::clone(&metrics));
+
+        let job = thread::spawn(move || {
+            let _slot = slot;
+            panic!("handler failed");
+        });
+        assert!(job.join().is_err());
+
+        assert_eq!(*count.lock().unwrap(), 0);
+        assert!(metrics.render().contains("xener_active_connections 0\n"));
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight_request() {
+        let root_dir = tempdir().unwrap();
+        let server_config = Arc::new(ServerConfig::with_params(
+            "127.0.0.1",
+            8111,
+            4,
+            &root_dir.path().to_string_lossy(),
+        ));
+        let server =
+            Server::new(server_config).with_handler(Method::POST, |_: &http::request::Request| {
+                thread::sleep(Duration::from_millis(1000));
+                http::response::Response::new().with_text("done")
+            });
+        let shutdown = server.shutdown_flag();
+        let handle = thread::spawn(move || server.run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect("127.0.0.1:8111").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"POST /slow HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        shutdown.store(true, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(300));
+        assert!(
+            TcpStream::connect("127.0.0.1:8111").is_err(),
+            "new connection accepted during shutdown"
+        );
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\ndone"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(handle.is_finished(), "server did not stop after draining");
+    }
+
+    #[test]
+    fn test_full_job_queue_answered_with_503() {
+        let root_dir = tempdir().unwrap();
+        let mut server_config =
+            ServerConfig::with_params("127.0.0.1", 8112, 10, &root_dir.path().to_string_lossy());
+        server_config.thread_count = Some(1);
+        server_config.job_queue_size = Some(1);
+        thread::spawn(move || {
+            let server = Server::new(Arc::new(server_config)).with_handler(
+                Method::POST,
+                |_: &http::request::Request| {
+                    thread::sleep(Duration::from_millis(1000));
+                    http::response::Response::new().with_text("done")
+                },
+            );
+            let _ = server.run();
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // Occupies the only worker
+        let mut busy = TcpStream::connect("127.0.0.1:8112").unwrap();
+        busy.write_all(
+            b"POST /slow HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // Fills the queue
+        let _queued = TcpStream::connect("127.0.0.1:8112").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut rejected = TcpStream::connect("127.0.0.1:8112").unwrap();
+        rejected
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable"),
+            "unexpected response: {}",
+            response
+        );
+        assert!(response.contains("Retry-After: "));
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "rejection waited for the busy worker"
+        );
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    fn test_head_matches_get_content_length() {
+        let temp_dir = tempdir().unwrap();
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(temp_dir.path().join("video.bin"), &content).unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), "hello xener").unwrap();
+        let _ = start_test_server("127.0.0.1", 8113, temp_dir.path().to_path_buf());
+
+        let client = Client::new();
+        for (path, range) in [
+            ("/notes.txt", None),
+            ("/notes.txt", Some("bytes=0-4")),
+            ("/video.bin", None),
+            ("/video.bin", Some("bytes=100000-100009")),
+        ] {
+            let url = format!("http://127.0.0.1:8113{}", path);
+            let send = |request: reqwest::blocking::RequestBuilder| {
+                let request = match range {
+                    Some(range) => request.header("Range", range),
+                    None => request,
+                };
+                let response = request.send().unwrap();
+                let status = response.status();
+                let headers = response.headers().clone();
+                (status, headers, response.bytes().unwrap())
+            };
+            let (get_status, get_headers, get_body) = send(client.get(&url));
+            let (head_status, head_headers, head_body) = send(client.head(&url));
+
+            assert_eq!(head_status, get_status, "{} {:?}", path, range);
+            for name in ["content-length", "content-range"] {
+                assert_eq!(
+                    head_headers.get(name),
+                    get_headers.get(name),
+                    "{} for {} {:?}",
+                    name,
+                    path,
+                    range
+                );
+            }
+            assert_eq!(
+                get_headers["content-length"],
+                get_body.len().to_string().as_str()
+            );
+            assert!(head_body.is_empty(), "HEAD sent a body");
+        }
+    }
+
+    #[test]
+    fn test_configured_server_header() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        let mut server_config = ServerConfig::with_params("127.0.0.1", 8114, 1, &root_dir);
+        server_config.server_header = Some(String::from("edge"));
+        let _ = start_test_server_with_config(server_config);
+
+        let client = Client::new();
+        let response = client.get("http://127.0.0.1:8114/").send().unwrap();
+        assert_eq!(response.headers()["server"], "edge");
+
+        let response = client.get("http://127.0.0.1:8114/missing").send().unwrap();
+        assert_eq!(response.status().as_u16(), 404);
+        assert_eq!(response.headers()["server"], "edge");
+    }
+
+    #[test]
+    fn test_default_and_disabled_server_header() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+
+        for (port, server_header, expected) in [
+            (8126, None, Some("Xener/0.0.1")),
+            (8127, Some(String::from("")), None),
+        ] {
+            let mut server_config = ServerConfig::with_params("127.0.0.1", port, 1, &root_dir);
+            server_config.server_header = server_header;
+            server_config.max_connections = Some(1);
+            let _ = start_test_server_with_config(server_config);
+
+            // Its kept-alive connection holds the only slot afterwards
+            let client = Client::new();
+            let response = client
+                .get(format!("http://127.0.0.1:{}/", port))
+                .send()
+                .unwrap();
+            assert_eq!(
+                response
+                    .headers()
+                    .get("server")
+                    .map(|value| value.to_str().unwrap()),
+                expected
+            );
+            assert_eq!(response.text().unwrap(), "hello");
+
+            let mut rejected = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            rejected
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut response = String::new();
+            rejected.read_to_string(&mut response).unwrap();
+            assert!(
+                response.starts_with("HTTP/1.1 503 Service Unavailable"),
+                "unexpected response: {}",
+                response
+            );
+            let server_line = response
+                .lines()
+                .find(|line| line.to_ascii_lowercase().starts_with("server:"));
+            assert_eq!(
+                server_line,
+                expected
+                    .map(|value| format!("Server: {}", value))
+                    .as_deref(),
+                "{}",
+                response
+            );
+            drop(client);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pid_file_lifecycle() {
+        let temp_dir = tempdir().unwrap();
+        let pid_file = temp_dir.path().join("xener.pid");
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        let mut server_config = ServerConfig::with_params("127.0.0.1", 8115, 4, &root_dir);
+        server_config.pid_file = Some(pid_file.to_string_lossy().to_string());
+        let server = Server::new(Arc::new(server_config));
+        let shutdown = server.shutdown_flag();
+        let handle = thread::spawn(move || server.run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+
+        shutdown.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        assert!(!pid_file.exists(), "PID file left behind");
+    }
+
+    #[test]
+    fn test_final_response_says_connection_close() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("index.html"), "hello").unwrap();
+        let root_dir = temp_dir.path().to_string_lossy().to_string();
+        let mut server_config = ServerConfig::with_params("127.0.0.1", 8116, 4, &root_dir);
+        server_config.max_requests_per_connection = Some(2);
+        let _ = start_test_server_with_config(server_config);
+
+        // Client asks to close
+        let mut stream = TcpStream::connect("127.0.0.1:8116").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        // Server closes after the last request allowed on the connection
+        let mut stream = TcpStream::connect("127.0.0.1:8116").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        stream.write_all(request).unwrap();
+        stream.write_all(request).unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+        let (first, second) = responses.split_once("hello").unwrap();
+        assert!(first.contains("Connection: keep-alive\r\n"), "{}", first);
+        assert!(second.starts_with("HTTP/1.1 200 OK"), "{}", second);
+        assert!(second.contains("Connection: close\r\n"), "{}", second);
+        assert!(second.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_conditional_get_not_modified() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), "hello xener").unwrap();
+        let _ = start_test_server("127.0.0.1", 8117, temp_dir.path().to_path_buf());
+
+        let client = Client::new();
+        let url = "http://127.0.0.1:8117/notes.txt";
+        let response = client.get(url).send().unwrap();
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let last_modified = response.headers()["last-modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        response.bytes().unwrap();
+
+        let response = client
+            .get(url)
+            .header("If-None-Match", &etag)
+            .header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        assert!(response.bytes().unwrap().is_empty());
+
+        let response = client
+            .get(url)
+            .header("If-Modified-Since", &last_modified)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 304);
+        response.bytes().unwrap();
+
+        // The connection stays usable after a bodiless 304
+        let response = client
+            .get(url)
+            .header("If-None-Match", "\"stale\"")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().unwrap(), "hello xener");
+    }
 }
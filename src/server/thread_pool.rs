@@ -1,23 +1,38 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
-use tracing::{debug, error, info};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::error::{Result, ServerError};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct ThreadPool {
-    sender: Option<mpsc::Sender<Job>>,
+    sender: Option<mpsc::SyncSender<Job>>,
     workers: Vec<Worker>,
     size: usize,
+    shutdown_timeout: Duration,
+    stopping: Arc<AtomicBool>,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> Self {
+    /// Creates a pool of `size` workers, at most `queue_size` jobs may wait for a
+    /// free worker before `execute` starts rejecting work
+    pub fn new(size: usize, queue_size: usize) -> Self {
         let size = if size > 0 { size } else { 1 };
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
         let receiver = Arc::new(Mutex::new(receiver));
         let mut workers = Vec::with_capacity(size);
 
-        info!("Creating thread pool with {} workers", size);
+        info!(
+            "Creating thread pool with {} workers and a queue of {} jobs",
+            size, queue_size
+        );
 
         for id in 0..size {
             workers.push(Worker::new(id, Arc::clone(&receiver)));
@@ -27,19 +42,40 @@ impl ThreadPool {
             sender: Some(sender),
             workers,
             size,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            stopping: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// How long `drop` waits for running jobs to finish before abandoning their workers
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set once the pool starts shutting down, long running jobs should check it
+    /// between units of work and return early
+    pub fn stopping(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stopping)
+    }
+
+    /// Queues a job without blocking, returns `ServerError::ServerBusy` when the
+    /// queue is full so the caller can shed load instead of buffering it
+    pub fn execute<F>(&self, f: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
-        if let Some(sender) = &self.sender {
-            if let Err(e) = sender.send(job) {
-                error!("Error sending job to thread pool: {:?}", e);
-            }
+        match &self.sender {
+            Some(sender) => sender.try_send(job).map_err(|e| match e {
+                mpsc::TrySendError::Full(_) => ServerError::ServerBusy,
+                mpsc::TrySendError::Disconnected(_) => {
+                    error!("Error sending job to thread pool: workers have shut down");
+                    ServerError::Other("Thread pool has shut down".to_string())
+                }
+            }),
+            None => Err(ServerError::Other("Thread pool has shut down".to_string())),
         }
     }
 
@@ -49,22 +85,38 @@ impl ThreadPool {
 }
 
 impl Drop for ThreadPool {
-    // Drop the sender to signal workers to terminate
-    // This is important because dropping the sender will cause
-    // all receivers to receive an error when they try to recv()
+    // Dropping the sender makes every idle worker's recv() fail so it exits, busy
+    // workers get until the shutdown timeout to finish their job
     fn drop(&mut self) {
         drop(self.sender.take());
+        self.stopping.store(true, Ordering::Relaxed);
 
-        info!("Shutting down thread pool, waiting for workers to finish");
+        info!(
+            "Shutting down thread pool, waiting up to {:?} for workers to finish",
+            self.shutdown_timeout
+        );
 
-        // Wait for all workers to finish
+        let deadline = Instant::now() + self.shutdown_timeout;
         for worker in &mut self.workers {
             debug!("Shutting down worker {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
+            let Some(thread) = worker.thread.take() else {
+                continue;
+            };
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(JOIN_POLL_INTERVAL);
+            }
+
+            if thread.is_finished() {
                 if let Err(e) = thread.join() {
                     error!("Error joining worker thread {}: {:?}", worker.id, e);
                 }
+            } else {
+                // Dropping the handle detaches the thread, it's left to finish on its own
+                warn!(
+                    "Worker {} did not finish within {:?}, abandoning it",
+                    worker.id, self.shutdown_timeout
+                );
             }
         }
         info!("Thread pool shutdown completed");
@@ -100,7 +152,16 @@ impl Worker {
                     match message {
                         Ok(job) => {
                             debug!("Worker {} got a job: executing", id);
-                            job();
+                            // A panicking job must not take the worker down with it,
+                            // otherwise the pool silently shrinks
+                            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                let reason = panic
+                                    .downcast_ref::<&str>()
+                                    .map(|s| s.to_string())
+                                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                                    .unwrap_or_else(|| String::from("unknown cause"));
+                                error!("Worker {} job panicked: {}", id, reason);
+                            }
                         }
                         Err(_) => {
                             debug!("Worker {} shutting down", id);
@@ -116,3 +177,99 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::ThreadPool;
+    use crate::error::ServerError;
+
+    #[test]
+    fn test_execute_signals_saturation() {
+        let pool = ThreadPool::new(1, 1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        // Occupy the only worker
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        })
+        .unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Fill the queue
+        pool.execute(|| {}).unwrap();
+
+        let result = pool.execute(|| {});
+        assert!(
+            matches!(result, Err(ServerError::ServerBusy)),
+            "saturated pool accepted a job"
+        );
+
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = ThreadPool::new(1, 2);
+        let (done_tx, done_rx) = mpsc::channel();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        pool.execute(move || done_tx.send(()).unwrap()).unwrap();
+
+        assert!(
+            done_rx.recv_timeout(Duration::from_secs(5)).is_ok(),
+            "job after a panic was never run"
+        );
+    }
+
+    #[test]
+    fn test_drop_abandons_job_past_shutdown_timeout() {
+        let pool = ThreadPool::new(1, 1).with_shutdown_timeout(Duration::from_millis(200));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv_timeout(Duration::from_secs(10));
+        })
+        .unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let start = Instant::now();
+        drop(pool);
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "drop waited {:?} for a stuck job",
+            start.elapsed()
+        );
+
+        // The abandoned job is still running
+        let _ = release_tx.send(());
+    }
+
+    #[test]
+    fn test_stopping_flag_set_on_drop() {
+        let pool = ThreadPool::new(1, 1);
+        let stopping = pool.stopping();
+        let (done_tx, done_rx) = mpsc::channel();
+        let job_stopping = pool.stopping();
+
+        pool.execute(move || {
+            while !job_stopping.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            done_tx.send(()).unwrap();
+        })
+        .unwrap();
+
+        assert!(!stopping.load(Ordering::Relaxed));
+        drop(pool);
+        assert!(done_rx.try_recv().is_ok(), "job did not see the stop flag");
+    }
+}
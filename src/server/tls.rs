@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::fs;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    RootCertStore, ServerConfig as RustlsServerConfig, SupportedProtocolVersion, version,
+};
+
+use crate::config::TlsConfig;
+use crate::error::{Result, ServerError};
+use tracing::{error, info};
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_VERSION: u8 = 0xa0;
+/// DER encoded OID 2.5.4.3, the `CN` attribute of a name
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Builds the rustls configuration for `tls`, failing if a certificate or key
+/// can't be loaded or they don't belong together
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<RustlsServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let default = load_certified_key(&provider, &tls.cert_path, &tls.key_path)?;
+    let by_name = tls
+        .certificates
+        .iter()
+        .map(|cert| {
+            let key = load_certified_key(&provider, &cert.cert_path, &cert.key_path)?;
+            Ok((cert.host.to_lowercase(), key))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let builder = RustlsServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(protocol_versions(tls.min_version.as_deref())?)
+        .map_err(|e| ServerError::Config(format!("invalid TLS configuration: {}", e)))?;
+    let builder = match &tls.client_ca_path {
+        Some(ca_path) => builder.with_client_cert_verifier(client_verifier(provider, ca_path)?),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_cert_resolver(Arc::new(SniResolver { default, by_name }));
+
+    Ok(Arc::new(config))
+}
+
+/// The rustls configuration for new connections, rebuilt when the certificate
+/// or key files change. Accepted connections keep the configuration they
+/// started with
+pub struct ReloadingConfig {
+    tls: TlsConfig,
+    current: ArcSwap<RustlsServerConfig>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadingConfig {
+    pub fn new(tls: TlsConfig) -> Result<Self> {
+        let modified = modified_times(&tls);
+        let current = ArcSwap::new(server_config(&tls)?);
+        Ok(ReloadingConfig {
+            tls,
+            current,
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RustlsServerConfig> {
+        self.current.load_full()
+    }
+
+    /// Rebuilds the configuration if any certificate or key file changed since
+    /// the last check. The current configuration is kept if the new files don't
+    /// load, and they are tried again on the next check
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = modified_times(&self.tls);
+        let mut last = self.modified.lock().unwrap();
+        if *last == modified {
+            return Ok(false);
+        }
+
+        self.current.store(server_config(&self.tls)?);
+        *last = modified;
+        Ok(true)
+    }
+
+    /// Checks for changed files every `interval`, never returns
+    pub fn watch(&self, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            match self.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificates"),
+                Ok(false) => {}
+                Err(e) => error!("Keeping the current TLS certificates: {}", e),
+            }
+        }
+    }
+}
+
+fn modified_times(tls: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let extra = tls
+        .certificates
+        .iter()
+        .flat_map(|cert| [&cert.cert_path, &cert.key_path]);
+    iter::once(&tls.cert_path)
+        .chain(iter::once(&tls.key_path))
+        .chain(extra)
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Picks the certificate for the host name the client asked for with SNI,
+/// falling back to the default one
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_lowercase()))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(key))
+    }
+}
+
+/// Requires every client to present a certificate issued by a CA in `ca_path`
+fn client_verifier(
+    provider: Arc<CryptoProvider>,
+    ca_path: &str,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certificates(ca_path)? {
+        roots.add(cert).map_err(|e| {
+            ServerError::Config(format!("invalid CA certificate in {}: {}", ca_path, e))
+        })?;
+    }
+
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|e| ServerError::Config(format!("invalid client CA bundle {}: {}", ca_path, e)))
+}
+
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| {
+            ServerError::Config(format!("failed to read certificates from {}: {}", path, e))
+        })?;
+    if certs.is_empty() {
+        return Err(ServerError::Config(format!(
+            "no certificates found in {}",
+            path
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Arc<CertifiedKey>> {
+    let certs = load_certificates(cert_path)?;
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+        ServerError::Config(format!(
+            "failed to read private key from {}: {}",
+            key_path, e
+        ))
+    })?;
+
+    let certified = CertifiedKey::from_der(certs, key, provider).map_err(|e| {
+        ServerError::Config(format!(
+            "invalid certificate {} or key {}: {}",
+            cert_path, key_path, e
+        ))
+    })?;
+    Ok(Arc::new(certified))
+}
+
+/// Subject common name of a DER encoded certificate, `None` if it has none or
+/// the certificate can't be parsed
+pub fn common_name(cert: &[u8]) -> Option<String> {
+    let (SEQUENCE, certificate, _) = der_element(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, tbs, _) = der_element(certificate)? else {
+        return None;
+    };
+
+    // version (optional), serial number, signature algorithm, issuer, validity
+    let (tag, _, mut fields) = der_element(tbs)?;
+    let skip = if tag == EXPLICIT_VERSION { 4 } else { 3 };
+    for _ in 0..skip {
+        (_, _, fields) = der_element(fields)?;
+    }
+
+    let (SEQUENCE, mut subject, _) = der_element(fields)? else {
+        return None;
+    };
+    while !subject.is_empty() {
+        let (_, mut attributes, next) = der_element(subject)?;
+        subject = next;
+        while !attributes.is_empty() {
+            let (_, attribute, next) = der_element(attributes)?;
+            attributes = next;
+            let (_, oid, value) = der_element(attribute)?;
+            if oid == COMMON_NAME {
+                let (_, value, _) = der_element(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// Splits the DER element at the start of `input` into its tag, its contents
+/// and the bytes that follow it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        let len = bytes
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+        (len, rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+fn protocol_versions(
+    min_version: Option<&str>,
+) -> Result<&'static [&'static SupportedProtocolVersion]> {
+    match min_version {
+        None | Some("1.2") => Ok(rustls::ALL_VERSIONS),
+        Some("1.3") => Ok(TLS13_ONLY),
+        Some(other) => Err(ServerError::Config(format!(
+            "unsupported TLS min_version {:?}, expected \"1.2\" or \"1.3\"",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use rustls::pki_types::CertificateDer;
+    use rustls::pki_types::pem::PemObject;
+
+    use super::{ReloadingConfig, common_name, server_config};
+    use crate::config::TlsConfig;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn test_server_config_rejects_mismatched_key() {
+        let tls = TlsConfig {
+            cert_path: fixture("localhost.crt"),
+            key_path: fixture("ca.key"),
+            min_version: None,
+            certificates: Vec::new(),
+            reload_interval: None,
+            client_ca_path: None,
+        };
+        assert!(server_config(&tls).is_err());
+    }
+
+    #[test]
+    fn test_server_config_rejects_unknown_min_version() {
+        let tls = TlsConfig {
+            cert_path: fixture("localhost.crt"),
+            key_path: fixture("localhost.key"),
+            min_version: Some(String::from("1.1")),
+            certificates: Vec::new(),
+            reload_interval: None,
+            client_ca_path: None,
+        };
+        assert!(server_config(&tls).is_err());
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_current_config() {
+        let temp_dir = tempdir().unwrap();
+        let cert_path = temp_dir.path().join("server.crt");
+        let key_path = temp_dir.path().join("server.key");
+        fs::copy(fixture("localhost.crt"), &cert_path).unwrap();
+        fs::copy(fixture("localhost.key"), &key_path).unwrap();
+
+        let config = ReloadingConfig::new(TlsConfig {
+            cert_path: cert_path.to_string_lossy().to_string(),
+            key_path: key_path.to_string_lossy().to_string(),
+            min_version: None,
+            certificates: Vec::new(),
+            reload_interval: None,
+            client_ca_path: None,
+        })
+        .unwrap();
+        let original = config.current();
+        assert!(!config.reload_if_changed().unwrap());
+
+        fs::copy(fixture("example.test.crt"), &cert_path).unwrap();
+        assert!(config.reload_if_changed().is_err());
+        assert!(Arc::ptr_eq(&original, &config.current()));
+        // Still tried, and still failing, on the next check
+        assert!(config.reload_if_changed().is_err());
+
+        fs::copy(fixture("example.test.key"), &key_path).unwrap();
+        assert!(config.reload_if_changed().unwrap());
+        assert!(!Arc::ptr_eq(&original, &config.current()));
+    }
+
+    #[test]
+    fn test_common_name() {
+        let cert = CertificateDer::from_pem_file(fixture("client.crt")).unwrap();
+        assert_eq!(common_name(&cert), Some(String::from("client.example")));
+
+        assert_eq!(common_name(b"not a certificate"), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::IpAddr;
+
+use tracing::warn;
+
+/// An address range in CIDR notation, a bare address covers just itself
+struct Network {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+impl Network {
+    fn parse(network: &str) -> Option<Self> {
+        let (address, prefix_len) = match network.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (network, None),
+        };
+        let address: IpAddr = address.trim().parse().ok()?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse().ok().filter(|&len| len <= max_len)?,
+            None => max_len,
+        };
+        Some(Network {
+            address,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => masked(
+                network.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `network` and `ip`, both `width` bits wide, share the first
+/// `prefix_len` bits
+fn masked(network: u128, ip: u128, width: u32, prefix_len: u32) -> bool {
+    let host_bits = width - prefix_len;
+    network.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+/// Proxies allowed to tell us the client's address with `X-Forwarded-For`
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: &[String]) -> Self {
+        let networks = networks
+            .iter()
+            .filter_map(|network| {
+                let parsed = Network::parse(network);
+                if parsed.is_none() {
+                    warn!("Ignoring invalid trusted proxy network {:?}", network);
+                }
+                parsed
+            })
+            .collect();
+
+        TrustedProxies { networks }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client behind `peer` according to `forwarded_for`, `None` when `peer`
+    /// isn't a trusted proxy or forwarded nothing. Every proxy appends the address
+    /// it got the request from, so the rightmost untrusted entry is the first one
+    /// not written by a proxy we trust, anything left of it could be forged
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+
+        let mut client = None;
+        for entry in forwarded_for?.rsplit(',') {
+            let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = Some(ip);
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::TrustedProxies;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn proxies(networks: &[&str]) -> TrustedProxies {
+        let networks: Vec<String> = networks.iter().map(|n| n.to_string()).collect();
+        TrustedProxies::new(&networks)
+    }
+
+    #[test]
+    fn test_trusted_proxy_forwards_client() {
+        let proxies = proxies(&["10.0.0.0/8", "192.168.1.5"]);
+
+        assert_eq!(
+            proxies.client_ip(ip("10.1.2.3"), Some("203.0.113.7")),
+            Some(ip("203.0.113.7"))
+        );
+        // Spoofed entries left of the last untrusted hop are skipped
+        assert_eq!(
+            proxies.client_ip(
+                ip("10.1.2.3"),
+                Some("1.1.1.1, 198.51.100.2, 192.168.1.5, 10.9.9.9")
+            ),
+            Some(ip("198.51.100.2"))
+        );
+        assert_eq!(proxies.client_ip(ip("192.168.1.5"), None), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer_header_ignored() {
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(ip("11.0.0.1"), Some("203.0.113.7")),
+            None
+        );
+        assert_eq!(
+            proxies(&[]).client_ip(ip("10.0.0.1"), Some("203.0.113.7")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_networks() {
+        let proxies = proxies(&["2001:db8::/32", "172.16.0.0/12", "0.0.0.0/40", "bogus"]);
+
+        assert!(proxies.is_trusted(ip("2001:db8:1::1")));
+        assert!(!proxies.is_trusted(ip("2001:db9::1")));
+        assert!(proxies.is_trusted(ip("172.31.255.255")));
+        assert!(proxies.is_trusted(ip("::ffff:172.16.0.1")));
+        assert!(!proxies.is_trusted(ip("172.32.0.0")));
+        assert_eq!(proxies.networks.len(), 2);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::config::ServerConfig;
+use crate::server::file_cache::FileCache;
+use crate::server::stat_cache::StatCache;
+use crate::server::static_handler::StaticFileHandler;
+
+/// Picks the static file handler for a request based on its `Host` header
+pub struct VirtualHosts {
+    default: StaticFileHandler,
+    hosts: HashMap<String, StaticFileHandler>,
+    strict: bool,
+}
+
+impl VirtualHosts {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        // Keyed by resolved path, so one cache serves every document root
+        let cache = FileCache::from_config(&config);
+        let stats = StatCache::from_config(&config);
+        let hosts = config
+            .virtual_hosts
+            .iter()
+            .map(|vhost| {
+                let handler = StaticFileHandler::new(config.clone())
+                    .with_root_dir(&served_root(&config, &vhost.doc_root))
+                    .with_cache(cache.clone())
+                    .with_stat_cache(stats.clone());
+                (vhost.host.to_lowercase(), handler)
+            })
+            .collect();
+
+        VirtualHosts {
+            default: StaticFileHandler::new(config.clone())
+                .with_root_dir(&served_root(&config, &config.doc_root))
+                .with_cache(cache)
+                .with_stat_cache(stats),
+            hosts,
+            strict: config.strict_vhost,
+        }
+    }
+
+    /// Returns `None` when strict matching is enabled and no virtual host matches
+    pub fn resolve(&self, host: Option<&String>) -> Option<&StaticFileHandler> {
+        let matched = host.and_then(|host| self.hosts.get(&host_name(host)));
+
+        match matched {
+            Some(handler) => Some(handler),
+            None if self.strict && !self.hosts.is_empty() => None,
+            None => Some(&self.default),
+        }
+    }
+}
+
+/// Where `doc_root` is found once the server is confined to the main document
+/// root with `chroot`, unchanged otherwise
+fn served_root(config: &ServerConfig, doc_root: &str) -> String {
+    if !config.chroot {
+        return doc_root.to_string();
+    }
+    match Path::new(doc_root).strip_prefix(&config.doc_root) {
+        Ok(relative) => Path::new("/").join(relative).to_string_lossy().to_string(),
+        Err(_) => {
+            warn!(
+                "Document root {} is outside the chroot {}, its files can't be served",
+                doc_root, config.doc_root
+            );
+            doc_root.to_string()
+        }
+    }
+}
+
+/// Lowercased host name from a `Host` header value, without the port
+pub fn host_name(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{VirtualHosts, served_root};
+    use crate::config::{ServerConfig, VirtualHost};
+
+    fn config(strict: bool) -> ServerConfig {
+        let mut config = ServerConfig::with_params("127.0.0.1", 8080, 1, "./static");
+        config.virtual_hosts = vec![VirtualHost {
+            host: String::from("example.com"),
+            doc_root: String::from("./example"),
+        }];
+        config.strict_vhost = strict;
+        config
+    }
+
+    #[test]
+    fn test_resolve_virtual_host() {
+        let vhosts = VirtualHosts::new(Arc::new(config(true)));
+
+        assert!(vhosts.resolve(Some(&"example.com".to_string())).is_some());
+        assert!(
+            vhosts
+                .resolve(Some(&"Example.com:8080".to_string()))
+                .is_some()
+        );
+        assert!(vhosts.resolve(Some(&"unknown.com".to_string())).is_none());
+        assert!(vhosts.resolve(None).is_none());
+    }
+
+    #[test]
+    fn test_resolve_falls_back_when_not_strict() {
+        let vhosts = VirtualHosts::new(Arc::new(config(false)));
+
+        assert!(vhosts.resolve(Some(&"unknown.com".to_string())).is_some());
+    }
+
+    #[test]
+    fn test_served_root_inside_chroot() {
+        let mut config = ServerConfig::with_params("127.0.0.1", 8080, 1, "/srv/www");
+        assert_eq!(served_root(&config, "/srv/www/blog"), "/srv/www/blog");
+
+        config.chroot = true;
+        assert_eq!(served_root(&config, "/srv/www"), "/");
+        assert_eq!(served_root(&config, "/srv/www/blog"), "/blog");
+        assert_eq!(served_root(&config, "/srv/other"), "/srv/other");
+    }
+}